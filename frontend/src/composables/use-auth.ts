@@ -1,4 +1,5 @@
 import { defineStore } from "pinia";
+import { getPasskeyAssertion } from "./use-passkey";
 
 function makeUrl(url: string): string {
   const baseHost = import.meta.env.VITE_BASE_HOST;
@@ -25,50 +26,131 @@ function makeUrl(url: string): string {
 const useAuth = defineStore(
   "librarian.auth",
   () => {
-    const token = ref<string>();
+    // the session itself lives in an HttpOnly cookie, only remember that we have one
+    const loggedIn = ref(false);
+    const username = ref<string>();
+    const role = ref<"owner" | "inviter" | "viewer">();
+    // not persisted, fetched again from the server after a reload
+    const csrfToken = ref<string>();
+    const totpEnabled = ref(false);
+
+    const isLoggedIn = computed(() => loggedIn.value);
+
+    function clear() {
+      loggedIn.value = false;
+      username.value = undefined;
+      role.value = undefined;
+      csrfToken.value = undefined;
+      totpEnabled.value = false;
+    }
+
+    async function fetchMe() {
+      const resp = await fetch(makeUrl("/api/auth/me"));
+      const data = await resp.json();
+
+      if (!data.ok) {
+        throw new Error(data.error);
+      }
 
-    const isLoggedIn = computed(() => !!token.value);
+      username.value = data.data.username ?? undefined;
+      role.value = data.data.role;
+      csrfToken.value = data.data.csrfToken ?? undefined;
+      totpEnabled.value = data.data.totpEnabled ?? false;
+    }
 
     async function test() {
       try {
-        const resp = await fetch(makeUrl("/api/invite/info"), {
+        await fetchMe();
+      } catch (error) {
+        console.error(error);
+
+        clear();
+
+        throw error;
+      }
+    }
+
+    /**
+     * Pick up a login made outside of the form, through OpenID Connect or the reverse proxy
+     */
+    async function resume(): Promise<boolean> {
+      try {
+        await fetchMe();
+        loggedIn.value = true;
+
+        return true;
+      } catch {
+        clear();
+
+        return false;
+      }
+    }
+
+    /**
+     * Log in, resolves to "totp" when the account also needs a two-factor code
+     */
+    async function login(
+      credentials: { token: string; code?: string } | { username: string; password: string; code?: string }
+    ): Promise<"ok" | "totp"> {
+      // test with api
+      try {
+        const resp = await fetch(makeUrl("/api/auth/login"), {
+          method: "POST",
+          body: JSON.stringify(credentials),
           headers: {
-            Authorization: `Bearer ${token.value}`,
+            "Content-Type": "application/json",
           },
         });
         const data = await resp.json();
 
-        if (!data.ok) {
-          token.value = undefined;
-
+        if (data.ok) {
+          loggedIn.value = true;
+          username.value = data.data.username ?? undefined;
+          role.value = data.data.role;
+          csrfToken.value = data.data.csrfToken;
+
+          return "ok";
+        } else if (data.totpRequired) {
+          return "totp";
+        } else {
           throw new Error(data.error);
         }
       } catch (error) {
         console.error(error);
 
-        token.value = undefined;
-
         throw error;
       }
     }
 
-    async function login(loginToken: string) {
-      // test with api
+    async function loginWithPasskey() {
       try {
-        const resp = await fetch(makeUrl("/api/auth/login"), {
+        const startResp = await fetch(makeUrl("/api/auth/passkeys/login/start"), {
+          method: "POST",
+        });
+        const options = await startResp.json();
+
+        if (!options.ok) {
+          throw new Error(options.error);
+        }
+
+        const assertion = await getPasskeyAssertion(options.data);
+        const resp = await fetch(makeUrl("/api/auth/passkeys/login/finish"), {
           method: "POST",
-          body: JSON.stringify({ token: loginToken }),
+          body: JSON.stringify(assertion),
           headers: {
             "Content-Type": "application/json",
           },
         });
         const data = await resp.json();
 
-        if (data.ok) {
-          token.value = loginToken;
-        } else {
+        if (!data.ok) {
           throw new Error(data.error);
         }
+
+        loggedIn.value = true;
+        username.value = data.data.username ?? undefined;
+        role.value = data.data.role;
+        csrfToken.value = data.data.csrfToken;
       } catch (error) {
         console.error(error);
 
@@ -77,14 +159,30 @@ const useAuth = defineStore(
     }
 
     function logout() {
-      token.value = undefined;
+      if (loggedIn.value) {
+        // end the session server side, this also removes the cookie
+        fetch(makeUrl("/api/auth/logout"), {
+          method: "POST",
+          headers: {
+            "X-CSRF-Token": csrfToken.value ?? "",
+          },
+        }).catch((error) => console.error(error));
+      }
+
+      clear();
     }
 
     return {
-      token,
+      loggedIn,
+      username,
+      role,
+      csrfToken,
+      totpEnabled,
       isLoggedIn,
       login,
+      loginWithPasskey,
       logout,
+      resume,
       test,
     };
   },
@@ -92,6 +190,7 @@ const useAuth = defineStore(
     persist: {
       key: "librarian.auth",
       storage: localStorage,
+      pick: ["loggedIn", "username", "role"],
     },
   }
 );
@@ -27,8 +27,9 @@ export default function useBackendFetch<T>(url: string, fetchOptions?: RequestIn
 
   const headers = new Headers(fetchOptions?.headers);
 
-  if (auth.token) {
-    headers.set("Authorization", `Bearer ${auth.token}`);
+  const method = (fetchOptions?.method ?? "GET").toUpperCase();
+  if (auth.csrfToken && !["GET", "HEAD", "OPTIONS"].includes(method)) {
+    headers.set("X-CSRF-Token", auth.csrfToken);
   }
 
   const mergedFetchOptions: RequestInit = {
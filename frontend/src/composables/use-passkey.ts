@@ -0,0 +1,122 @@
+import useBackendFetch from "./use-backend-fetch";
+
+export interface Passkey {
+  id: string;
+  adminId: string;
+  name: string;
+  createdAt: number;
+  lastUsedAt?: number | null;
+}
+
+interface PasskeyChallenge<T> {
+  challengeId: string;
+  publicKey: T;
+}
+
+// the same as the browser options, with every buffer base64url encoded
+type ServerCreationOptions = Omit<PublicKeyCredentialCreationOptions, "challenge" | "user" | "excludeCredentials"> & {
+  challenge: string;
+  user: { id: string; name: string; displayName: string };
+  excludeCredentials: { type: "public-key"; id: string }[];
+};
+type ServerRequestOptions = Omit<PublicKeyCredentialRequestOptions, "challenge"> & {
+  challenge: string;
+};
+
+function toBase64Url(buffer: ArrayBuffer): string {
+  const bytes = new Uint8Array(buffer);
+  let binary = "";
+  bytes.forEach((byte) => {
+    binary += String.fromCharCode(byte);
+  });
+
+  return btoa(binary).replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+function fromBase64Url(value: string): ArrayBuffer {
+  const base64 = value.replace(/-/g, "+").replace(/_/g, "/");
+  const binary = atob(base64.padEnd(Math.ceil(base64.length / 4) * 4, "="));
+
+  return Uint8Array.from(binary, (char) => char.charCodeAt(0)).buffer;
+}
+
+export function isPasskeySupported() {
+  return typeof window !== "undefined" && !!window.PublicKeyCredential;
+}
+
+/**
+ * Ask the browser to pick one of the passkeys of this site, the result is sent to the login endpoint
+ */
+export async function getPasskeyAssertion(options: PasskeyChallenge<ServerRequestOptions>) {
+  const credential = (await navigator.credentials.get({
+    publicKey: {
+      ...options.publicKey,
+      challenge: fromBase64Url(options.publicKey.challenge),
+    },
+  })) as PublicKeyCredential | null;
+
+  if (!credential) {
+    throw new Error("No passkey was selected");
+  }
+
+  const response = credential.response as AuthenticatorAssertionResponse;
+
+  return {
+    challengeId: options.challengeId,
+    credential: {
+      id: credential.id,
+      response: {
+        clientDataJSON: toBase64Url(response.clientDataJSON),
+        authenticatorData: toBase64Url(response.authenticatorData),
+        signature: toBase64Url(response.signature),
+        userHandle: response.userHandle ? toBase64Url(response.userHandle) : null,
+      },
+    },
+  };
+}
+
+export async function registerPasskey(name: string): Promise<Passkey> {
+  const options = await useBackendFetch<PasskeyChallenge<ServerCreationOptions>>("/auth/passkeys/register/start", {
+    method: "POST",
+  });
+
+  const publicKey = options.publicKey;
+  const credential = (await navigator.credentials.create({
+    publicKey: {
+      ...publicKey,
+      challenge: fromBase64Url(publicKey.challenge),
+      user: {
+        ...publicKey.user,
+        id: fromBase64Url(publicKey.user.id),
+      },
+      excludeCredentials: publicKey.excludeCredentials.map((excluded) => ({
+        ...excluded,
+        id: fromBase64Url(excluded.id),
+      })),
+    },
+  })) as PublicKeyCredential | null;
+
+  if (!credential) {
+    throw new Error("No passkey was created");
+  }
+
+  const response = credential.response as AuthenticatorAttestationResponse;
+
+  return useBackendFetch<Passkey>("/auth/passkeys/register/finish", {
+    method: "POST",
+    body: JSON.stringify({
+      challengeId: options.challengeId,
+      name,
+      credential: {
+        id: credential.id,
+        response: {
+          clientDataJSON: toBase64Url(response.clientDataJSON),
+          attestationObject: toBase64Url(response.attestationObject),
+        },
+      },
+    }),
+    headers: {
+      "Content-Type": "application/json",
+    },
+  });
+}
@@ -25,9 +25,11 @@ declare module 'vue' {
     IMdiWebOff: typeof import('~icons/mdi/web-off')['default']
     InviteAdd: typeof import('./../components/InviteAdd.vue')['default']
     LoginForm: typeof import('./../components/LoginForm.vue')['default']
+    PasskeySetup: typeof import('./../components/PasskeySetup.vue')['default']
     RouterLink: typeof import('vue-router')['RouterLink']
     RouterView: typeof import('vue-router')['RouterView']
     Toast: typeof import('./../components/Toast.vue')['default']
     ToastContainer: typeof import('./../components/ToastContainer.vue')['default']
+    TotpSetup: typeof import('./../components/TotpSetup.vue')['default']
   }
 }
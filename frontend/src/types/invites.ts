@@ -9,6 +9,9 @@ export interface InviteOption {
   sharedLibraries: InviteSharedLibrary | null;
   expiresAt: number | null;
   roles: string[] | null;
+  issueApiKey?: boolean;
+  userId?: string | null;
+  generateCredentials?: boolean;
 }
 
 export interface Invite {
@@ -16,6 +19,21 @@ export interface Invite {
   token: string;
   option: InviteOption;
   user_id: string | null;
+  note?: string | null;
+  email?: string | null;
+  pausedAt?: number | null;
+  updatedAt?: number | null;
+  tags?: string[];
+  createdAt?: number | null;
+}
+
+export interface PublicInvite {
+  kind: "komga" | "navidrome";
+  token: string;
+  expiresAt: number | null;
+  emailRequired: boolean;
+  handover: boolean;
+  paused: boolean;
 }
 
 export interface InviteConfig {
@@ -30,6 +48,7 @@ export interface InviteConfig {
   };
   navidrome: {
     active: boolean;
+    libraryAccess: boolean;
     libraries: {
       id: number;
       name: string;
@@ -44,6 +63,8 @@ export interface AddEmitKomga {
   excludeLabels: string[];
   roles: string[];
   expiresAt?: number | null;
+  userId?: string;
+  generateCredentials?: boolean;
 }
 
 export interface AddEmitNavidrome {
@@ -51,4 +72,6 @@ export interface AddEmitNavidrome {
   libraries: number[];
   isAdmin: boolean;
   expiresAt?: number | null;
+  userId?: string;
+  generateCredentials?: boolean;
 }
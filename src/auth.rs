@@ -0,0 +1,408 @@
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier, password_hash::SaltString};
+use hmac::{Hmac, Mac};
+use rand::{Rng, distr::Alphanumeric};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{
+        LazyLock, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use subtle::ConstantTimeEq;
+
+use crate::database::AdminRole;
+
+const SESSION_PREFIX: &str = "kls_";
+pub const API_KEY_PREFIX: &str = "kla_";
+const TOKEN_LENGTH: usize = 48;
+/// How long an admin stays logged in, in seconds
+pub const SESSION_DURATION: u64 = 7 * 24 * 60 * 60;
+/// How long a session survives without any request, in seconds
+pub const SESSION_IDLE_TIMEOUT: u64 = 12 * 60 * 60;
+pub const SESSION_COOKIE: &str = "klib_session";
+pub const OIDC_STATE_COOKIE: &str = "klib_oidc";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::encode_b64(&rand::rng().random::<[u8; 16]>())?;
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+
+    Ok(hash.to_string())
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(e) => {
+            tracing::error!("Stored password hash is invalid: {}", e);
+            false
+        }
+    }
+}
+
+/// Spend as long as checking a real password, so a missing account cannot be told apart by how
+/// fast the login is refused. Never matches.
+pub fn verify_missing_password(password: &str) {
+    static DUMMY_HASH: LazyLock<String> =
+        LazyLock::new(|| hash_password("k-librarian").unwrap_or_default());
+
+    verify_password(password, &DUMMY_HASH);
+}
+
+/// Whether the shared token from the config is an Argon2 or bcrypt hash instead of plaintext
+pub fn is_hashed_token(configured: &str) -> bool {
+    configured.starts_with("$argon2") || is_bcrypt_hash(configured)
+}
+
+fn is_bcrypt_hash(configured: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"]
+        .iter()
+        .any(|prefix| configured.starts_with(prefix))
+}
+
+/// Check that a hashed shared token from the config can be read, plaintext is always valid
+pub fn validate_hashed_token(configured: &str) -> Result<(), String> {
+    if configured.starts_with("$argon2") {
+        PasswordHash::new(configured).map_err(|e| e.to_string())?;
+    } else if is_bcrypt_hash(configured) {
+        configured
+            .parse::<bcrypt::HashParts>()
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Hash of the token that last matched a hashed shared token, so scripts using it do not pay
+/// for a slow hash on every request. The config cannot change while running.
+static VERIFIED_TOKEN: OnceLock<[u8; 32]> = OnceLock::new();
+
+/// Check a token against the shared token from the config, in constant time
+pub fn verify_shared_token(token: &str, configured: &str) -> bool {
+    let digest: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+
+    if !is_hashed_token(configured) {
+        // compare the digests so the length of the token does not leak either
+        let expected: [u8; 32] = Sha256::digest(configured.as_bytes()).into();
+        return digest.ct_eq(&expected).into();
+    }
+
+    if let Some(verified) = VERIFIED_TOKEN.get() {
+        return digest.ct_eq(verified).into();
+    }
+
+    let valid = if is_bcrypt_hash(configured) {
+        bcrypt::verify(token, configured).unwrap_or_else(|e| {
+            tracing::error!("Configured token hash is invalid: {}", e);
+            false
+        })
+    } else {
+        verify_password(token, configured)
+    };
+
+    if valid {
+        let _ = VERIFIED_TOKEN.set(digest);
+    }
+
+    valid
+}
+
+fn random_token(prefix: &str) -> String {
+    let token: String = rand::rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect();
+
+    format!("{prefix}{token}")
+}
+
+/// A new random session token, only its hash is stored in the database
+pub fn generate_session_token() -> String {
+    random_token(SESSION_PREFIX)
+}
+
+/// A new random token for the double submit CSRF check, bound to a session
+pub fn generate_csrf_token() -> String {
+    random_token("")
+}
+
+/// A new random API key, shown once when created and only stored hashed
+pub fn generate_api_key() -> String {
+    random_token(API_KEY_PREFIX)
+}
+
+/// The hash of a session token or API key, as stored in the database
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Random key of this process, for the tokens that are only valid until the next restart
+static PROCESS_KEY: LazyLock<[u8; 32]> = LazyLock::new(|| rand::rng().random());
+
+/// The CSRF token of a user logged in by the reverse proxy, which has no session to keep one in.
+/// It is keyed with a secret of this process, so it differs for every user, cannot be worked out
+/// from anything in the config and changes on every restart.
+pub fn proxy_csrf_token(username: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(PROCESS_KEY.as_slice())
+        .expect("HMAC accepts keys of any length");
+    mac.update(username.as_bytes());
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Read a cookie from the request headers
+pub fn cookie_value<'a>(headers: &'a axum::http::HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(axum::http::header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| {
+            let (key, value) = pair.trim().split_once('=')?;
+            (key == name).then_some(value)
+        })
+}
+
+/// The `Set-Cookie` value for the session cookie, an empty token removes the cookie
+pub fn session_cookie(token: &str, max_age: u64, secure: bool) -> String {
+    let mut cookie = format!(
+        "{SESSION_COOKIE}={token}; Path=/api; Max-Age={max_age}; HttpOnly; SameSite=Strict"
+    );
+    if secure {
+        cookie.push_str("; Secure");
+    }
+
+    cookie
+}
+
+/// The `Set-Cookie` value binding an OpenID Connect login to the browser that started it.
+/// It has to be `Lax`, the provider redirects back from another site.
+pub fn oidc_state_cookie(state: &str, max_age: u64, secure: bool) -> String {
+    let mut cookie = format!(
+        "{OIDC_STATE_COOKIE}={state}; Path=/api/auth/oidc; Max-Age={max_age}; HttpOnly; SameSite=Lax"
+    );
+    if secure {
+        cookie.push_str("; Secure");
+    }
+
+    cookie
+}
+
+const TOTP_ISSUER: &str = "K-Librarian";
+const TOTP_STEP: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+/// The account name of the shared token in the authenticator apps
+pub const SHARED_TOKEN_TOTP_ACCOUNT: &str = "admin-token";
+
+/// The last TOTP step accepted for the shared token, which has no account to keep it in.
+/// It is forgotten on restart.
+static SHARED_TOKEN_TOTP_STEP: AtomicU64 = AtomicU64::new(0);
+
+#[derive(thiserror::Error, Debug)]
+pub enum TotpError {
+    #[error("invalid TOTP secret: {0}")]
+    InvalidSecret(String),
+    #[error("failed to create TOTP: {0}")]
+    Totp(#[from] totp_rs::TotpUrlError),
+    #[error("failed to render QR code: {0}")]
+    QrCode(#[from] qrcode::types::QrError),
+}
+
+/// A new random base32 encoded TOTP secret of 160 bits
+pub fn generate_totp_secret() -> String {
+    totp_rs::Secret::generate_secret().to_encoded().to_string()
+}
+
+/// The RFC 6238 TOTP for a secret, with the default 6 digits and 30 seconds step
+pub fn totp(secret: &str, account: &str) -> Result<totp_rs::TOTP, TotpError> {
+    let secret = totp_rs::Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| TotpError::InvalidSecret(e.to_string()))?;
+
+    Ok(totp_rs::TOTP::new(
+        totp_rs::Algorithm::SHA1,
+        6,
+        0,
+        TOTP_STEP,
+        secret,
+        Some(TOTP_ISSUER.to_string()),
+        account.to_string(),
+    )?)
+}
+
+/// The provisioning QR code as an SVG image, for the authenticator apps
+pub fn totp_qr_code(totp: &totp_rs::TOTP) -> Result<String, TotpError> {
+    let code = qrcode::QrCode::new(totp.get_url())?;
+
+    Ok(code
+        .render::<qrcode::render::svg::Color>()
+        .min_dimensions(200, 200)
+        .build())
+}
+
+/// Check a code against the current step and the one before and after it, to allow for some
+/// clock drift. Returns the matched step, which should be remembered so a code cannot be used twice.
+pub fn verify_totp(totp: &totp_rs::TOTP, code: &str, last_step: Option<u64>) -> Option<u64> {
+    verify_totp_at(totp, code, last_step, crate::database::unix_now())
+}
+
+fn verify_totp_at(
+    totp: &totp_rs::TOTP,
+    code: &str,
+    last_step: Option<u64>,
+    now: u64,
+) -> Option<u64> {
+    [now.saturating_sub(TOTP_STEP), now, now + TOTP_STEP]
+        .into_iter()
+        .map(|time| (time / TOTP_STEP, time))
+        .filter(|(step, _)| last_step.is_none_or(|last| *step > last))
+        .find(|(_, time)| totp.check(code.trim(), *time))
+        .map(|(step, _)| step)
+}
+
+/// Check a code for the shared token against the secret from the config. Like for the admins
+/// a step is only accepted once, even by two logins at the same time.
+pub fn verify_shared_token_totp(secret: &str, code: &str) -> Result<bool, TotpError> {
+    let totp = totp(secret, SHARED_TOKEN_TOTP_ACCOUNT)?;
+    let last_step = Some(SHARED_TOKEN_TOTP_STEP.load(Ordering::SeqCst)).filter(|step| *step > 0);
+
+    Ok(verify_totp(&totp, code, last_step)
+        .is_some_and(|step| SHARED_TOKEN_TOTP_STEP.fetch_max(step, Ordering::SeqCst) < step))
+}
+
+/// New one-time recovery codes, returned as `(codes, hashes)`, only the hashes are stored
+pub fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = rand::rng()
+                .sample_iter(&Alphanumeric)
+                .take(10)
+                .map(|c| char::from(c).to_ascii_lowercase())
+                .collect();
+            let code = format!("{}-{}", &code[..5], &code[5..]);
+            let hash = hash_token(&code);
+            (code, hash)
+        })
+        .unzip()
+}
+
+/// Find a recovery code in the stored hashes, returns the position of the matching hash
+pub fn find_recovery_code(hashes: &[String], code: &str) -> Option<usize> {
+    let hash = hash_token(&code.trim().to_ascii_lowercase());
+    hashes.iter().position(|stored| *stored == hash)
+}
+
+/// The highest role any of the groups gives, `None` when the user is in none of them
+pub fn role_for_groups(roles: &HashMap<String, AdminRole>, groups: &[String]) -> Option<AdminRole> {
+    groups
+        .iter()
+        .filter_map(|group| roles.get(group).copied())
+        .min_by_key(|role| match role {
+            AdminRole::Owner => 0,
+            AdminRole::Inviter => 1,
+            AdminRole::Viewer => 2,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA1 seed of RFC 6238 appendix B, `12345678901234567890`, base32 encoded
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn rfc_totp() -> totp_rs::TOTP {
+        totp(RFC_SECRET, "admin").unwrap()
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238_vectors() {
+        let totp = rfc_totp();
+
+        // the RFC uses 8 digits, these are the last 6 of them
+        for (time, code) in [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ] {
+            assert_eq!(totp.generate(time), code, "at {time}");
+        }
+    }
+
+    #[test]
+    fn verify_totp_accepts_one_step_of_drift() {
+        let totp = rfc_totp();
+
+        // 287082 belongs to step 1, from 30 to 59
+        assert_eq!(verify_totp_at(&totp, "287082", None, 59), Some(1));
+        assert_eq!(verify_totp_at(&totp, "287082", None, 30 + 30), Some(1));
+        assert_eq!(verify_totp_at(&totp, "287082", None, 0), Some(1));
+        assert_eq!(verify_totp_at(&totp, " 287082 ", None, 59), Some(1));
+        assert_eq!(verify_totp_at(&totp, "287082", None, 59 + 60), None);
+        assert_eq!(verify_totp_at(&totp, "000000", None, 59), None);
+    }
+
+    #[test]
+    fn verify_totp_rejects_replayed_steps() {
+        let totp = rfc_totp();
+
+        assert_eq!(verify_totp_at(&totp, "287082", Some(0), 59), Some(1));
+        assert_eq!(verify_totp_at(&totp, "287082", Some(1), 59), None);
+        assert_eq!(verify_totp_at(&totp, "287082", Some(2), 59), None);
+    }
+
+    #[test]
+    fn role_for_groups_picks_the_highest_role() {
+        let roles = HashMap::from([
+            ("owners".to_string(), AdminRole::Owner),
+            ("inviters".to_string(), AdminRole::Inviter),
+            ("viewers".to_string(), AdminRole::Viewer),
+        ]);
+        let groups = |groups: &[&str]| groups.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(
+            role_for_groups(&roles, &groups(&["viewers", "owners", "inviters"])),
+            Some(AdminRole::Owner)
+        );
+        assert_eq!(
+            role_for_groups(&roles, &groups(&["users", "viewers", "inviters"])),
+            Some(AdminRole::Inviter)
+        );
+        assert_eq!(role_for_groups(&roles, &groups(&["users"])), None);
+        assert_eq!(role_for_groups(&roles, &[]), None);
+    }
+
+    #[test]
+    fn recovery_codes_are_found_by_hash() {
+        let (codes, hashes) = generate_recovery_codes();
+
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert_eq!(find_recovery_code(&hashes, &codes[3]), Some(3));
+        assert_eq!(
+            find_recovery_code(&hashes, &codes[3].to_ascii_uppercase()),
+            Some(3)
+        );
+        assert_eq!(find_recovery_code(&hashes, "aaaaa-bbbbb"), None);
+    }
+
+    #[test]
+    fn proxy_csrf_tokens_differ_per_user() {
+        assert_eq!(proxy_csrf_token("alice"), proxy_csrf_token("alice"));
+        assert_ne!(proxy_csrf_token("alice"), proxy_csrf_token("bob"));
+        assert_ne!(proxy_csrf_token("alice"), hash_token("alice"));
+    }
+}
@@ -1,8 +1,10 @@
-use std::path::PathBuf;
+use std::{collections::HashMap, path::PathBuf};
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::database::AdminRole;
+
 /// Main configuration structure for k-librarian
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -10,8 +12,12 @@ pub struct Config {
     pub host: String,
     /// The port to bind the web server to
     pub port: u16,
-    /// Authentication token for accessing the admin panel
+    /// Authentication token for accessing the admin panel, either plaintext or an Argon2 or bcrypt
+    /// hash made with `k-librarian hash-token`
     pub token: String,
+    /// Base32 TOTP secret, asks for a two-factor code when logging in with the token (optional)
+    #[serde(rename = "token-totp-secret")]
+    pub token_totp_secret: Option<String>,
     /// Path to the database file (relative or absolute)
     #[serde(rename = "db-path")]
     pub db_path: PathBuf,
@@ -19,6 +25,23 @@ pub struct Config {
     pub komga: KomgaConfig,
     /// Navidrome instance configuration (optional)
     pub navidrome: Option<NavidromeConfig>,
+    /// Webhook URL to notify when a user's access expires (optional)
+    #[serde(rename = "notify-webhook")]
+    pub notify_webhook: Option<String>,
+    /// Public URL of k-librarian, used to build invite links (optional)
+    #[serde(rename = "public-url")]
+    pub public_url: Option<String>,
+    /// OpenID Connect provider to log in to the admin panel with (optional)
+    pub oidc: Option<OidcConfig>,
+    /// Trust the user given by a reverse proxy doing forward authentication (optional)
+    #[serde(rename = "proxy-auth")]
+    pub proxy_auth: Option<ProxyAuthConfig>,
+    /// Reverse proxies whose `X-Forwarded-For` header gives the real client address (optional)
+    #[serde(rename = "trusted-proxies", default)]
+    pub trusted_proxies: Vec<ipnet::IpNet>,
+    /// Limits on failed logins and invite token guesses, enabled by default
+    #[serde(rename = "rate-limit", default)]
+    pub rate_limit: RateLimitConfig,
 }
 
 /// Komga instance configuration
@@ -34,6 +57,109 @@ pub struct KomgaConfig {
     pub hostname: Option<String>,
 }
 
+/// OpenID Connect provider configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcConfig {
+    /// Issuer URL of the provider, used to discover its endpoints
+    pub issuer: String,
+    /// Client ID registered at the provider
+    #[serde(rename = "client-id")]
+    pub client_id: String,
+    /// Client secret, leave empty for a public client
+    #[serde(rename = "client-secret")]
+    pub client_secret: Option<String>,
+    /// Name of the provider shown on the login button
+    pub name: Option<String>,
+    /// Scopes to request besides `openid`
+    #[serde(default = "default_oidc_scopes")]
+    pub scopes: Vec<String>,
+    /// Claim holding the groups of the user
+    #[serde(rename = "groups-claim", default = "default_oidc_groups_claim")]
+    pub groups_claim: String,
+    /// The admin role given by each group, users in none of these groups cannot log in
+    pub roles: HashMap<String, AdminRole>,
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec![
+        "profile".to_string(),
+        "email".to_string(),
+        "groups".to_string(),
+    ]
+}
+
+fn default_oidc_groups_claim() -> String {
+    "groups".to_string()
+}
+
+/// Forward authentication configuration, for a reverse proxy that logs the admin in
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyAuthConfig {
+    /// Addresses of the reverse proxies, the headers of anyone else are ignored
+    #[serde(rename = "trusted-proxies")]
+    pub trusted_proxies: Vec<ipnet::IpNet>,
+    /// Header holding the username
+    #[serde(rename = "user-header", default = "default_proxy_user_header")]
+    pub user_header: String,
+    /// Header holding the comma separated groups of the user
+    #[serde(rename = "groups-header", default = "default_proxy_groups_header")]
+    pub groups_header: String,
+    /// The admin role given by each group, users in none of these groups are refused
+    pub roles: HashMap<String, AdminRole>,
+}
+
+fn default_proxy_user_header() -> String {
+    "Remote-User".to_string()
+}
+
+fn default_proxy_groups_header() -> String {
+    "Remote-Groups".to_string()
+}
+
+impl ProxyAuthConfig {
+    /// Whether a request coming from this address may set the headers
+    pub fn is_trusted(&self, ip: std::net::IpAddr) -> bool {
+        // an IPv4 client can show up as an IPv4-mapped IPv6 address on a dual stack socket
+        let ip = ip.to_canonical();
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// Brute-force protection configuration, durations are in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Whether failed attempts are limited at all
+    pub enabled: bool,
+    /// Failed attempts a single client can make within the window before being locked out
+    #[serde(rename = "max-attempts")]
+    pub max_attempts: u32,
+    /// Failed attempts everyone together can make within the window before all attempts are
+    /// refused, against guessing from many addresses
+    #[serde(rename = "global-max-attempts")]
+    pub global_max_attempts: u32,
+    /// How long failed attempts are counted
+    pub window: u64,
+    /// How long the first lockout lasts, every following one lasts twice as long
+    pub lockout: u64,
+    /// The longest a lockout can last
+    #[serde(rename = "max-lockout")]
+    pub max_lockout: u64,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_attempts: 5,
+            global_max_attempts: 100,
+            window: 5 * 60,
+            lockout: 60,
+            max_lockout: 60 * 60,
+        }
+    }
+}
+
 /// Navidrome instance configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NavidromeConfig {
@@ -61,6 +187,16 @@ impl Config {
         toml::from_str(content).with_context(|| "Failed to parse TOML configuration")
     }
 
+    /// Whether a request coming from this address may tell us the real client address
+    pub fn is_trusted_proxy(&self, ip: std::net::IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted_proxies.iter().any(|net| net.contains(&ip))
+            || self
+                .proxy_auth
+                .as_ref()
+                .is_some_and(|proxy_auth| proxy_auth.is_trusted(ip))
+    }
+
     /// Get the effective Komga hostname (hostname field or host field)
     pub fn komga_hostname(&self) -> &str {
         self.komga.hostname.as_deref().unwrap_or(&self.komga.host)
@@ -94,6 +230,14 @@ impl Config {
         if self.token.trim().is_empty() {
             anyhow::bail!("Auth token cannot be empty");
         }
+        if let Err(e) = crate::auth::validate_hashed_token(&self.token) {
+            anyhow::bail!("Auth token hash is invalid: {}", e);
+        }
+        if let Some(secret) = &self.token_totp_secret
+            && let Err(e) = crate::auth::totp(secret, crate::auth::SHARED_TOKEN_TOTP_ACCOUNT)
+        {
+            anyhow::bail!("Auth token TOTP secret is invalid: {}", e);
+        }
 
         // Validate Komga configuration
         if self.komga.host.trim().is_empty() {
@@ -119,6 +263,48 @@ impl Config {
             }
         }
 
+        // Validate OpenID Connect configuration if present
+        if let Some(ref oidc) = self.oidc {
+            if oidc.issuer.trim().is_empty() {
+                anyhow::bail!("OIDC issuer cannot be empty");
+            }
+            if oidc.client_id.trim().is_empty() {
+                anyhow::bail!("OIDC client ID cannot be empty");
+            }
+            if oidc.roles.is_empty() {
+                anyhow::bail!("OIDC roles cannot be empty, nobody would be able to log in");
+            }
+        }
+
+        // Validate forward authentication configuration if present
+        if let Some(ref proxy_auth) = self.proxy_auth {
+            if proxy_auth.trusted_proxies.is_empty() {
+                anyhow::bail!("Proxy auth needs at least one trusted proxy");
+            }
+            if axum::http::HeaderName::try_from(proxy_auth.user_header.as_str()).is_err() {
+                anyhow::bail!("Proxy auth user header is not a valid header name");
+            }
+            if axum::http::HeaderName::try_from(proxy_auth.groups_header.as_str()).is_err() {
+                anyhow::bail!("Proxy auth groups header is not a valid header name");
+            }
+            if proxy_auth.roles.is_empty() {
+                anyhow::bail!("Proxy auth roles cannot be empty, nobody would be able to log in");
+            }
+        }
+
+        // Validate rate limit configuration
+        if self.rate_limit.enabled {
+            if self.rate_limit.max_attempts == 0 || self.rate_limit.global_max_attempts == 0 {
+                anyhow::bail!("Rate limit attempts must be at least 1");
+            }
+            if self.rate_limit.window == 0 || self.rate_limit.lockout == 0 {
+                anyhow::bail!("Rate limit window and lockout cannot be 0");
+            }
+            if self.rate_limit.max_lockout < self.rate_limit.lockout {
+                anyhow::bail!("Rate limit max-lockout cannot be shorter than the lockout");
+            }
+        }
+
         Ok(())
     }
 }
@@ -129,6 +315,7 @@ impl Default for Config {
             host: "127.0.0.1".to_string(),
             port: 5148,
             token: "this-is-your-auth-token".to_string(),
+            token_totp_secret: None,
             db_path: PathBuf::from("./.klibrarian/database.sqlite"),
             komga: KomgaConfig {
                 host: "https://demo.komga.org".to_string(),
@@ -137,6 +324,12 @@ impl Default for Config {
                 hostname: None,
             },
             navidrome: None,
+            notify_webhook: None,
+            public_url: None,
+            oidc: None,
+            proxy_auth: None,
+            trusted_proxies: vec![],
+            rate_limit: RateLimitConfig::default(),
         }
     }
 }
@@ -5,6 +5,9 @@ use sqlx::sqlite::SqliteConnectOptions;
 use crate::komga::KomgaUserCreateOptionSharedLibraries;
 
 const TOKEN_PREFIX: &str = "kli_";
+/// The columns read back into an `InviteRow`, `created_at` is stored as text by SQLite
+const INVITE_COLUMNS: &str = "token, option, uuid, kind, missing_libraries, note, email, \
+    paused_at, updated_at, tags, CAST(strftime('%s', created_at) AS INTEGER) AS created_at, created_by";
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct KomgaInviteOption {
@@ -18,6 +21,35 @@ pub struct KomgaInviteOption {
     pub expire_at: Option<u64>,
     #[serde(rename = "roles")]
     pub roles: Option<Vec<String>>,
+    /// How long the created account keeps its access, in seconds
+    #[serde(rename = "accessDuration", default)]
+    pub access_duration: Option<u64>,
+    #[serde(rename = "accessExpiryAction", default)]
+    pub access_expiry_action: AccessExpiryAction,
+    /// The restriction profile to use instead of the restrictions above
+    #[serde(rename = "profileId", default)]
+    pub profile_id: Option<uuid::Uuid>,
+    /// Create an API key for the new user, to be used by e-reader apps
+    #[serde(rename = "issueApiKey", default)]
+    pub issue_api_key: bool,
+    /// Not supported, Komga cannot change the email of an account so invites with it are refused.
+    /// Only Navidrome accounts can be handed over.
+    #[serde(rename = "userId", default)]
+    pub user_id: Option<String>,
+    /// Pick the username and password for the invitee instead of asking for them
+    #[serde(rename = "generateCredentials", default)]
+    pub generate_credentials: bool,
+}
+
+impl KomgaInviteOption {
+    /// Replace the restrictions of this invite with the one from the profile
+    pub fn with_profile(mut self, profile: &KomgaProfileOption) -> Self {
+        self.labels_allow = profile.labels_allow.clone();
+        self.labels_exclude = profile.labels_exclude.clone();
+        self.shared_libraries = profile.shared_libraries.clone();
+        self.roles = profile.roles.clone();
+        self
+    }
 }
 
 #[derive(serde::Serialize, serde::Deserialize, Clone)]
@@ -26,10 +58,445 @@ pub struct NavidromeInviteOption {
     pub is_admin: bool,
     #[serde(rename = "expiresAt")]
     pub expire_at: Option<u64>,
+    #[serde(rename = "libraryIds", default)]
+    pub library_ids: Vec<u64>,
+    /// How long the created account keeps its access, in seconds
+    #[serde(rename = "accessDuration", default)]
+    pub access_duration: Option<u64>,
+    #[serde(rename = "accessExpiryAction", default)]
+    pub access_expiry_action: AccessExpiryAction,
+    /// The restriction profile to use instead of the restrictions above
+    #[serde(rename = "profileId", default)]
+    pub profile_id: Option<uuid::Uuid>,
+    /// Hand over this existing account instead of creating a new one
+    #[serde(rename = "userId", default)]
+    pub user_id: Option<String>,
+    /// Pick the username and password for the invitee instead of asking for them
+    #[serde(rename = "generateCredentials", default)]
+    pub generate_credentials: bool,
+}
+
+impl NavidromeInviteOption {
+    /// Replace the restrictions of this invite with the one from the profile
+    pub fn with_profile(mut self, profile: &NavidromeProfileOption) -> Self {
+        self.library_ids = profile.library_ids.clone();
+        self
+    }
+}
+
+/// The restrictions a Komga profile applies to its users
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct KomgaProfileOption {
+    #[serde(rename = "labelsAllow")]
+    pub labels_allow: Option<Vec<String>>,
+    #[serde(rename = "labelsExclude")]
+    pub labels_exclude: Option<Vec<String>>,
+    #[serde(rename = "sharedLibraries")]
+    pub shared_libraries: Option<KomgaUserCreateOptionSharedLibraries>,
+    #[serde(rename = "roles")]
+    pub roles: Option<Vec<String>>,
+}
+
+/// The restrictions a Navidrome profile applies to its users
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+pub struct NavidromeProfileOption {
     #[serde(rename = "libraryIds")]
     pub library_ids: Vec<u64>,
 }
 
+/// A named set of restrictions that can be shared by multiple invites
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+#[serde(tag = "kind")]
+pub enum RestrictionProfile {
+    #[serde(rename = "komga")]
+    Komga {
+        id: uuid::Uuid,
+        name: String,
+        option: KomgaProfileOption,
+    },
+    #[serde(rename = "navidrome")]
+    Navidrome {
+        id: uuid::Uuid,
+        name: String,
+        option: NavidromeProfileOption,
+    },
+}
+
+impl RestrictionProfile {
+    pub fn id(&self) -> uuid::Uuid {
+        match self {
+            RestrictionProfile::Komga { id, .. } => *id,
+            RestrictionProfile::Navidrome { id, .. } => *id,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            RestrictionProfile::Komga { name, .. } => name,
+            RestrictionProfile::Navidrome { name, .. } => name,
+        }
+    }
+
+    pub fn kind(&self) -> &str {
+        match self {
+            RestrictionProfile::Komga { .. } => "komga",
+            RestrictionProfile::Navidrome { .. } => "navidrome",
+        }
+    }
+
+    pub fn option_str(&self) -> Result<String, serde_json::Error> {
+        match self {
+            RestrictionProfile::Komga { option, .. } => serde_json::to_string(option),
+            RestrictionProfile::Navidrome { option, .. } => serde_json::to_string(option),
+        }
+    }
+
+    /// Whether the users of this profile are admins of their server
+    pub fn grants_admin(&self) -> bool {
+        match self {
+            RestrictionProfile::Komga { option, .. } => has_admin_role(option.roles.as_deref()),
+            RestrictionProfile::Navidrome { .. } => false,
+        }
+    }
+}
+
+fn has_admin_role(roles: Option<&[String]>) -> bool {
+    roles.is_some_and(|roles| roles.iter().any(|role| role.eq_ignore_ascii_case("ADMIN")))
+}
+
+/// What to do with an account once its access duration has passed
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AccessExpiryAction {
+    /// Remove every library from the user but keep the account
+    #[default]
+    #[serde(rename = "revoke")]
+    Revoke,
+    /// Delete the user entirely
+    #[serde(rename = "delete")]
+    Delete,
+}
+
+impl AccessExpiryAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessExpiryAction::Revoke => "revoke",
+            AccessExpiryAction::Delete => "delete",
+        }
+    }
+}
+
+impl std::str::FromStr for AccessExpiryAction {
+    type Err = std::convert::Infallible;
+
+    /// Anything unknown falls back to the least destructive action
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(match value {
+            "delete" => AccessExpiryAction::Delete,
+            _ => AccessExpiryAction::Revoke,
+        })
+    }
+}
+
+/// A user that has been created in Komga or Navidrome through an invite
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct ProvisionedUser {
+    /// The user ID in the upstream server
+    pub id: String,
+    pub kind: String,
+    /// The login name of the user, email for Komga and username for Navidrome
+    pub username: Option<String>,
+    /// The invite token the user was created from, empty for imported users
+    pub token: Option<TokenId>,
+    /// The restriction profile the user was created with
+    #[serde(rename = "profileId")]
+    pub profile_id: Option<uuid::Uuid>,
+    /// The restrictions the user was granted, the shape depends on the kind
+    pub restrictions: Option<serde_json::Value>,
+    #[serde(rename = "accessExpiresAt")]
+    pub access_expires_at: Option<u64>,
+    #[serde(rename = "accessExpiryAction")]
+    pub access_expiry_action: AccessExpiryAction,
+    /// When the expiry action has been carried out
+    #[serde(rename = "expiredAt")]
+    pub expired_at: Option<u64>,
+    /// How many times the expiry action failed, it is given up after too many
+    #[serde(rename = "expiryFailures")]
+    pub expiry_failures: u32,
+    /// Differences found between the granted restrictions and the upstream user
+    pub drift: Vec<UserDrift>,
+    /// When the user was last compared against the upstream server
+    #[serde(rename = "checkedAt")]
+    pub checked_at: Option<u64>,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<String>,
+}
+
+/// A single difference between what k-librarian granted and what the upstream server has
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct UserDrift {
+    pub field: String,
+    pub expected: serde_json::Value,
+    pub actual: serde_json::Value,
+}
+
+impl UserDrift {
+    pub fn new(
+        field: impl Into<String>,
+        expected: impl serde::Serialize,
+        actual: impl serde::Serialize,
+    ) -> Self {
+        Self {
+            field: field.into(),
+            expected: serde_json::to_value(expected).unwrap_or_default(),
+            actual: serde_json::to_value(actual).unwrap_or_default(),
+        }
+    }
+
+    /// The user no longer exists upstream
+    pub fn deleted() -> Self {
+        Self::new("account", "exists", "deleted")
+    }
+}
+
+impl ProvisionedUser {
+    pub fn from_invite(
+        invite: &InviteToken,
+        user_id: &str,
+        restrictions: serde_json::Value,
+    ) -> Self {
+        let (access_duration, access_expiry_action, profile_id) = match invite {
+            InviteToken::Komga { option, .. } => (
+                option.access_duration,
+                option.access_expiry_action,
+                option.profile_id,
+            ),
+            InviteToken::Navidrome { option, .. } => (
+                option.access_duration,
+                option.access_expiry_action,
+                option.profile_id,
+            ),
+        };
+
+        Self {
+            id: user_id.to_string(),
+            kind: invite.kind().to_string(),
+            username: None,
+            token: Some(invite.token()),
+            profile_id,
+            restrictions: Some(restrictions),
+            access_expires_at: access_duration.map(|duration| unix_now() + duration),
+            access_expiry_action,
+            expired_at: None,
+            expiry_failures: 0,
+            drift: vec![],
+            checked_at: None,
+            created_at: None,
+        }
+    }
+
+    /// A user that already existed upstream before k-librarian knew about it
+    pub fn imported(
+        kind: &str,
+        user_id: &str,
+        username: &str,
+        restrictions: serde_json::Value,
+    ) -> Self {
+        Self {
+            id: user_id.to_string(),
+            kind: kind.to_string(),
+            username: Some(username.to_string()),
+            token: None,
+            profile_id: None,
+            restrictions: Some(restrictions),
+            access_expires_at: None,
+            access_expiry_action: AccessExpiryAction::default(),
+            expired_at: None,
+            expiry_failures: 0,
+            drift: vec![],
+            checked_at: None,
+            created_at: None,
+        }
+    }
+
+    pub fn with_username(mut self, username: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self
+    }
+
+    pub fn is_access_expired(&self) -> bool {
+        self.expired_at.is_some()
+    }
+}
+
+pub fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
+}
+
+/// What an admin account is allowed to do
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum AdminRole {
+    /// Everything, including managing the other admins
+    Owner,
+    /// Can look at and create invites
+    Inviter,
+    /// Can only look at the invites
+    Viewer,
+}
+
+impl AdminRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AdminRole::Owner => "owner",
+            AdminRole::Inviter => "inviter",
+            AdminRole::Viewer => "viewer",
+        }
+    }
+
+    pub fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "owner" => Some(AdminRole::Owner),
+            "inviter" => Some(AdminRole::Inviter),
+            "viewer" => Some(AdminRole::Viewer),
+            _ => None,
+        }
+    }
+}
+
+/// An account that can log in to the admin panel
+#[derive(serde::Serialize, Clone)]
+pub struct Admin {
+    pub id: uuid::Uuid,
+    pub username: String,
+    pub role: AdminRole,
+    #[serde(skip)]
+    pub password_hash: String,
+    #[serde(rename = "createdAt")]
+    pub created_at: Option<u64>,
+    /// Base32 TOTP secret, set during enrollment before being enabled
+    #[serde(skip)]
+    pub totp_secret: Option<String>,
+    #[serde(rename = "totpEnabled")]
+    pub totp_enabled: bool,
+    /// The last accepted TOTP step, a code is only accepted once
+    #[serde(skip)]
+    pub totp_last_step: Option<u64>,
+    /// Hashes of the unused recovery codes
+    #[serde(skip)]
+    pub recovery_codes: Vec<String>,
+    /// The subject at the OpenID Connect provider, for admins created by logging in there
+    #[serde(rename = "oidcSubject")]
+    pub oidc_subject: Option<String>,
+}
+
+/// What an API key is allowed to do, a key can have several scopes
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum ApiKeyScope {
+    /// Create new invites, alone or in bulk, and edit them
+    CreateInvite,
+    /// List and look at the invites
+    ReadInvites,
+    /// Delete or pause invites
+    Revoke,
+    /// Read the server info and manage the restriction profiles
+    Config,
+}
+
+/// A WebAuthn credential an admin can log in with instead of their password
+#[derive(serde::Serialize, Clone)]
+pub struct Passkey {
+    /// The base64url credential ID chosen by the authenticator
+    pub id: String,
+    #[serde(rename = "adminId")]
+    pub admin_id: uuid::Uuid,
+    pub name: String,
+    /// Base64url SEC1 encoded P-256 public key
+    #[serde(skip)]
+    pub public_key: String,
+    #[serde(skip)]
+    pub sign_count: u32,
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+    #[serde(rename = "lastUsedAt")]
+    pub last_used_at: Option<u64>,
+}
+
+/// A key for scripts and bots, used as a bearer token like a session
+#[derive(serde::Serialize, Clone)]
+pub struct ApiKey {
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    #[serde(skip)]
+    pub key_hash: String,
+    #[serde(rename = "createdBy")]
+    pub created_by: Option<String>,
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<u64>,
+    #[serde(rename = "lastUsedAt")]
+    pub last_used_at: Option<u64>,
+}
+
+/// A logged in admin panel session, kept in a cookie
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub token_hash: String,
+    /// Empty for a login with the shared token
+    pub admin_id: Option<uuid::Uuid>,
+    /// Has to be sent back in the `X-CSRF-Token` header for every change
+    pub csrf_token: String,
+    pub expires_at: u64,
+}
+
+/// Bookkeeping data for an invite that is not part of the invite options
+#[derive(serde::Serialize, serde::Deserialize, Clone, Default)]
+pub struct InviteMetadata {
+    /// Shared libraries of the invite that no longer exist upstream
+    #[serde(rename = "missingLibraries", default)]
+    pub missing_libraries: Vec<String>,
+    /// Free text for the admin, e.g. who the invite was handed to
+    #[serde(default)]
+    pub note: Option<String>,
+    /// The only email that can redeem this invite
+    #[serde(default)]
+    pub email: Option<String>,
+    /// When the invite was paused, a paused invite cannot be redeemed
+    #[serde(rename = "pausedAt", default)]
+    pub paused_at: Option<u64>,
+    /// When the options or the paused state last changed
+    #[serde(rename = "updatedAt", default)]
+    pub updated_at: Option<u64>,
+    /// Free labels to group invites, e.g. the event they were created for
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(rename = "createdAt", default)]
+    pub created_at: Option<u64>,
+    /// Username of the admin who created the invite, empty when created with the shared token
+    #[serde(rename = "createdBy", default)]
+    pub created_by: Option<String>,
+}
+
+/// What the invitee gets to see of an invite, without anything meant for the admins
+#[derive(serde::Serialize)]
+pub struct PublicInvite {
+    pub token: TokenId,
+    pub kind: &'static str,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<u64>,
+    /// Whether the invitee has to pick their own username, email and password
+    #[serde(rename = "emailRequired")]
+    pub email_required: bool,
+    /// Whether an existing account is handed over instead of a new one being made
+    pub handover: bool,
+    pub paused: bool,
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 #[serde(tag = "kind")]
 pub enum InviteToken {
@@ -38,12 +505,16 @@ pub enum InviteToken {
         token: TokenId,
         option: KomgaInviteOption,
         uuid: Option<String>,
+        #[serde(flatten)]
+        meta: InviteMetadata,
     },
     #[serde(rename = "navidrome")]
     Navidrome {
         token: TokenId,
         option: NavidromeInviteOption,
         uuid: Option<String>,
+        #[serde(flatten)]
+        meta: InviteMetadata,
     },
 }
 
@@ -56,10 +527,7 @@ impl InviteToken {
     }
 
     pub fn is_expired(&self) -> bool {
-        let unix_time = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .expect("Time went backwards")
-            .as_secs();
+        let unix_time = unix_now();
 
         match self {
             InviteToken::Komga { option, .. } => {
@@ -100,149 +568,1425 @@ impl InviteToken {
         }
     }
 
-    pub fn kind(&self) -> &str {
-        match self {
-            InviteToken::Komga { .. } => "komga",
-            InviteToken::Navidrome { .. } => "navidrome",
-        }
+    pub fn kind(&self) -> &str {
+        match self {
+            InviteToken::Komga { .. } => "komga",
+            InviteToken::Navidrome { .. } => "navidrome",
+        }
+    }
+
+    /// Apply a partial update to the options, keys that are not given keep their value
+    pub fn merge_option(
+        &mut self,
+        changes: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<(), serde_json::Error> {
+        let mut merged = self.option();
+        if let Some(object) = merged.as_object_mut() {
+            object.extend(changes);
+        }
+
+        match self {
+            InviteToken::Komga { option, .. } => *option = serde_json::from_value(merged)?,
+            InviteToken::Navidrome { option, .. } => *option = serde_json::from_value(merged)?,
+        }
+
+        Ok(())
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.meta().paused_at.is_some()
+    }
+
+    pub fn meta(&self) -> &InviteMetadata {
+        match self {
+            InviteToken::Komga { meta, .. } => meta,
+            InviteToken::Navidrome { meta, .. } => meta,
+        }
+    }
+
+    pub fn profile_id(&self) -> Option<uuid::Uuid> {
+        match self {
+            InviteToken::Komga { option, .. } => option.profile_id,
+            InviteToken::Navidrome { option, .. } => option.profile_id,
+        }
+    }
+
+    fn meta_mut(&mut self) -> &mut InviteMetadata {
+        match self {
+            InviteToken::Komga { meta, .. } => meta,
+            InviteToken::Navidrome { meta, .. } => meta,
+        }
+    }
+
+    /// Attach a note and a bound email to a freshly created invite
+    pub fn with_note_and_email(mut self, note: Option<String>, email: Option<String>) -> Self {
+        let meta = self.meta_mut();
+        meta.note = note;
+        meta.email = email;
+        self
+    }
+
+    pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+        self.meta_mut().tags = tags;
+        self
+    }
+
+    pub fn with_creator(mut self, created_by: Option<String>) -> Self {
+        self.meta_mut().created_by = created_by;
+        self
+    }
+
+    /// Whether the account made by this invite is an admin of its server, not counting its profile
+    pub fn grants_admin(&self) -> bool {
+        match self {
+            InviteToken::Komga { option, .. } => has_admin_role(option.roles.as_deref()),
+            InviteToken::Navidrome { option, .. } => option.is_admin,
+        }
+    }
+
+    /// The pre-provisioned account this invite hands over, if any
+    pub fn handover_user_id(&self) -> Option<&str> {
+        match self {
+            InviteToken::Komga { option, .. } => option.user_id.as_deref(),
+            InviteToken::Navidrome { option, .. } => option.user_id.as_deref(),
+        }
+    }
+
+    pub fn generates_credentials(&self) -> bool {
+        match self {
+            InviteToken::Komga { option, .. } => option.generate_credentials,
+            InviteToken::Navidrome { option, .. } => option.generate_credentials,
+        }
+    }
+
+    pub fn public_view(&self) -> PublicInvite {
+        let (kind, expires_at) = match self {
+            InviteToken::Komga { option, .. } => ("komga", option.expire_at),
+            InviteToken::Navidrome { option, .. } => ("navidrome", option.expire_at),
+        };
+
+        PublicInvite {
+            token: self.token(),
+            kind,
+            expires_at,
+            email_required: !self.generates_credentials(),
+            handover: self.handover_user_id().is_some(),
+            paused: self.is_paused(),
+        }
+    }
+
+    pub fn create_komga(option: KomgaInviteOption) -> Self {
+        InviteToken::Komga {
+            token: TokenId::new(),
+            uuid: option.user_id.clone(),
+            option,
+            meta: InviteMetadata {
+                created_at: Some(unix_now()),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn create_navidrome(option: NavidromeInviteOption) -> Self {
+        InviteToken::Navidrome {
+            token: TokenId::new(),
+            uuid: option.user_id.clone(),
+            option,
+            meta: InviteMetadata {
+                created_at: Some(unix_now()),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LocalDatabase {
+    pool: sqlx::Pool<sqlx::Sqlite>,
+}
+
+impl LocalDatabase {
+    pub async fn new(path: &PathBuf) -> Result<Self, sqlx::Error> {
+        tracing::info!("📂 Using database at: {}", path.display());
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+
+        let pool = sqlx::SqlitePool::connect_with(options).await?;
+        Ok(Self { pool })
+    }
+
+    pub async fn setup(&self) -> Result<(), sqlx::Error> {
+        // Create tables if they do not exist
+        sqlx::query(
+            r#"CREATE TABLE IF NOT EXISTS invites (
+                token TEXT PRIMARY KEY,
+                option TEXT NOT NULL,
+                uuid TEXT,
+                kind TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        self.add_column_if_missing("invites", "missing_libraries", "TEXT")
+            .await?;
+        self.add_column_if_missing("invites", "note", "TEXT")
+            .await?;
+        self.add_column_if_missing("invites", "email", "TEXT")
+            .await?;
+        self.add_column_if_missing("invites", "paused_at", "INTEGER")
+            .await?;
+        self.add_column_if_missing("invites", "updated_at", "INTEGER")
+            .await?;
+        self.add_column_if_missing("invites", "tags", "TEXT")
+            .await?;
+        self.add_column_if_missing("invites", "created_by", "TEXT")
+            .await?;
+
+        sqlx::query(
+            r#"CREATE TABLE IF NOT EXISTS users (
+                id TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                token TEXT,
+                access_expires_at INTEGER,
+                access_expiry_action TEXT NOT NULL DEFAULT 'revoke',
+                expired_at INTEGER,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                PRIMARY KEY (kind, id)
+            )"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        self.add_column_if_missing("users", "profile_id", "TEXT")
+            .await?;
+        self.add_column_if_missing("users", "restrictions", "TEXT")
+            .await?;
+        self.add_column_if_missing("users", "drift", "TEXT").await?;
+        self.add_column_if_missing("users", "checked_at", "INTEGER")
+            .await?;
+        self.add_column_if_missing("users", "username", "TEXT")
+            .await?;
+        self.add_column_if_missing("users", "expiry_failures", "INTEGER NOT NULL DEFAULT 0")
+            .await?;
+
+        sqlx::query(
+            r#"CREATE TABLE IF NOT EXISTS profiles (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL UNIQUE,
+                kind TEXT NOT NULL,
+                option TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )"#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(
+            r#"CREATE TABLE IF NOT EXISTS admins (
+                id TEXT PRIMARY KEY,
+                username TEXT NOT NULL UNIQUE,
+                password_hash TEXT NOT NULL,
+                role TEXT NOT NULL,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        self.add_column_if_missing("admins", "totp_secret", "TEXT")
+            .await?;
+        self.add_column_if_missing("admins", "totp_enabled", "INTEGER NOT NULL DEFAULT 0")
+            .await?;
+        self.add_column_if_missing("admins", "totp_last_step", "INTEGER")
+            .await?;
+        self.add_column_if_missing("admins", "recovery_codes", "TEXT")
+            .await?;
+        self.add_column_if_missing("admins", "oidc_subject", "TEXT")
+            .await?;
+        sqlx::query(
+            "CREATE UNIQUE INDEX IF NOT EXISTS admins_oidc_subject ON admins (oidc_subject)",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // only the hash of the session token is stored, a leaked database cannot be used to log in
+        sqlx::query(
+            r#"CREATE TABLE IF NOT EXISTS sessions (
+                token_hash TEXT PRIMARY KEY,
+                admin_id TEXT,
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL
+            )"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        self.add_column_if_missing("sessions", "csrf_token", "TEXT")
+            .await?;
+        self.add_column_if_missing("sessions", "last_seen_at", "INTEGER")
+            .await?;
+
+        sqlx::query(
+            r#"CREATE TABLE IF NOT EXISTS passkeys (
+                id TEXT PRIMARY KEY,
+                admin_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                public_key TEXT NOT NULL,
+                sign_count INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL,
+                last_used_at INTEGER
+            )"#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // the logins sent to the OpenID Connect provider that did not come back yet
+        sqlx::query(
+            r#"CREATE TABLE IF NOT EXISTS oidc_logins (
+                state TEXT PRIMARY KEY,
+                pkce_verifier TEXT NOT NULL,
+                nonce TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            )"#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // the challenges of the passkey ceremonies that are still in progress
+        sqlx::query(
+            r#"CREATE TABLE IF NOT EXISTS passkey_challenges (
+                id TEXT PRIMARY KEY,
+                admin_id TEXT,
+                kind TEXT NOT NULL,
+                challenge TEXT NOT NULL,
+                expires_at INTEGER NOT NULL
+            )"#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // same as the sessions, only the hash of the key is stored
+        sqlx::query(
+            r#"CREATE TABLE IF NOT EXISTS api_keys (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                key_hash TEXT NOT NULL UNIQUE,
+                scopes TEXT NOT NULL,
+                created_by TEXT,
+                created_at INTEGER NOT NULL,
+                expires_at INTEGER,
+                last_used_at INTEGER
+            )"#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Add a column to an existing table, used for tables created by older versions
+    async fn add_column_if_missing(
+        &self,
+        table: &str,
+        column: &str,
+        definition: &str,
+    ) -> Result<(), sqlx::Error> {
+        let columns: Vec<(String,)> =
+            sqlx::query_as(&format!("SELECT name FROM pragma_table_info('{table}')"))
+                .fetch_all(&self.pool)
+                .await?;
+
+        if !columns.iter().any(|(name,)| name == column) {
+            sqlx::query(&format!(
+                "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+            ))
+            .execute(&self.pool)
+            .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn add_invite(&self, invite: &InviteToken) -> Result<(), LocalDatabaseError> {
+        insert_invite(&self.pool, invite).await
+    }
+
+    /// Add several invites at once, either all of them are stored or none
+    pub async fn add_invites(&self, invites: &[InviteToken]) -> Result<(), LocalDatabaseError> {
+        let mut transaction = self.pool.begin().await?;
+
+        for invite in invites {
+            insert_invite(&mut *transaction, invite).await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn get_invite(
+        &self,
+        token: TokenId,
+    ) -> Result<Option<InviteToken>, LocalDatabaseError> {
+        let row: Option<InviteRow> = sqlx::query_as(&format!(
+            r#"
+            SELECT {INVITE_COLUMNS} FROM invites
+            WHERE token = ? OR token = ?
+            "#,
+        ))
+        .bind(token.to_string())
+        .bind(token.0.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => {
+                let invite = cast_sql_row_to_invite_token(row)?;
+                Ok(Some(invite))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub async fn delete_invite(&self, token: TokenId) -> Result<(), LocalDatabaseError> {
+        sqlx::query("DELETE FROM invites WHERE token = ? OR token = ?")
+            .bind(token.to_string())
+            .bind(token.0.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn apply_user_id(
+        &self,
+        token: TokenId,
+        user_id: &str,
+    ) -> Result<(), LocalDatabaseError> {
+        sqlx::query("UPDATE invites SET uuid = ? WHERE token = ? OR token = ?")
+            .bind(user_id)
+            .bind(token.to_string())
+            .bind(token.0.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Forget the user of every invite that was bound to it, used when the user is deleted upstream
+    pub async fn clear_invite_user_id(
+        &self,
+        kind: &str,
+        user_id: &str,
+    ) -> Result<u64, LocalDatabaseError> {
+        let result = sqlx::query("UPDATE invites SET uuid = NULL WHERE kind = ? AND uuid = ?")
+            .bind(kind)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Replace the options of an invite, the kind of an invite cannot change
+    pub async fn update_invite_option(
+        &self,
+        invite: &InviteToken,
+    ) -> Result<(), LocalDatabaseError> {
+        sqlx::query("UPDATE invites SET option = ?, updated_at = ? WHERE token = ? OR token = ?")
+            .bind(invite.option_str()?)
+            .bind(unix_now() as i64)
+            .bind(invite.token().to_string())
+            .bind(invite.token().0.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Change the note and the tags of an invite, `None` keeps the current value
+    pub async fn set_invite_note_and_tags(
+        &self,
+        token: TokenId,
+        note: Option<&str>,
+        tags: Option<&[String]>,
+    ) -> Result<(), LocalDatabaseError> {
+        let tags = tags.map(serde_json::to_string).transpose()?;
+
+        // an empty note clears it
+        sqlx::query(
+            r#"
+            UPDATE invites SET
+                note = CASE WHEN ? THEN NULLIF(?, '') ELSE note END,
+                tags = COALESCE(?, tags),
+                updated_at = ?
+            WHERE token = ? OR token = ?
+            "#,
+        )
+        .bind(note.is_some())
+        .bind(note)
+        .bind(tags)
+        .bind(unix_now() as i64)
+        .bind(token.to_string())
+        .bind(token.0.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_invite_paused(
+        &self,
+        token: TokenId,
+        paused: bool,
+    ) -> Result<(), LocalDatabaseError> {
+        let now = unix_now() as i64;
+
+        // keep the original pause time when pausing an already paused invite
+        sqlx::query(
+            r#"
+            UPDATE invites SET
+                paused_at = CASE WHEN ? THEN COALESCE(paused_at, ?) ELSE NULL END,
+                updated_at = ?
+            WHERE token = ? OR token = ?
+            "#,
+        )
+        .bind(paused)
+        .bind(now)
+        .bind(now)
+        .bind(token.to_string())
+        .bind(token.0.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_invite_missing_libraries(
+        &self,
+        token: TokenId,
+        missing_libraries: &[String],
+    ) -> Result<(), LocalDatabaseError> {
+        sqlx::query("UPDATE invites SET missing_libraries = ? WHERE token = ? OR token = ?")
+            .bind(serde_json::to_string(missing_libraries)?)
+            .bind(token.to_string())
+            .bind(token.0.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_all_invites(&self) -> Result<Vec<InviteToken>, LocalDatabaseError> {
+        let rows: Vec<InviteRow> = sqlx::query_as(&format!(
+            r#"
+            SELECT {INVITE_COLUMNS} FROM invites
+            "#,
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut invites: Vec<InviteToken> = Vec::new();
+        for row in rows {
+            // do this to bubble up any errors
+            let invite = cast_sql_row_to_invite_token(row)?;
+            invites.push(invite);
+        }
+
+        Ok(invites)
+    }
+
+    /// Find invites matching the filter, returning the requested page and the total count
+    pub async fn search_invites(
+        &self,
+        filter: &InviteFilter,
+    ) -> Result<(Vec<InviteToken>, u64), LocalDatabaseError> {
+        let mut count_query = sqlx::QueryBuilder::new("SELECT COUNT(*) FROM invites");
+        filter.push_conditions(&mut count_query);
+        let (total,): (i64,) = count_query.build_query_as().fetch_one(&self.pool).await?;
+
+        let mut query = sqlx::QueryBuilder::new(format!("SELECT {INVITE_COLUMNS} FROM invites"));
+        filter.push_conditions(&mut query);
+
+        let sort_column = match filter.sort {
+            InviteSort::CreatedAt => "invites.created_at",
+            InviteSort::ExpiresAt => "json_extract(invites.option, '$.expiresAt')",
+        };
+        let order = match filter.order {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+        // invites without expiry never expire, so they go last when sorting by expiry
+        query.push(format!(
+            " ORDER BY {sort_column} IS NULL, {sort_column} {order}, invites.rowid {order}"
+        ));
+
+        if let Some(per_page) = filter.per_page {
+            query.push(" LIMIT ");
+            query.push_bind(per_page as i64);
+            query.push(" OFFSET ");
+            query.push_bind(filter.offset().unwrap_or(i64::MAX));
+        }
+
+        let rows: Vec<InviteRow> = query.build_query_as().fetch_all(&self.pool).await?;
+        let invites = rows
+            .into_iter()
+            .map(cast_sql_row_to_invite_token)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((invites, total as u64))
+    }
+
+    pub async fn add_user(&self, user: &ProvisionedUser) -> Result<(), LocalDatabaseError> {
+        // a retried redemption would insert the same user again, so refresh the row instead
+        sqlx::query(
+            r#"
+            INSERT INTO users (id, kind, username, token, profile_id, restrictions, access_expires_at, access_expiry_action)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (kind, id) DO UPDATE SET
+                username = excluded.username,
+                token = excluded.token,
+                profile_id = excluded.profile_id,
+                restrictions = excluded.restrictions,
+                access_expires_at = excluded.access_expires_at,
+                access_expiry_action = excluded.access_expiry_action
+            "#,
+        )
+        .bind(&user.id)
+        .bind(&user.kind)
+        .bind(&user.username)
+        .bind(user.token.map(|t| t.to_string()))
+        .bind(user.profile_id.map(|p| p.to_string()))
+        .bind(
+            user.restrictions
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?,
+        )
+        .bind(user.access_expires_at.map(|t| t as i64))
+        .bind(user.access_expiry_action.as_str())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Add users that are not known yet, returning how many were added
+    pub async fn import_users(&self, users: &[ProvisionedUser]) -> Result<u64, LocalDatabaseError> {
+        let mut transaction = self.pool.begin().await?;
+
+        let mut imported = 0;
+        for user in users {
+            let result = sqlx::query(
+                r#"
+                INSERT INTO users (id, kind, username, restrictions)
+                VALUES (?, ?, ?, ?)
+                ON CONFLICT (kind, id) DO NOTHING
+                "#,
+            )
+            .bind(&user.id)
+            .bind(&user.kind)
+            .bind(&user.username)
+            .bind(
+                user.restrictions
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?,
+            )
+            .execute(&mut *transaction)
+            .await?;
+
+            imported += result.rows_affected();
+        }
+
+        transaction.commit().await?;
+
+        Ok(imported)
+    }
+
+    pub async fn get_user(
+        &self,
+        kind: &str,
+        user_id: &str,
+    ) -> Result<Option<ProvisionedUser>, LocalDatabaseError> {
+        let row: Option<UserRow> = sqlx::query_as(
+            r#"
+            SELECT * FROM users WHERE kind = ? AND id = ?
+            "#,
+        )
+        .bind(kind)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match row {
+            Some(row) => Ok(Some(cast_sql_row_to_user(row)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn get_all_users(&self) -> Result<Vec<ProvisionedUser>, LocalDatabaseError> {
+        let rows: Vec<UserRow> = sqlx::query_as(
+            r#"
+            SELECT * FROM users
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(cast_sql_row_to_user).collect()
+    }
+
+    /// Get all users whose access has run out but has not been acted upon yet, skipping the ones
+    /// that failed `max_failures` times already
+    pub async fn get_users_pending_expiry(
+        &self,
+        max_failures: u32,
+    ) -> Result<Vec<ProvisionedUser>, LocalDatabaseError> {
+        let rows: Vec<UserRow> = sqlx::query_as(
+            r#"
+            SELECT * FROM users
+            WHERE access_expires_at IS NOT NULL AND access_expires_at <= ? AND expired_at IS NULL
+                AND expiry_failures < ?
+            "#,
+        )
+        .bind(unix_now() as i64)
+        .bind(max_failures as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(cast_sql_row_to_user).collect()
+    }
+
+    pub async fn set_user_access_expiry(
+        &self,
+        kind: &str,
+        user_id: &str,
+        access_expires_at: Option<u64>,
+    ) -> Result<(), LocalDatabaseError> {
+        // a new expiry gets a fresh set of attempts
+        sqlx::query(
+            "UPDATE users SET access_expires_at = ?, expiry_failures = 0 WHERE kind = ? AND id = ?",
+        )
+        .bind(access_expires_at.map(|t| t as i64))
+        .bind(kind)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_user(&self, kind: &str, user_id: &str) -> Result<(), LocalDatabaseError> {
+        sqlx::query("DELETE FROM users WHERE kind = ? AND id = ?")
+            .bind(kind)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn mark_user_expired(
+        &self,
+        kind: &str,
+        user_id: &str,
+    ) -> Result<(), LocalDatabaseError> {
+        sqlx::query("UPDATE users SET expired_at = ? WHERE kind = ? AND id = ?")
+            .bind(unix_now() as i64)
+            .bind(kind)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Count a failed expiry action, returns how many times it failed so far
+    pub async fn record_expiry_failure(
+        &self,
+        kind: &str,
+        user_id: &str,
+    ) -> Result<u32, LocalDatabaseError> {
+        let failures: i64 = sqlx::query_scalar(
+            r#"
+            UPDATE users SET expiry_failures = expiry_failures + 1
+            WHERE kind = ? AND id = ?
+            RETURNING expiry_failures
+            "#,
+        )
+        .bind(kind)
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(failures as u32)
+    }
+
+    /// Get all users whose access has not been taken away by k-librarian
+    pub async fn get_active_users(&self) -> Result<Vec<ProvisionedUser>, LocalDatabaseError> {
+        let rows: Vec<UserRow> = sqlx::query_as("SELECT * FROM users WHERE expired_at IS NULL")
+            .fetch_all(&self.pool)
+            .await?;
+
+        rows.into_iter().map(cast_sql_row_to_user).collect()
+    }
+
+    /// Get all users that differ from what they were granted
+    pub async fn get_drifted_users(&self) -> Result<Vec<ProvisionedUser>, LocalDatabaseError> {
+        let rows: Vec<UserRow> = sqlx::query_as(
+            "SELECT * FROM users WHERE drift IS NOT NULL AND drift != '[]' AND expired_at IS NULL",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(cast_sql_row_to_user).collect()
+    }
+
+    pub async fn set_user_drift(
+        &self,
+        kind: &str,
+        user_id: &str,
+        drift: &[UserDrift],
+    ) -> Result<(), LocalDatabaseError> {
+        sqlx::query("UPDATE users SET drift = ?, checked_at = ? WHERE kind = ? AND id = ?")
+            .bind(serde_json::to_string(drift)?)
+            .bind(unix_now() as i64)
+            .bind(kind)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_user_restrictions(
+        &self,
+        kind: &str,
+        user_id: &str,
+        restrictions: &serde_json::Value,
+    ) -> Result<(), LocalDatabaseError> {
+        sqlx::query("UPDATE users SET restrictions = ? WHERE kind = ? AND id = ?")
+            .bind(serde_json::to_string(restrictions)?)
+            .bind(kind)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Get all users created from the given profile that still have access
+    pub async fn get_users_by_profile(
+        &self,
+        profile_id: uuid::Uuid,
+    ) -> Result<Vec<ProvisionedUser>, LocalDatabaseError> {
+        let rows: Vec<UserRow> = sqlx::query_as(
+            r#"
+            SELECT * FROM users
+            WHERE profile_id = ? AND expired_at IS NULL
+            "#,
+        )
+        .bind(profile_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(cast_sql_row_to_user).collect()
+    }
+
+    pub async fn add_profile(
+        &self,
+        profile: &RestrictionProfile,
+    ) -> Result<(), LocalDatabaseError> {
+        sqlx::query(
+            r#"
+            INSERT INTO profiles (id, name, kind, option)
+            VALUES (?, ?, ?, ?)
+            "#,
+        )
+        .bind(profile.id().to_string())
+        .bind(profile.name())
+        .bind(profile.kind())
+        .bind(profile.option_str()?)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn update_profile(
+        &self,
+        profile: &RestrictionProfile,
+    ) -> Result<(), LocalDatabaseError> {
+        sqlx::query(
+            r#"
+            UPDATE profiles SET name = ?, option = ?, updated_at = CURRENT_TIMESTAMP
+            WHERE id = ?
+            "#,
+        )
+        .bind(profile.name())
+        .bind(profile.option_str()?)
+        .bind(profile.id().to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_profile(
+        &self,
+        id: uuid::Uuid,
+    ) -> Result<Option<RestrictionProfile>, LocalDatabaseError> {
+        let row: Option<(String, String, String, String)> =
+            sqlx::query_as("SELECT id, name, kind, option FROM profiles WHERE id = ?")
+                .bind(id.to_string())
+                .fetch_optional(&self.pool)
+                .await?;
+
+        match row {
+            Some(row) => Ok(Some(cast_sql_row_to_profile(row)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn get_all_profiles(&self) -> Result<Vec<RestrictionProfile>, LocalDatabaseError> {
+        let rows: Vec<(String, String, String, String)> =
+            sqlx::query_as("SELECT id, name, kind, option FROM profiles ORDER BY name")
+                .fetch_all(&self.pool)
+                .await?;
+
+        rows.into_iter().map(cast_sql_row_to_profile).collect()
+    }
+
+    pub async fn delete_profile(&self, id: uuid::Uuid) -> Result<(), LocalDatabaseError> {
+        sqlx::query("DELETE FROM profiles WHERE id = ?")
+            .bind(id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn add_admin(&self, admin: &Admin) -> Result<(), LocalDatabaseError> {
+        sqlx::query(
+            r#"
+            INSERT INTO admins (id, username, password_hash, role, oidc_subject)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(admin.id.to_string())
+        .bind(&admin.username)
+        .bind(&admin.password_hash)
+        .bind(admin.role.as_str())
+        .bind(&admin.oidc_subject)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Change the password or the role of an admin, `None` keeps the current value
+    pub async fn update_admin(
+        &self,
+        id: uuid::Uuid,
+        password_hash: Option<&str>,
+        role: Option<AdminRole>,
+    ) -> Result<(), LocalDatabaseError> {
+        sqlx::query(
+            r#"
+            UPDATE admins SET
+                password_hash = COALESCE(?, password_hash),
+                role = COALESCE(?, role),
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = ?
+            "#,
+        )
+        .bind(password_hash)
+        .bind(role.map(|role| role.as_str()))
+        .bind(id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Store the two-factor state of an admin as it is on `admin`
+    pub async fn update_admin_totp(&self, admin: &Admin) -> Result<(), LocalDatabaseError> {
+        sqlx::query(
+            r#"
+            UPDATE admins SET
+                totp_secret = ?,
+                totp_enabled = ?,
+                totp_last_step = ?,
+                recovery_codes = ?,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = ?
+            "#,
+        )
+        .bind(&admin.totp_secret)
+        .bind(admin.totp_enabled)
+        .bind(admin.totp_last_step.map(|step| step as i64))
+        .bind(serde_json::to_string(&admin.recovery_codes)?)
+        .bind(admin.id.to_string())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Accept a TOTP step of an admin once, false when the same or a later step was already used,
+    /// possibly by another login at the same time
+    pub async fn claim_totp_step(
+        &self,
+        id: uuid::Uuid,
+        step: u64,
+    ) -> Result<bool, LocalDatabaseError> {
+        let result = sqlx::query(
+            r#"
+            UPDATE admins SET totp_last_step = ?
+            WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)
+            "#,
+        )
+        .bind(step as i64)
+        .bind(id.to_string())
+        .bind(step as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Replace the recovery codes of an admin, false when they changed since they were read,
+    /// so a code cannot be used up twice at the same time
+    pub async fn replace_recovery_codes(
+        &self,
+        id: uuid::Uuid,
+        current: &[String],
+        remaining: &[String],
+    ) -> Result<bool, LocalDatabaseError> {
+        let result = sqlx::query(
+            r#"
+            UPDATE admins SET recovery_codes = ?, updated_at = CURRENT_TIMESTAMP
+            WHERE id = ? AND recovery_codes = ?
+            "#,
+        )
+        .bind(serde_json::to_string(remaining)?)
+        .bind(id.to_string())
+        .bind(serde_json::to_string(current)?)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn get_admin(&self, id: uuid::Uuid) -> Result<Option<Admin>, LocalDatabaseError> {
+        let row: Option<AdminRow> =
+            sqlx::query_as(&format!("SELECT {ADMIN_COLUMNS} FROM admins WHERE id = ?"))
+                .bind(id.to_string())
+                .fetch_optional(&self.pool)
+                .await?;
+
+        row.map(cast_sql_row_to_admin).transpose()
+    }
+
+    pub async fn get_admin_by_username(
+        &self,
+        username: &str,
+    ) -> Result<Option<Admin>, LocalDatabaseError> {
+        let row: Option<AdminRow> = sqlx::query_as(&format!(
+            "SELECT {ADMIN_COLUMNS} FROM admins WHERE username = ?"
+        ))
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.map(cast_sql_row_to_admin).transpose()
     }
 
-    pub fn create_komga(option: KomgaInviteOption) -> Self {
-        InviteToken::Komga {
-            token: TokenId::new(),
-            option,
-            uuid: None,
-        }
+    pub async fn get_admin_by_oidc_subject(
+        &self,
+        subject: &str,
+    ) -> Result<Option<Admin>, LocalDatabaseError> {
+        let row: Option<AdminRow> = sqlx::query_as(&format!(
+            "SELECT {ADMIN_COLUMNS} FROM admins WHERE oidc_subject = ?"
+        ))
+        .bind(subject)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.map(cast_sql_row_to_admin).transpose()
     }
 
-    pub fn create_navidrome(option: NavidromeInviteOption) -> Self {
-        InviteToken::Navidrome {
-            token: TokenId::new(),
-            option,
-            uuid: None,
-        }
+    pub async fn get_all_admins(&self) -> Result<Vec<Admin>, LocalDatabaseError> {
+        let rows: Vec<AdminRow> = sqlx::query_as(&format!(
+            "SELECT {ADMIN_COLUMNS} FROM admins ORDER BY created_at"
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(cast_sql_row_to_admin).collect()
     }
-}
 
-#[derive(Debug)]
-pub struct LocalDatabase {
-    pool: sqlx::Pool<sqlx::Sqlite>,
-}
+    /// Delete an admin with their passkeys and log them out everywhere
+    pub async fn delete_admin(&self, id: uuid::Uuid) -> Result<(), LocalDatabaseError> {
+        let mut transaction = self.pool.begin().await?;
 
-impl LocalDatabase {
-    pub async fn new(path: &PathBuf) -> Result<Self, sqlx::Error> {
-        tracing::info!("📂 Using database at: {}", path.display());
-        let options = SqliteConnectOptions::new()
-            .filename(path)
-            .create_if_missing(true);
+        sqlx::query("DELETE FROM sessions WHERE admin_id = ?")
+            .bind(id.to_string())
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query("DELETE FROM passkeys WHERE admin_id = ?")
+            .bind(id.to_string())
+            .execute(&mut *transaction)
+            .await?;
+        sqlx::query("DELETE FROM admins WHERE id = ?")
+            .bind(id.to_string())
+            .execute(&mut *transaction)
+            .await?;
 
-        let pool = sqlx::SqlitePool::connect_with(options).await?;
-        Ok(Self { pool })
+        transaction.commit().await?;
+
+        Ok(())
     }
 
-    pub async fn setup(&self) -> Result<(), sqlx::Error> {
-        // Create tables if they do not exist
+    /// Store a new session, `admin_id` is empty for a login with the shared token
+    pub async fn add_session(
+        &self,
+        token_hash: &str,
+        admin_id: Option<uuid::Uuid>,
+        csrf_token: &str,
+        expires_at: u64,
+    ) -> Result<(), LocalDatabaseError> {
+        let now = unix_now() as i64;
+
+        // a good moment to forget about the sessions nobody will use again
+        sqlx::query("DELETE FROM sessions WHERE expires_at <= ?")
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+
         sqlx::query(
-            r#"CREATE TABLE IF NOT EXISTS invites (
-                token TEXT PRIMARY KEY,
-                option TEXT NOT NULL,
-                uuid TEXT,
-                kind TEXT NOT NULL,
-                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-            )"#,
+            r#"
+            INSERT INTO sessions (token_hash, admin_id, csrf_token, created_at, last_seen_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
         )
+        .bind(token_hash)
+        .bind(admin_id.map(|id| id.to_string()))
+        .bind(csrf_token)
+        .bind(now)
+        .bind(now)
+        .bind(expires_at as i64)
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
-    pub async fn add_invite(&self, invite: &InviteToken) -> Result<(), LocalDatabaseError> {
-        let option_json = invite.option_str()?;
+    /// Get a session that has neither expired nor been idle for longer than `idle_timeout` seconds,
+    /// and mark it as used
+    pub async fn get_session(
+        &self,
+        token_hash: &str,
+        idle_timeout: u64,
+    ) -> Result<Option<Session>, LocalDatabaseError> {
+        let now = unix_now() as i64;
 
-        // execute insert query
-        sqlx::query(
+        let row: Option<(Option<String>, Option<String>, i64)> = sqlx::query_as(
             r#"
-            INSERT INTO invites (token, option, uuid, kind)
-            VALUES (?, ?, ?, ?)
+            SELECT admin_id, csrf_token, expires_at FROM sessions
+            WHERE token_hash = ? AND expires_at > ? AND COALESCE(last_seen_at, created_at) > ?
             "#,
         )
-        .bind(invite.token().to_string())
-        .bind(option_json)
-        .bind(invite.uuid().map(|s| s.to_string()))
-        .bind(invite.kind())
+        .bind(token_hash)
+        .bind(now)
+        .bind(now - idle_timeout as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        let Some((admin_id, csrf_token, expires_at)) = row else {
+            return Ok(None);
+        };
+
+        sqlx::query("UPDATE sessions SET last_seen_at = ? WHERE token_hash = ?")
+            .bind(now)
+            .bind(token_hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(Some(Session {
+            token_hash: token_hash.to_string(),
+            admin_id: admin_id.as_deref().map(uuid::Uuid::parse_str).transpose()?,
+            csrf_token: csrf_token.unwrap_or_default(),
+            expires_at: expires_at as u64,
+        }))
+    }
+
+    pub async fn delete_session(&self, token_hash: &str) -> Result<(), LocalDatabaseError> {
+        sqlx::query("DELETE FROM sessions WHERE token_hash = ?")
+            .bind(token_hash)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Log an admin out everywhere, e.g. after a password change
+    pub async fn delete_admin_sessions(
+        &self,
+        admin_id: uuid::Uuid,
+    ) -> Result<(), LocalDatabaseError> {
+        sqlx::query("DELETE FROM sessions WHERE admin_id = ?")
+            .bind(admin_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn add_oidc_login(
+        &self,
+        state: &str,
+        pkce_verifier: &str,
+        nonce: &str,
+        expires_at: u64,
+    ) -> Result<(), LocalDatabaseError> {
+        sqlx::query("DELETE FROM oidc_logins WHERE expires_at <= ?")
+            .bind(unix_now() as i64)
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(
+            "INSERT INTO oidc_logins (state, pkce_verifier, nonce, expires_at) VALUES (?, ?, ?, ?)",
+        )
+        .bind(state)
+        .bind(pkce_verifier)
+        .bind(nonce)
+        .bind(expires_at as i64)
         .execute(&self.pool)
         .await?;
 
         Ok(())
     }
 
-    pub async fn get_invite(
+    /// Take a pending login by its state, returns the PKCE verifier and the nonce
+    pub async fn take_oidc_login(
         &self,
-        token: TokenId,
-    ) -> Result<Option<InviteToken>, LocalDatabaseError> {
-        let row: Option<(String, String, Option<String>, String)> = sqlx::query_as(
+        state: &str,
+    ) -> Result<Option<(String, String)>, LocalDatabaseError> {
+        let row: Option<(String, String)> = sqlx::query_as(
             r#"
-            SELECT token, option, uuid, kind FROM invites
-            WHERE token = ? OR token = ?
+            DELETE FROM oidc_logins
+            WHERE state = ? AND expires_at > ?
+            RETURNING pkce_verifier, nonce
             "#,
         )
-        .bind(token.to_string())
-        .bind(token.0.to_string())
+        .bind(state)
+        .bind(unix_now() as i64)
         .fetch_optional(&self.pool)
         .await?;
 
-        match row {
-            Some(row) => {
-                let invite = cast_sql_row_to_invite_token(row)?;
-                Ok(Some(invite))
-            }
-            None => Ok(None),
-        }
+        Ok(row)
     }
 
-    pub async fn delete_invite(&self, token: TokenId) -> Result<(), LocalDatabaseError> {
-        sqlx::query("DELETE FROM invites WHERE token = ? OR token = ?")
-            .bind(token.to_string())
-            .bind(token.0.to_string())
+    pub async fn add_passkey(&self, passkey: &Passkey) -> Result<(), LocalDatabaseError> {
+        sqlx::query(
+            r#"
+            INSERT INTO passkeys (id, admin_id, name, public_key, sign_count, created_at)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(&passkey.id)
+        .bind(passkey.admin_id.to_string())
+        .bind(&passkey.name)
+        .bind(&passkey.public_key)
+        .bind(passkey.sign_count as i64)
+        .bind(passkey.created_at as i64)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_passkey(&self, id: &str) -> Result<Option<Passkey>, LocalDatabaseError> {
+        let row: Option<PasskeyRow> = sqlx::query_as(&format!(
+            "SELECT {PASSKEY_COLUMNS} FROM passkeys WHERE id = ?"
+        ))
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.map(cast_sql_row_to_passkey).transpose()
+    }
+
+    pub async fn get_admin_passkeys(
+        &self,
+        admin_id: uuid::Uuid,
+    ) -> Result<Vec<Passkey>, LocalDatabaseError> {
+        let rows: Vec<PasskeyRow> = sqlx::query_as(&format!(
+            "SELECT {PASSKEY_COLUMNS} FROM passkeys WHERE admin_id = ? ORDER BY created_at"
+        ))
+        .bind(admin_id.to_string())
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(cast_sql_row_to_passkey).collect()
+    }
+
+    /// Remember the new signature counter after a login
+    pub async fn touch_passkey(&self, id: &str, sign_count: u32) -> Result<(), LocalDatabaseError> {
+        sqlx::query("UPDATE passkeys SET sign_count = ?, last_used_at = ? WHERE id = ?")
+            .bind(sign_count as i64)
+            .bind(unix_now() as i64)
+            .bind(id)
             .execute(&self.pool)
             .await?;
 
         Ok(())
     }
 
-    pub async fn apply_user_id(
+    /// Delete a passkey of an admin, returns false when the admin has no such passkey
+    pub async fn delete_passkey(
         &self,
-        token: TokenId,
-        user_id: &str,
+        admin_id: uuid::Uuid,
+        id: &str,
+    ) -> Result<bool, LocalDatabaseError> {
+        let result = sqlx::query("DELETE FROM passkeys WHERE id = ? AND admin_id = ?")
+            .bind(id)
+            .bind(admin_id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Store the challenge of a passkey ceremony, `admin_id` is empty when logging in
+    pub async fn add_passkey_challenge(
+        &self,
+        id: uuid::Uuid,
+        admin_id: Option<uuid::Uuid>,
+        kind: &str,
+        challenge: &str,
+        expires_at: u64,
     ) -> Result<(), LocalDatabaseError> {
-        sqlx::query("UPDATE invites SET uuid = ? WHERE token = ? OR token = ?")
-            .bind(user_id)
-            .bind(token.to_string())
-            .bind(token.0.to_string())
+        sqlx::query("DELETE FROM passkey_challenges WHERE expires_at <= ?")
+            .bind(unix_now() as i64)
             .execute(&self.pool)
             .await?;
 
+        sqlx::query(
+            r#"
+            INSERT INTO passkey_challenges (id, admin_id, kind, challenge, expires_at)
+            VALUES (?, ?, ?, ?, ?)
+            "#,
+        )
+        .bind(id.to_string())
+        .bind(admin_id.map(|id| id.to_string()))
+        .bind(kind)
+        .bind(challenge)
+        .bind(expires_at as i64)
+        .execute(&self.pool)
+        .await?;
+
         Ok(())
     }
 
-    pub async fn get_all_invites(&self) -> Result<Vec<InviteToken>, LocalDatabaseError> {
-        let rows: Vec<(String, String, Option<String>, String)> = sqlx::query_as(
+    /// Take a challenge that has not expired yet, a challenge can only be used once
+    pub async fn take_passkey_challenge(
+        &self,
+        id: uuid::Uuid,
+        kind: &str,
+    ) -> Result<Option<(Option<uuid::Uuid>, String)>, LocalDatabaseError> {
+        let row: Option<(Option<String>, String)> = sqlx::query_as(
+            r#"
+            DELETE FROM passkey_challenges
+            WHERE id = ? AND kind = ? AND expires_at > ?
+            RETURNING admin_id, challenge
+            "#,
+        )
+        .bind(id.to_string())
+        .bind(kind)
+        .bind(unix_now() as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.map(|(admin_id, challenge)| {
+            Ok((
+                admin_id.as_deref().map(uuid::Uuid::parse_str).transpose()?,
+                challenge,
+            ))
+        })
+        .transpose()
+    }
+
+    pub async fn add_api_key(&self, key: &ApiKey) -> Result<(), LocalDatabaseError> {
+        sqlx::query(
             r#"
-            SELECT token, option, uuid, kind FROM invites
+            INSERT INTO api_keys (id, name, key_hash, scopes, created_by, created_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
             "#,
         )
+        .bind(key.id.to_string())
+        .bind(&key.name)
+        .bind(&key.key_hash)
+        .bind(serde_json::to_string(&key.scopes)?)
+        .bind(&key.created_by)
+        .bind(key.created_at as i64)
+        .bind(key.expires_at.map(|t| t as i64))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_all_api_keys(&self) -> Result<Vec<ApiKey>, LocalDatabaseError> {
+        let rows: Vec<ApiKeyRow> = sqlx::query_as(&format!(
+            "SELECT {API_KEY_COLUMNS} FROM api_keys ORDER BY created_at"
+        ))
         .fetch_all(&self.pool)
         .await?;
 
-        let mut invites: Vec<InviteToken> = Vec::new();
-        for row in rows {
-            // do this to bubble up any errors
-            let invite = cast_sql_row_to_invite_token(row)?;
-            invites.push(invite);
-        }
+        rows.into_iter().map(cast_sql_row_to_api_key).collect()
+    }
 
-        Ok(invites)
+    /// Get the API key with this hash if it has not expired yet
+    pub async fn get_api_key_by_hash(
+        &self,
+        key_hash: &str,
+    ) -> Result<Option<ApiKey>, LocalDatabaseError> {
+        let row: Option<ApiKeyRow> = sqlx::query_as(&format!(
+            r#"
+            SELECT {API_KEY_COLUMNS} FROM api_keys
+            WHERE key_hash = ? AND (expires_at IS NULL OR expires_at > ?)
+            "#
+        ))
+        .bind(key_hash)
+        .bind(unix_now() as i64)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.map(cast_sql_row_to_api_key).transpose()
+    }
+
+    pub async fn touch_api_key(&self, id: uuid::Uuid) -> Result<(), LocalDatabaseError> {
+        sqlx::query("UPDATE api_keys SET last_used_at = ? WHERE id = ?")
+            .bind(unix_now() as i64)
+            .bind(id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Revoke an API key, returns false when there was no such key
+    pub async fn delete_api_key(&self, id: uuid::Uuid) -> Result<bool, LocalDatabaseError> {
+        let result = sqlx::query("DELETE FROM api_keys WHERE id = ?")
+            .bind(id.to_string())
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
     }
 }
 
@@ -256,6 +2000,12 @@ pub enum LocalDatabaseError {
     InvalidInviteToken(#[from] APIKeyParseError),
     #[error("unknown token kind: {0}")]
     UnknownTokenKind(String),
+    #[error("invalid ID, expected UUID format")]
+    InvalidId(#[from] uuid::Error),
+    #[error("unknown profile kind: {0}")]
+    UnknownProfileKind(String),
+    #[error("unknown admin role: {0}")]
+    UnknownAdminRole(String),
 }
 
 /// The token ID for invite, which is UUID based.
@@ -367,29 +2117,364 @@ impl std::fmt::Display for TokenId {
     }
 }
 
-fn cast_sql_row_to_invite_token(
-    row: (String, String, Option<String>, String),
-) -> Result<InviteToken, LocalDatabaseError> {
-    let (token, option_str, uuid, kind) = row;
-    let token_uuid = TokenId::from_string(token)?;
+async fn insert_invite<'e, E: sqlx::SqliteExecutor<'e>>(
+    executor: E,
+    invite: &InviteToken,
+) -> Result<(), LocalDatabaseError> {
+    let option_json = invite.option_str()?;
+    let meta = invite.meta();
 
-    match kind.to_lowercase().as_str() {
+    // execute insert query
+    sqlx::query(
+        r#"
+        INSERT INTO invites (token, option, uuid, kind, note, email, tags, created_by)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+        "#,
+    )
+    .bind(invite.token().to_string())
+    .bind(option_json)
+    .bind(invite.uuid().map(|s| s.to_string()))
+    .bind(invite.kind())
+    .bind(&meta.note)
+    .bind(&meta.email)
+    .bind(serde_json::to_string(&meta.tags)?)
+    .bind(&meta.created_by)
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InviteStatus {
+    /// Can be redeemed right now
+    Active,
+    Paused,
+    Expired,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+pub enum InviteSort {
+    #[default]
+    #[serde(rename = "createdAt")]
+    CreatedAt,
+    #[serde(rename = "expiresAt")]
+    ExpiresAt,
+}
+
+#[derive(serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Filters, sorting and pagination for listing invites, every field is optional
+#[derive(serde::Deserialize, Default)]
+pub struct InviteFilter {
+    pub kind: Option<String>,
+    pub status: Option<InviteStatus>,
+    pub tag: Option<String>,
+    /// Username of the admin who created the invites
+    #[serde(rename = "createdBy")]
+    pub created_by: Option<String>,
+    #[serde(rename = "createdAfter")]
+    pub created_after: Option<u64>,
+    #[serde(rename = "createdBefore")]
+    pub created_before: Option<u64>,
+    #[serde(rename = "expiresAfter")]
+    pub expires_after: Option<u64>,
+    #[serde(rename = "expiresBefore")]
+    pub expires_before: Option<u64>,
+    #[serde(default)]
+    pub sort: InviteSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// Starts at 1, only used with `perPage`
+    pub page: Option<u64>,
+    #[serde(rename = "perPage")]
+    pub per_page: Option<u64>,
+}
+
+impl InviteFilter {
+    /// How many invites come before the requested page, empty when that does not fit a query
+    pub fn offset(&self) -> Option<i64> {
+        let per_page = self.per_page.unwrap_or(0);
+        let skipped = self.page.unwrap_or(1).max(1) - 1;
+        skipped
+            .checked_mul(per_page)
+            .and_then(|offset| i64::try_from(offset).ok())
+    }
+
+    fn push_conditions(&self, query: &mut sqlx::QueryBuilder<'_, sqlx::Sqlite>) {
+        let expires_at = "json_extract(invites.option, '$.expiresAt')";
+        let created_at = "CAST(strftime('%s', invites.created_at) AS INTEGER)";
+        let now = unix_now() as i64;
+
+        query.push(" WHERE 1 = 1");
+        if let Some(kind) = &self.kind {
+            query.push(" AND invites.kind = ");
+            query.push_bind(kind.to_lowercase());
+        }
+        match self.status {
+            Some(InviteStatus::Active) => {
+                query.push(format!(
+                    " AND invites.paused_at IS NULL AND ({expires_at} IS NULL OR {expires_at} >= "
+                ));
+                query.push_bind(now);
+                query.push(")");
+            }
+            Some(InviteStatus::Paused) => {
+                query.push(" AND invites.paused_at IS NOT NULL");
+            }
+            Some(InviteStatus::Expired) => {
+                query.push(format!(" AND {expires_at} < "));
+                query.push_bind(now);
+            }
+            None => {}
+        }
+        if let Some(tag) = &self.tag {
+            query.push(" AND EXISTS (SELECT 1 FROM json_each(invites.tags) WHERE value = ");
+            query.push_bind(tag.clone());
+            query.push(")");
+        }
+        if let Some(created_by) = &self.created_by {
+            query.push(" AND invites.created_by = ");
+            query.push_bind(created_by.clone());
+        }
+        for (column, operator, value) in [
+            (created_at, ">=", self.created_after),
+            (created_at, "<=", self.created_before),
+            (expires_at, ">=", self.expires_after),
+            (expires_at, "<=", self.expires_before),
+        ] {
+            if let Some(value) = value {
+                query.push(format!(" AND {column} {operator} "));
+                query.push_bind(value as i64);
+            }
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct InviteRow {
+    token: String,
+    option: String,
+    uuid: Option<String>,
+    kind: String,
+    missing_libraries: Option<String>,
+    note: Option<String>,
+    email: Option<String>,
+    paused_at: Option<i64>,
+    updated_at: Option<i64>,
+    tags: Option<String>,
+    created_at: Option<i64>,
+    created_by: Option<String>,
+}
+
+fn cast_sql_row_to_invite_token(row: InviteRow) -> Result<InviteToken, LocalDatabaseError> {
+    let token_uuid = TokenId::from_string(row.token)?;
+    let meta = InviteMetadata {
+        missing_libraries: row
+            .missing_libraries
+            .map(|m| serde_json::from_str(&m))
+            .transpose()?
+            .unwrap_or_default(),
+        note: row.note,
+        email: row.email,
+        paused_at: row.paused_at.map(|t| t as u64),
+        updated_at: row.updated_at.map(|t| t as u64),
+        tags: row
+            .tags
+            .map(|t| serde_json::from_str(&t))
+            .transpose()?
+            .unwrap_or_default(),
+        created_at: row.created_at.map(|t| t as u64),
+        created_by: row.created_by,
+    };
+
+    match row.kind.to_lowercase().as_str() {
         "komga" => {
-            let option = serde_json::from_str::<KomgaInviteOption>(&option_str)?;
+            let option = serde_json::from_str::<KomgaInviteOption>(&row.option)?;
             Ok(InviteToken::Komga {
                 token: token_uuid,
                 option,
-                uuid,
+                uuid: row.uuid,
+                meta,
             })
         }
         "navidrome" => {
-            let option = serde_json::from_str::<NavidromeInviteOption>(&option_str)?;
+            let option = serde_json::from_str::<NavidromeInviteOption>(&row.option)?;
             Ok(InviteToken::Navidrome {
                 token: token_uuid,
                 option,
-                uuid,
+                uuid: row.uuid,
+                meta,
             })
         }
-        _ => Err(LocalDatabaseError::UnknownTokenKind(kind)),
+        _ => Err(LocalDatabaseError::UnknownTokenKind(row.kind)),
+    }
+}
+
+const ADMIN_COLUMNS: &str = "id, username, password_hash, role, \
+    CAST(strftime('%s', created_at) AS INTEGER) AS created_at, \
+    totp_secret, totp_enabled, totp_last_step, recovery_codes, oidc_subject";
+
+#[derive(sqlx::FromRow)]
+struct AdminRow {
+    id: String,
+    username: String,
+    password_hash: String,
+    role: String,
+    created_at: Option<i64>,
+    totp_secret: Option<String>,
+    totp_enabled: bool,
+    totp_last_step: Option<i64>,
+    recovery_codes: Option<String>,
+    oidc_subject: Option<String>,
+}
+
+fn cast_sql_row_to_admin(row: AdminRow) -> Result<Admin, LocalDatabaseError> {
+    Ok(Admin {
+        id: uuid::Uuid::parse_str(&row.id)?,
+        username: row.username,
+        role: AdminRole::from_str(&row.role)
+            .ok_or(LocalDatabaseError::UnknownAdminRole(row.role))?,
+        password_hash: row.password_hash,
+        created_at: row.created_at.map(|t| t as u64),
+        totp_secret: row.totp_secret,
+        totp_enabled: row.totp_enabled,
+        totp_last_step: row.totp_last_step.map(|step| step as u64),
+        recovery_codes: row
+            .recovery_codes
+            .map(|codes| serde_json::from_str(&codes))
+            .transpose()?
+            .unwrap_or_default(),
+        oidc_subject: row.oidc_subject,
+    })
+}
+
+const PASSKEY_COLUMNS: &str =
+    "id, admin_id, name, public_key, sign_count, created_at, last_used_at";
+
+#[derive(sqlx::FromRow)]
+struct PasskeyRow {
+    id: String,
+    admin_id: String,
+    name: String,
+    public_key: String,
+    sign_count: i64,
+    created_at: i64,
+    last_used_at: Option<i64>,
+}
+
+fn cast_sql_row_to_passkey(row: PasskeyRow) -> Result<Passkey, LocalDatabaseError> {
+    Ok(Passkey {
+        id: row.id,
+        admin_id: uuid::Uuid::parse_str(&row.admin_id)?,
+        name: row.name,
+        public_key: row.public_key,
+        sign_count: row.sign_count as u32,
+        created_at: row.created_at as u64,
+        last_used_at: row.last_used_at.map(|t| t as u64),
+    })
+}
+
+const API_KEY_COLUMNS: &str =
+    "id, name, key_hash, scopes, created_by, created_at, expires_at, last_used_at";
+
+#[derive(sqlx::FromRow)]
+struct ApiKeyRow {
+    id: String,
+    name: String,
+    key_hash: String,
+    scopes: String,
+    created_by: Option<String>,
+    created_at: i64,
+    expires_at: Option<i64>,
+    last_used_at: Option<i64>,
+}
+
+fn cast_sql_row_to_api_key(row: ApiKeyRow) -> Result<ApiKey, LocalDatabaseError> {
+    Ok(ApiKey {
+        id: uuid::Uuid::parse_str(&row.id)?,
+        name: row.name,
+        scopes: serde_json::from_str(&row.scopes)?,
+        key_hash: row.key_hash,
+        created_by: row.created_by,
+        created_at: row.created_at as u64,
+        expires_at: row.expires_at.map(|t| t as u64),
+        last_used_at: row.last_used_at.map(|t| t as u64),
+    })
+}
+
+fn cast_sql_row_to_profile(
+    row: (String, String, String, String),
+) -> Result<RestrictionProfile, LocalDatabaseError> {
+    let (id, name, kind, option_str) = row;
+    let id = uuid::Uuid::parse_str(&id)?;
+
+    match kind.to_lowercase().as_str() {
+        "komga" => {
+            let option = serde_json::from_str::<KomgaProfileOption>(&option_str)?;
+            Ok(RestrictionProfile::Komga { id, name, option })
+        }
+        "navidrome" => {
+            let option = serde_json::from_str::<NavidromeProfileOption>(&option_str)?;
+            Ok(RestrictionProfile::Navidrome { id, name, option })
+        }
+        _ => Err(LocalDatabaseError::UnknownProfileKind(kind)),
     }
 }
+
+#[derive(sqlx::FromRow)]
+struct UserRow {
+    id: String,
+    kind: String,
+    username: Option<String>,
+    token: Option<String>,
+    profile_id: Option<String>,
+    access_expires_at: Option<i64>,
+    access_expiry_action: String,
+    expired_at: Option<i64>,
+    expiry_failures: i64,
+    restrictions: Option<String>,
+    drift: Option<String>,
+    checked_at: Option<i64>,
+    created_at: Option<String>,
+}
+
+fn cast_sql_row_to_user(row: UserRow) -> Result<ProvisionedUser, LocalDatabaseError> {
+    let token = row.token.map(TokenId::from_string).transpose()?;
+    let profile_id = row
+        .profile_id
+        .map(|p| uuid::Uuid::parse_str(&p))
+        .transpose()?;
+    let restrictions = row
+        .restrictions
+        .map(|r| serde_json::from_str(&r))
+        .transpose()?;
+    let drift = row
+        .drift
+        .map(|d| serde_json::from_str(&d))
+        .transpose()?
+        .unwrap_or_default();
+
+    Ok(ProvisionedUser {
+        id: row.id,
+        kind: row.kind,
+        username: row.username,
+        token,
+        profile_id,
+        restrictions,
+        access_expires_at: row.access_expires_at.map(|t| t as u64),
+        access_expiry_action: row.access_expiry_action.parse().unwrap_or_default(),
+        expired_at: row.expired_at.map(|t| t as u64),
+        expiry_failures: row.expiry_failures as u32,
+        drift,
+        checked_at: row.checked_at.map(|t| t as u64),
+        created_at: row.created_at,
+    })
+}
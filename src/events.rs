@@ -0,0 +1,196 @@
+use std::{collections::HashSet, time::Duration};
+
+use crate::{
+    AppState,
+    database::{InviteToken, UserDrift},
+    komga::{KomgaError, KomgaEvent},
+};
+
+/// How long to wait before reconnecting, doubled on every failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Flag every Komga invite that shares a library which no longer exists
+async fn refresh_invite_missing_libraries(state: &AppState) -> Result<(), KomgaError> {
+    let libraries: HashSet<String> = state
+        .komga
+        .get_libraries()
+        .await?
+        .into_iter()
+        .map(|library| library.id)
+        .collect();
+
+    let invites = match state.db.get_all_invites().await {
+        Ok(invites) => invites,
+        Err(e) => {
+            tracing::error!("Failed to get invites for library check: {}", e);
+            return Ok(());
+        }
+    };
+
+    for invite in invites {
+        let InviteToken::Komga {
+            token,
+            option,
+            meta,
+            ..
+        } = &invite
+        else {
+            continue;
+        };
+
+        let missing_libraries: Vec<String> = option
+            .shared_libraries
+            .as_ref()
+            .map(|shared| {
+                shared
+                    .library_ids
+                    .iter()
+                    .filter(|id| !libraries.contains(*id))
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if missing_libraries == meta.missing_libraries {
+            continue;
+        }
+
+        if !missing_libraries.is_empty() {
+            tracing::warn!(
+                "[{}] Invite shares libraries that no longer exist: {:?}",
+                token,
+                missing_libraries
+            );
+        }
+
+        if let Err(e) = state
+            .db
+            .set_invite_missing_libraries(*token, &missing_libraries)
+            .await
+        {
+            tracing::error!("[{}] Failed to flag missing libraries: {}", token, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Check which of the known Komga users no longer exist and forget them
+async fn handle_user_change(state: &AppState) -> Result<(), KomgaError> {
+    let upstream_users: HashSet<String> = state
+        .komga
+        .list_users()
+        .await?
+        .into_iter()
+        .map(|user| user.id)
+        .collect();
+
+    let mut known_users: HashSet<String> = match state.db.get_active_users().await {
+        Ok(users) => users
+            .into_iter()
+            .filter(|user| user.kind == "komga")
+            .map(|user| user.id)
+            .collect(),
+        Err(e) => {
+            tracing::error!("Failed to get users for deletion check: {}", e);
+            HashSet::new()
+        }
+    };
+    if let Ok(invites) = state.db.get_all_invites().await {
+        known_users.extend(
+            invites
+                .iter()
+                .filter(|invite| invite.kind() == "komga")
+                .filter_map(|invite| invite.uuid().map(|uuid| uuid.to_string())),
+        );
+    }
+
+    for user_id in known_users.difference(&upstream_users) {
+        tracing::info!("[komga / {}] User has been deleted in Komga", user_id);
+
+        match state.db.clear_invite_user_id("komga", user_id).await {
+            Ok(0) => {}
+            Ok(count) => tracing::info!(
+                "[komga / {}] Cleared deleted user from {} invites",
+                user_id,
+                count
+            ),
+            Err(e) => tracing::error!(
+                "[komga / {}] Failed to clear deleted user from invites: {}",
+                user_id,
+                e
+            ),
+        }
+
+        if let Err(e) = state
+            .db
+            .set_user_drift("komga", user_id, &[UserDrift::deleted()])
+            .await
+        {
+            tracing::error!(
+                "[komga / {}] Failed to mark user as deleted: {}",
+                user_id,
+                e
+            );
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_event(state: &AppState, event: KomgaEvent) -> Result<(), KomgaError> {
+    match event.event.as_str() {
+        "LibraryAdded" | "LibraryDeleted" => {
+            tracing::info!("Komga library changed: {} {}", event.event, event.data);
+            refresh_invite_missing_libraries(state).await
+        }
+        // sessions also expire on logout and timeouts, so deletions are left to the reconcile job
+        "UserDeleted" => handle_user_change(state).await,
+        _ => Ok(()),
+    }
+}
+
+/// Listen to the Komga event stream forever, reconnecting when the connection drops
+pub async fn listen_komga(state: AppState) {
+    let mut delay = RECONNECT_DELAY;
+
+    loop {
+        match state.komga.subscribe_events().await {
+            Ok(mut stream) => {
+                tracing::info!("Subscribed to Komga events");
+                delay = RECONNECT_DELAY;
+
+                // we might have missed some events while disconnected
+                if let Err(e) = refresh_invite_missing_libraries(&state).await {
+                    tracing::error!("Failed to check invite libraries: {}", e);
+                }
+                if let Err(e) = handle_user_change(&state).await {
+                    tracing::error!("Failed to check deleted users: {}", e);
+                }
+
+                loop {
+                    match stream.next_event().await {
+                        Ok(Some(event)) => {
+                            if let Err(e) = handle_event(&state, event).await {
+                                tracing::error!("Failed to handle Komga event: {}", e);
+                            }
+                        }
+                        Ok(None) => {
+                            tracing::warn!("Komga event stream closed");
+                            break;
+                        }
+                        Err(e) => {
+                            tracing::error!("Komga event stream failed: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => tracing::error!("Failed to subscribe to Komga events: {}", e),
+        }
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
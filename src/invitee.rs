@@ -1,15 +1,22 @@
 use std::sync::Arc;
 
+use rand::{Rng, distr::Alphanumeric};
 use tokio::sync::Mutex;
 
 use crate::{
     AppState,
-    database::InviteToken,
+    database::{
+        InviteToken, KomgaInviteOption, KomgaProfileOption, NavidromeInviteOption, ProvisionedUser,
+        RestrictionProfile,
+    },
     komga::{self, KomgaUserCreate},
     navidrome,
 };
 
 const KOMGA_DEFAULT_ROLES: &[&str] = &["USER", "FILE_DOWNLOAD", "PAGE_STREAMING"];
+/// Komga logs in with an email, so generated accounts get one on a domain that is never mailed
+const GENERATED_EMAIL_DOMAIN: &str = "k-librarian.invalid";
+const GENERATED_PASSWORD_LENGTH: usize = 20;
 
 #[derive(serde::Serialize, serde::Deserialize, garde::Validate)]
 pub struct InviteTokenApplicationPayload {
@@ -19,8 +26,159 @@ pub struct InviteTokenApplicationPayload {
     password: String,
     #[garde(custom(validate_username()))]
     username: String, // although, ignored in Komga
+    /// The name shown to other users, only used by Navidrome
+    #[serde(rename = "displayName", default)]
+    #[garde(length(max = 64))]
+    display_name: Option<String>,
 }
 
+impl InviteTokenApplicationPayload {
+    /// Random credentials for invites that do not ask the invitee for anything
+    pub fn generate() -> Self {
+        let suffix: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(6)
+            .map(|c| char::from(c).to_ascii_lowercase())
+            .collect();
+        let password: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(GENERATED_PASSWORD_LENGTH)
+            .map(char::from)
+            .collect();
+
+        let username = format!("reader-{suffix}");
+        Self {
+            email: format!("{username}@{GENERATED_EMAIL_DOMAIN}"),
+            password,
+            username,
+            display_name: None,
+        }
+    }
+}
+
+/// Credentials k-librarian picked for the invitee, only returned once
+#[derive(serde::Serialize)]
+pub struct GeneratedCredentials {
+    pub username: String,
+    pub email: String,
+    pub password: String,
+}
+
+/// What the invitee receives after a successful redemption
+#[derive(serde::Serialize)]
+pub struct InviteRedemption {
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<GeneratedCredentials>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub komga: Option<KomgaSetup>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subsonic: Option<SubsonicSetup>,
+}
+
+/// Everything needed to connect an e-reader to Komga
+#[derive(serde::Serialize)]
+pub struct KomgaSetup {
+    #[serde(rename = "opdsV1")]
+    pub opds_v1: String,
+    #[serde(rename = "opdsV2")]
+    pub opds_v2: String,
+    /// Only returned once, when the invite asked for an API key
+    #[serde(rename = "apiKey")]
+    pub api_key: Option<String>,
+    /// Only available when the user has the KOBO_SYNC role and an API key was issued
+    pub kobo: Option<String>,
+    /// Only available when the user has the KOREADER_SYNC role
+    pub koreader: Option<String>,
+    /// Names of the libraries the user can read
+    pub libraries: Vec<String>,
+}
+
+impl KomgaSetup {
+    async fn new(
+        komga: &komga::KomgaClient,
+        host: &str,
+        granted: &KomgaProfileOption,
+        api_key: Option<String>,
+    ) -> Self {
+        let host = host.trim_end_matches('/');
+        let roles = granted.roles.clone().unwrap_or_default();
+        let has_role = |role: &str| roles.iter().any(|r| r == role);
+
+        let libraries = match komga.get_libraries().await {
+            Ok(libraries) => libraries
+                .into_iter()
+                .filter(|library| match &granted.shared_libraries {
+                    Some(shared) if !shared.all => shared.library_ids.contains(&library.id),
+                    _ => true,
+                })
+                .map(|library| library.name)
+                .collect(),
+            Err(e) => {
+                // the account is already created, do not fail the redemption for this
+                tracing::error!("Failed to get Komga libraries for setup kit: {}", e);
+                vec![]
+            }
+        };
+
+        Self {
+            opds_v1: format!("{host}/opds/v1.2/catalog"),
+            opds_v2: format!("{host}/opds/v2/catalog"),
+            // Kobo sync is authenticated by putting the API key in the URL
+            kobo: api_key
+                .as_ref()
+                .filter(|_| has_role("KOBO_SYNC"))
+                .map(|key| format!("{host}/kobo/{key}/")),
+            koreader: has_role("KOREADER_SYNC").then(|| format!("{host}/koreader")),
+            libraries,
+            api_key,
+        }
+    }
+}
+
+/// Everything needed to connect a Subsonic client to Navidrome
+#[derive(serde::Serialize)]
+pub struct SubsonicSetup {
+    pub server: String,
+    pub username: String,
+    pub apps: &'static [SubsonicApp],
+}
+
+#[derive(serde::Serialize)]
+pub struct SubsonicApp {
+    pub name: &'static str,
+    pub platform: &'static str,
+    pub url: &'static str,
+}
+
+const SUBSONIC_APPS: &[SubsonicApp] = &[
+    SubsonicApp {
+        name: "Symfonium",
+        platform: "Android",
+        url: "https://symfonium.app",
+    },
+    SubsonicApp {
+        name: "Tempo",
+        platform: "Android",
+        url: "https://github.com/CappielloAntonio/tempo",
+    },
+    SubsonicApp {
+        name: "Amperfy",
+        platform: "iOS",
+        url: "https://github.com/BLeeEZ/amperfy",
+    },
+    SubsonicApp {
+        name: "play:Sub",
+        platform: "iOS",
+        url: "https://apps.apple.com/app/play-sub-music-streamer/id955329386",
+    },
+    SubsonicApp {
+        name: "Feishin",
+        platform: "Windows, macOS, Linux",
+        url: "https://github.com/jeffvli/feishin",
+    },
+];
+
 #[non_exhaustive]
 #[derive(Debug, thiserror::Error)]
 pub enum UserCreationError {
@@ -32,6 +190,16 @@ pub enum UserCreationError {
     NavidromeError(#[from] navidrome::NavidromeError),
     #[error("failed to communicate with the database: {0}")]
     DatabaseError(#[from] crate::database::LocalDatabaseError),
+    #[error("this invite requires an email, username and password")]
+    MissingCredentials,
+    #[error("this invite can only be redeemed with the email it was sent to")]
+    EmailMismatch,
+    #[error("the account handed over by this invite no longer exists")]
+    HandoverUserMissing,
+    #[error("{0} accounts cannot be handed over, since their email cannot be changed")]
+    HandoverUnsupported(&'static str),
+    #[error("restriction profile {0} not found for this invite kind")]
+    ProfileNotFound(uuid::Uuid),
     #[error("client {0} is unavailable for user creation")]
     ClientUnavailable(&'static str),
     #[error("unknown error during user creation")]
@@ -39,12 +207,42 @@ pub enum UserCreationError {
     UnknownError,
 }
 
+async fn resolve_komga_option(
+    database: &Arc<crate::database::LocalDatabase>,
+    option: &KomgaInviteOption,
+) -> Result<KomgaInviteOption, UserCreationError> {
+    match option.profile_id {
+        None => Ok(option.clone()),
+        Some(profile_id) => match database.get_profile(profile_id).await? {
+            Some(RestrictionProfile::Komga {
+                option: profile, ..
+            }) => Ok(option.clone().with_profile(&profile)),
+            _ => Err(UserCreationError::ProfileNotFound(profile_id)),
+        },
+    }
+}
+
+async fn resolve_navidrome_option(
+    database: &Arc<crate::database::LocalDatabase>,
+    option: &NavidromeInviteOption,
+) -> Result<NavidromeInviteOption, UserCreationError> {
+    match option.profile_id {
+        None => Ok(option.clone()),
+        Some(profile_id) => match database.get_profile(profile_id).await? {
+            Some(RestrictionProfile::Navidrome {
+                option: profile, ..
+            }) => Ok(option.clone().with_profile(&profile)),
+            _ => Err(UserCreationError::ProfileNotFound(profile_id)),
+        },
+    }
+}
+
 async fn create_user_in_komga(
     database: &Arc<crate::database::LocalDatabase>,
     komga: &Arc<komga::KomgaClient>,
     token: &InviteToken,
     payload: &InviteTokenApplicationPayload,
-) -> Result<(), UserCreationError> {
+) -> Result<KomgaProfileOption, UserCreationError> {
     let (user_create, user_id, create_option) = match token {
         InviteToken::Navidrome { .. } => {
             return Err(UserCreationError::WrongInviteKind(
@@ -53,6 +251,7 @@ async fn create_user_in_komga(
             ));
         }
         InviteToken::Komga { option, uuid, .. } => {
+            let option = resolve_komga_option(database, option).await?;
             let roles = option.roles.clone().unwrap_or(
                 KOMGA_DEFAULT_ROLES
                     .to_vec()
@@ -67,12 +266,39 @@ async fn create_user_in_komga(
                 roles,
             };
 
-            (user_create, uuid.clone(), option.clone())
+            (user_create, uuid.clone(), option)
         }
     };
 
+    let granted = KomgaProfileOption {
+        labels_allow: create_option.labels_allow.clone(),
+        labels_exclude: create_option.labels_exclude.clone(),
+        shared_libraries: create_option.shared_libraries.clone(),
+        roles: Some(user_create.roles.clone()),
+    };
+    let restrictions = serde_json::to_value(&granted).unwrap();
+
+    if token.handover_user_id().is_some() && user_id.is_none() {
+        return Err(UserCreationError::HandoverUserMissing);
+    }
+
     match user_id {
         Some(uuid) => {
+            if token.handover_user_id().is_some() {
+                // refused when the invite is created, but older invites might still ask for it
+                return Err(UserCreationError::HandoverUnsupported("Komga"));
+            } else if token.generates_credentials() {
+                // generated passwords change on every attempt, the email is kept by redeem_invite
+                tracing::info!(
+                    "[{} / {}] Setting a new password for existing user: {}",
+                    token.token(),
+                    uuid,
+                    &payload.email
+                );
+
+                komga.update_user_password(&uuid, &payload.password).await?;
+            }
+
             tracing::info!(
                 "[{} / {}] User already created, applying restrictions",
                 token.token(),
@@ -83,13 +309,20 @@ async fn create_user_in_komga(
                 .apply_user_restriction(&uuid, &create_option.into())
                 .await?;
 
+            database
+                .add_user(
+                    &ProvisionedUser::from_invite(token, &uuid, restrictions)
+                        .with_username(&payload.email),
+                )
+                .await?;
+
             // Delete the invite token after successful user creation
             tracing::info!(
                 "[{}] Deleting invite token after user restriction application",
                 token.token()
             );
             database.delete_invite(token.token()).await?;
-            Ok(())
+            Ok(granted)
         }
         None => {
             tracing::info!(
@@ -117,6 +350,13 @@ async fn create_user_in_komga(
                 .apply_user_restriction(&user.id, &create_option.into())
                 .await?;
 
+            database
+                .add_user(
+                    &ProvisionedUser::from_invite(token, &user.id, restrictions)
+                        .with_username(&payload.email),
+                )
+                .await?;
+
             // Delete the invite token after successful user creation
             tracing::info!(
                 "[{}] Deleting invite token after user creation",
@@ -129,7 +369,7 @@ async fn create_user_in_komga(
                 token.token(),
                 user.id
             );
-            Ok(())
+            Ok(granted)
         }
     }
 }
@@ -139,7 +379,7 @@ async fn create_user_in_navidrome(
     navidrome: &Arc<Mutex<navidrome::NavidromeClient>>,
     token: &InviteToken,
     payload: &InviteTokenApplicationPayload,
-) -> Result<(), UserCreationError> {
+) -> Result<String, UserCreationError> {
     let (user_create, user_id, create_option) = match token {
         InviteToken::Komga { .. } => {
             return Err(UserCreationError::WrongInviteKind(
@@ -148,28 +388,83 @@ async fn create_user_in_navidrome(
             ));
         }
         InviteToken::Navidrome { option, uuid, .. } => {
-            let user_create = navidrome::NavidromeUserCreate::new(
+            let option = resolve_navidrome_option(database, option).await?;
+            let mut user_create = navidrome::NavidromeUserCreate::new(
                 &payload.username,
                 &payload.email,
                 &payload.password,
                 option.is_admin,
             );
+            if let Some(display_name) = payload
+                .display_name
+                .as_deref()
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+            {
+                user_create = user_create.with_name(display_name);
+            }
 
-            (user_create, uuid.clone(), option.clone())
+            (user_create, uuid.clone(), option)
         }
     };
 
+    let restrictions = serde_json::json!({
+        "isAdmin": create_option.is_admin,
+        "libraryIds": create_option.library_ids,
+    });
+
+    if token.handover_user_id().is_some() && user_id.is_none() {
+        return Err(UserCreationError::HandoverUserMissing);
+    }
+
     let mut navidrome_client = navidrome.lock().await;
 
     match user_id {
         Some(uuid) => {
+            // a pre-provisioned account keeps its username, the invitee only picks the credentials
+            let username = if token.handover_user_id().is_some() || token.generates_credentials() {
+                tracing::info!(
+                    "[{} / {}] Setting credentials of existing user to: {}",
+                    token.token(),
+                    uuid,
+                    &payload.email
+                );
+
+                let user = navidrome_client
+                    .get_user(&uuid)
+                    .await?
+                    .ok_or(UserCreationError::HandoverUserMissing)?;
+                let mut user_update = navidrome::NavidromeUserUpdate::from(user);
+                user_update.email = payload.email.clone();
+                user_update.password = Some(payload.password.clone());
+                user_update.is_admin = create_option.is_admin;
+                if let Some(display_name) = payload
+                    .display_name
+                    .as_deref()
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                {
+                    user_update.name = display_name.to_string();
+                }
+
+                navidrome_client.update_user(&user_update).await?.username
+            } else {
+                payload.username.clone()
+            };
+
             tracing::info!(
                 "[{} / {}] User already created, applying restrictions",
                 token.token(),
                 uuid
             );
 
-            if !create_option.is_admin && !create_option.library_ids.is_empty() {
+            if navidrome_client.library_access().await == Some(false) {
+                tracing::warn!(
+                    "[{}] Navidrome does not support library access, skipping restrictions for: {}",
+                    token.token(),
+                    &uuid
+                );
+            } else if !create_option.is_admin && !create_option.library_ids.is_empty() {
                 navidrome_client
                     .apply_user_library(&uuid, &create_option.into())
                     .await?;
@@ -181,13 +476,20 @@ async fn create_user_in_navidrome(
                 );
             }
 
+            database
+                .add_user(
+                    &ProvisionedUser::from_invite(token, &uuid, restrictions)
+                        .with_username(&username),
+                )
+                .await?;
+
             // Delete the invite token after successful user creation
             tracing::info!(
                 "[{}] Deleting invite token after user restriction application",
                 token.token()
             );
             database.delete_invite(token.token()).await?;
-            Ok(())
+            Ok(username)
         }
         None => {
             tracing::info!(
@@ -205,7 +507,13 @@ async fn create_user_in_navidrome(
             );
             database.apply_user_id(token.token(), &user.id).await?;
 
-            if !create_option.is_admin && !create_option.library_ids.is_empty() {
+            if navidrome_client.library_access().await == Some(false) {
+                tracing::warn!(
+                    "[{}] Navidrome does not support library access, skipping restrictions for: {}",
+                    token.token(),
+                    &user.id
+                );
+            } else if !create_option.is_admin && !create_option.library_ids.is_empty() {
                 // Apply user restrictions
                 tracing::info!(
                     "[{}] Applying restrictions for: {}",
@@ -224,6 +532,13 @@ async fn create_user_in_navidrome(
                 );
             }
 
+            database
+                .add_user(
+                    &ProvisionedUser::from_invite(token, &user.id, restrictions)
+                        .with_username(&payload.username),
+                )
+                .await?;
+
             // Delete the invite token after successful user creation
             tracing::info!(
                 "[{}] Deleting invite token after user creation",
@@ -236,31 +551,116 @@ async fn create_user_in_navidrome(
                 token.token(),
                 user.id
             );
-            Ok(())
+            Ok(user.username)
+        }
+    }
+}
+
+/// Redeem an invite, generating the credentials when the invite asks for it
+pub async fn redeem_invite(
+    state: &AppState,
+    token: &InviteToken,
+    payload: Option<InviteTokenApplicationPayload>,
+) -> Result<InviteRedemption, UserCreationError> {
+    let bound_email = token.meta().email.as_deref();
+
+    if !token.generates_credentials() {
+        let payload = payload.ok_or(UserCreationError::MissingCredentials)?;
+        if let Some(email) = bound_email
+            && !email.eq_ignore_ascii_case(&payload.email)
+        {
+            return Err(UserCreationError::EmailMismatch);
         }
+
+        return create_user_in(state, token, &payload).await;
     }
+
+    let mut payload = InviteTokenApplicationPayload::generate();
+    if let Some(email) = bound_email {
+        payload.email = email.to_string();
+    }
+    // an earlier attempt already created the account, and Komga cannot change its email
+    if let InviteToken::Komga {
+        uuid: Some(uuid), ..
+    } = token
+        && let Some(user) = state
+            .komga
+            .list_users()
+            .await?
+            .into_iter()
+            .find(|user| user.id == *uuid)
+    {
+        payload.email = user.email;
+    }
+    let mut redemption = create_user_in(state, token, &payload).await?;
+    let username = match &redemption.subsonic {
+        Some(subsonic) => subsonic.username.clone(),
+        None => payload.username,
+    };
+    redemption.credentials = Some(GeneratedCredentials {
+        username,
+        email: payload.email,
+        password: payload.password,
+    });
+
+    Ok(redemption)
 }
 
-pub async fn create_user_in(
+async fn create_user_in(
     state: &AppState,
     token: &InviteToken,
     payload: &InviteTokenApplicationPayload,
-) -> Result<String, UserCreationError> {
+) -> Result<InviteRedemption, UserCreationError> {
     match token {
         InviteToken::Komga { .. } => {
-            create_user_in_komga(&state.db, &state.komga, token, payload).await?;
+            let granted = create_user_in_komga(&state.db, &state.komga, token, payload).await?;
+
+            let api_key = match token {
+                InviteToken::Komga { option, .. } if option.issue_api_key => {
+                    tracing::info!("[{}] Creating API key for the new user", token.token());
+                    let comment = format!("K-Librarian ({})", token.token());
+                    match state
+                        .komga
+                        .create_api_key_for(&payload.email, &payload.password, &comment)
+                        .await
+                    {
+                        Ok(api_key) => Some(api_key.key),
+                        Err(e) => {
+                            // the account is already created, do not fail the redemption for this
+                            tracing::error!("[{}] Failed to create API key: {}", token.token(), e);
+                            None
+                        }
+                    }
+                }
+                _ => None,
+            };
 
             // get the host
             let host = state.config.komga_hostname();
 
-            Ok(host.to_string())
+            Ok(InviteRedemption {
+                host: host.to_string(),
+                credentials: None,
+                komga: Some(KomgaSetup::new(&state.komga, host, &granted, api_key).await),
+                subsonic: None,
+            })
         }
         InviteToken::Navidrome { .. } => {
             match (&state.navidrome, state.config.navidrome_hostname()) {
                 (Some(navidrome), Some(navidrome_host)) => {
-                    create_user_in_navidrome(&state.db, navidrome, token, payload).await?;
+                    let username =
+                        create_user_in_navidrome(&state.db, navidrome, token, payload).await?;
 
-                    Ok(navidrome_host.to_string())
+                    Ok(InviteRedemption {
+                        host: navidrome_host.to_string(),
+                        credentials: None,
+                        komga: None,
+                        subsonic: Some(SubsonicSetup {
+                            server: navidrome_host.to_string(),
+                            username,
+                            apps: SUBSONIC_APPS,
+                        }),
+                    })
                 }
                 _ => Err(UserCreationError::ClientUnavailable("Navidrome")),
             }
@@ -287,3 +687,69 @@ fn validate_username() -> impl FnOnce(&str, &()) -> garde::Result + 'static {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::komga::testing::{StubKomga, serve};
+
+    #[tokio::test]
+    async fn retries_a_generated_credentials_invite_on_the_same_account() {
+        let stub = Arc::new(Mutex::new(StubKomga {
+            failing_restrictions: 1,
+            ..StubKomga::default()
+        }));
+        let state = crate::testing::state(&serve(stub.clone()).await).await;
+
+        let option: KomgaInviteOption =
+            serde_json::from_value(serde_json::json!({ "generateCredentials": true })).unwrap();
+        let invite = InviteToken::create_komga(option);
+        state.db.add_invite(&invite).await.unwrap();
+
+        // the account is created, but restricting it fails
+        assert!(redeem_invite(&state, &invite, None).await.is_err());
+        let invite = state.db.get_invite(invite.token()).await.unwrap().unwrap();
+        let created = stub.lock().unwrap().users[0].clone();
+        assert_eq!(invite.uuid(), Some(created.id.as_str()));
+
+        let redemption = redeem_invite(&state, &invite, None).await.unwrap();
+        let credentials = redemption.credentials.unwrap();
+
+        assert_eq!(credentials.email, created.email);
+        assert!(state.db.get_invite(invite.token()).await.unwrap().is_none());
+
+        let stub = stub.lock().unwrap();
+        assert_eq!(stub.users.len(), 1);
+        assert_eq!(stub.email_changes, 0);
+        assert_eq!(stub.passwords[&created.id], credentials.password);
+    }
+
+    #[tokio::test]
+    async fn only_links_kobo_sync_with_an_api_key() {
+        let url = serve(Arc::new(Mutex::new(StubKomga::default()))).await;
+        let komga = komga::KomgaClient::new(url, "service".to_string(), "password".to_string());
+        let granted = KomgaProfileOption {
+            labels_allow: None,
+            labels_exclude: None,
+            shared_libraries: None,
+            roles: Some(vec!["KOBO_SYNC".to_string()]),
+        };
+
+        let setup = KomgaSetup::new(&komga, "https://komga.example.com/", &granted, None).await;
+        assert_eq!(setup.kobo, None);
+
+        let setup = KomgaSetup::new(
+            &komga,
+            "https://komga.example.com/",
+            &granted,
+            Some("key".to_string()),
+        )
+        .await;
+        assert_eq!(
+            setup.kobo.as_deref(),
+            Some("https://komga.example.com/kobo/key/")
+        );
+    }
+}
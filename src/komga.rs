@@ -1,4 +1,7 @@
-use crate::{config::KomgaConfig, database::KomgaInviteOption};
+use crate::{
+    config::KomgaConfig,
+    database::{KomgaInviteOption, KomgaProfileOption},
+};
 
 const USER_AGENT: &str = concat!(
     "K-Librarian/",
@@ -13,7 +16,7 @@ pub struct KomgaClient {
     client: reqwest::Client,
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
 pub struct KomgaUser {
     pub id: String,
     pub email: String,
@@ -50,6 +53,8 @@ pub struct KomgaUserCreateOption {
     pub labels_exclude: Option<Vec<String>>,
     #[serde(rename = "sharedLibraries")]
     pub shared_libraries: Option<KomgaUserCreateOptionSharedLibraries>,
+    #[serde(rename = "roles", skip_serializing_if = "Option::is_none")]
+    pub roles: Option<Vec<String>>,
 }
 
 impl From<KomgaInviteOption> for KomgaUserCreateOption {
@@ -58,10 +63,30 @@ impl From<KomgaInviteOption> for KomgaUserCreateOption {
             labels_allow: val.labels_allow,
             labels_exclude: val.labels_exclude,
             shared_libraries: val.shared_libraries,
+            // roles are already set when creating the user
+            roles: None,
+        }
+    }
+}
+
+impl From<KomgaProfileOption> for KomgaUserCreateOption {
+    fn from(val: KomgaProfileOption) -> Self {
+        KomgaUserCreateOption {
+            labels_allow: val.labels_allow,
+            labels_exclude: val.labels_exclude,
+            shared_libraries: val.shared_libraries,
+            roles: val.roles,
         }
     }
 }
 
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct KomgaApiKey {
+    pub id: String,
+    pub key: String,
+    pub comment: String,
+}
+
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct KomgaMinimalLibrary {
     pub id: String,
@@ -117,6 +142,71 @@ impl std::fmt::Display for KomgaCommonError {
     }
 }
 
+/// A single server-sent event published by Komga
+#[derive(Debug)]
+pub struct KomgaEvent {
+    pub event: String,
+    pub data: serde_json::Value,
+}
+
+/// An open connection to the Komga server-sent events endpoint
+pub struct KomgaEventStream {
+    response: reqwest::Response,
+    /// Bytes received but not yet part of a complete line, a chunk can end inside a character
+    buffer: Vec<u8>,
+    /// The lines of the event being received
+    event: String,
+}
+
+impl KomgaEventStream {
+    /// Wait for the next event, returns `None` once Komga closes the stream
+    pub async fn next_event(&mut self) -> Result<Option<KomgaEvent>, KomgaError> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&byte| byte == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end_matches(['\n', '\r']);
+
+                // events are separated by an empty line
+                if line.is_empty() {
+                    let raw_event = std::mem::take(&mut self.event);
+                    if let Some(event) = parse_sse_event(&raw_event) {
+                        return Ok(Some(event));
+                    }
+                } else {
+                    self.event.push_str(line);
+                    self.event.push('\n');
+                }
+                continue;
+            }
+
+            match self.response.chunk().await? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+fn parse_sse_event(raw_event: &str) -> Option<KomgaEvent> {
+    let mut event = None;
+    let mut data = String::new();
+
+    for line in raw_event.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            event = Some(value.trim().to_string());
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push_str(value.trim());
+        }
+    }
+
+    // comments and keep-alive have no event name
+    let event = event?;
+    let data = serde_json::from_str(&data).unwrap_or(serde_json::Value::Null);
+
+    Some(KomgaEvent { event, data })
+}
+
 impl KomgaClient {
     pub fn new(url: String, username: String, password: String) -> Self {
         let client = reqwest::ClientBuilder::new()
@@ -153,6 +243,24 @@ impl KomgaClient {
         Ok(user)
     }
 
+    pub async fn list_users(&self) -> Result<Vec<KomgaUser>, KomgaError> {
+        let res = self
+            .client
+            .get(format!("{}/api/v2/users", self.url))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .await?;
+
+        // an error page would otherwise look like a parse error, or like nobody exists anymore
+        if !res.status().is_success() {
+            return Err(KomgaError::ListUsers(res.status()));
+        }
+
+        let users: Vec<KomgaUser> = res.json().await?;
+
+        Ok(users)
+    }
+
     pub async fn create_user(&self, user: KomgaUserCreate) -> Result<KomgaUser, KomgaError> {
         let res = self
             .client
@@ -196,6 +304,87 @@ impl KomgaClient {
         }
     }
 
+    /// Komga has no "disabled" state, so every library and role is taken away from the user
+    /// instead. Their API keys can only be removed by the user, but there is nothing left for
+    /// them to reach.
+    pub async fn revoke_user_access(&self, user_id: &str) -> Result<(), KomgaError> {
+        let option = KomgaUserCreateOption {
+            labels_allow: None,
+            labels_exclude: None,
+            shared_libraries: Some(KomgaUserCreateOptionSharedLibraries {
+                all: false,
+                library_ids: vec![],
+            }),
+            // without the streaming, download and sync roles nothing can be read anymore
+            roles: Some(vec![]),
+        };
+
+        self.apply_user_restriction(user_id, &option).await
+    }
+
+    pub async fn delete_user(&self, user_id: &str) -> Result<(), KomgaError> {
+        let res = self
+            .client
+            .delete(format!("{}/api/v2/users/{}", self.url, user_id))
+            .basic_auth(&self.username, Some(&self.password))
+            .send()
+            .await?;
+
+        let status_code = res.status();
+
+        if status_code.is_success() {
+            Ok(())
+        } else if status_code == reqwest::StatusCode::NOT_FOUND {
+            Err(KomgaError::UserNotFound(user_id.to_string()))
+        } else {
+            Err(KomgaError::DeleteUser)
+        }
+    }
+
+    pub async fn update_user_password(
+        &self,
+        user_id: &str,
+        password: &str,
+    ) -> Result<(), KomgaError> {
+        let res = self
+            .client
+            .patch(format!("{}/api/v2/users/{}/password", self.url, user_id))
+            .basic_auth(&self.username, Some(&self.password))
+            .json(&serde_json::json!({ "password": password }))
+            .send()
+            .await?;
+
+        if res.status().is_success() {
+            Ok(())
+        } else {
+            Err(KomgaError::UpdateUser)
+        }
+    }
+
+    /// Create an API key for another user, authenticating as that user
+    pub async fn create_api_key_for(
+        &self,
+        email: &str,
+        password: &str,
+        comment: &str,
+    ) -> Result<KomgaApiKey, KomgaError> {
+        let res = self
+            .client
+            .post(format!("{}/api/v2/users/me/api-keys", self.url))
+            .basic_auth(email, Some(password))
+            .json(&serde_json::json!({ "comment": comment }))
+            .send()
+            .await?;
+
+        if res.status().is_success() {
+            let api_key: KomgaApiKey = res.json().await?;
+
+            Ok(api_key)
+        } else {
+            Err(KomgaError::CreateApiKey)
+        }
+    }
+
     pub async fn get_sharing_labels(&self) -> Result<Vec<String>, KomgaError> {
         let res = self
             .client
@@ -222,6 +411,23 @@ impl KomgaClient {
         Ok(libraries)
     }
 
+    pub async fn subscribe_events(&self) -> Result<KomgaEventStream, KomgaError> {
+        let res = self
+            .client
+            .get(format!("{}/sse/v1/events", self.url))
+            .basic_auth(&self.username, Some(&self.password))
+            .header(reqwest::header::ACCEPT, "text/event-stream")
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(KomgaEventStream {
+            response: res,
+            buffer: Vec::new(),
+            event: String::new(),
+        })
+    }
+
     pub fn get_host(&self) -> String {
         self.url.clone()
     }
@@ -239,6 +445,168 @@ pub enum KomgaError {
     Violation(#[from] KomgaViolationsError),
     #[error("failed to apply user restriction")]
     ApplyUserRestriction,
+    #[error("failed to list users, Komga answered {0}")]
+    ListUsers(reqwest::StatusCode),
+    #[error("failed to delete user")]
+    DeleteUser,
+    #[error("failed to create API key")]
+    CreateApiKey,
+    #[error("failed to update user")]
+    UpdateUser,
+    #[error("user not found: {0}")]
+    UserNotFound(String),
     #[error("unknown error occurred")]
     Unknown,
 }
+
+/// A Komga server keeping its users in memory, for the tests
+#[cfg(test)]
+pub mod testing {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        Json, Router,
+        extract::{Path, State},
+        http::StatusCode,
+        routing::{get, patch},
+    };
+
+    use super::{KomgaUser, KomgaUserCreate};
+
+    #[derive(Default)]
+    pub struct StubKomga {
+        pub users: Vec<KomgaUser>,
+        pub passwords: HashMap<String, String>,
+        /// How many of the next restriction updates fail
+        pub failing_restrictions: u32,
+        /// Every email change that was asked for, Komga ignores them
+        pub email_changes: u32,
+    }
+
+    pub type SharedStub = Arc<Mutex<StubKomga>>;
+
+    /// Serve the stub on a random port, returns its URL
+    pub async fn serve(stub: SharedStub) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let router = Router::new()
+            .route("/api/v2/users", get(list_users).post(create_user))
+            .route("/api/v2/users/{id}", patch(update_user))
+            .route("/api/v2/users/{id}/password", patch(update_password))
+            .route(
+                "/api/v1/libraries",
+                get(|| async { Json(Vec::<()>::new()) }),
+            )
+            .with_state(stub);
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        url
+    }
+
+    async fn list_users(State(stub): State<SharedStub>) -> Json<Vec<KomgaUser>> {
+        let stub = stub.lock().unwrap();
+        Json(stub.users.clone())
+    }
+
+    async fn create_user(
+        State(stub): State<SharedStub>,
+        Json(create): Json<KomgaUserCreate>,
+    ) -> Json<KomgaUser> {
+        let mut stub = stub.lock().unwrap();
+        let user = KomgaUser {
+            id: format!("komga-{}", stub.users.len() + 1),
+            email: create.email,
+            roles: create.roles,
+            shared_all_libraries: true,
+            shared_libraries_ids: vec![],
+            labels_allow: vec![],
+            labels_exclude: vec![],
+        };
+        stub.passwords.insert(user.id.clone(), create.password);
+        stub.users.push(user.clone());
+
+        Json(user)
+    }
+
+    async fn update_user(
+        State(stub): State<SharedStub>,
+        Path(id): Path<String>,
+        Json(update): Json<serde_json::Value>,
+    ) -> StatusCode {
+        let mut stub = stub.lock().unwrap();
+        if update.get("email").is_some() {
+            stub.email_changes += 1;
+        }
+        if !stub.users.iter().any(|user| user.id == id) {
+            return StatusCode::NOT_FOUND;
+        }
+        if stub.failing_restrictions > 0 {
+            stub.failing_restrictions -= 1;
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+
+        let user = stub.users.iter_mut().find(|user| user.id == id).unwrap();
+        if let Ok(roles) = serde_json::from_value(update["roles"].clone()) {
+            user.roles = roles;
+        }
+        if let Some(shared) = update["sharedLibraries"].as_object() {
+            user.shared_all_libraries = shared["all"].as_bool().unwrap_or_default();
+            user.shared_libraries_ids =
+                serde_json::from_value(shared["libraryIds"].clone()).unwrap_or_default();
+        }
+
+        StatusCode::NO_CONTENT
+    }
+
+    async fn update_password(
+        State(stub): State<SharedStub>,
+        Path(id): Path<String>,
+        Json(update): Json<serde_json::Value>,
+    ) -> StatusCode {
+        let mut stub = stub.lock().unwrap();
+        let password = update["password"].as_str().unwrap_or_default().to_string();
+        stub.passwords.insert(id, password);
+
+        StatusCode::NO_CONTENT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{
+        testing::{StubKomga, serve},
+        *,
+    };
+
+    #[tokio::test]
+    async fn revoking_takes_every_library_and_role_away() {
+        let stub = Arc::new(Mutex::new(StubKomga {
+            users: vec![KomgaUser {
+                id: "reader".to_string(),
+                email: "reader@example.com".to_string(),
+                roles: vec!["FILE_DOWNLOAD".to_string(), "KOBO_SYNC".to_string()],
+                shared_all_libraries: true,
+                shared_libraries_ids: vec![],
+                labels_allow: vec![],
+                labels_exclude: vec![],
+            }],
+            ..Default::default()
+        }));
+        let url = serve(stub.clone()).await;
+        let client = KomgaClient::new(url, "service".to_string(), "password".to_string());
+
+        client.revoke_user_access("reader").await.unwrap();
+
+        let stub = stub.lock().unwrap();
+        let user = &stub.users[0];
+        assert!(user.roles.is_empty());
+        assert!(!user.shared_all_libraries);
+        assert!(user.shared_libraries_ids.is_empty());
+    }
+}
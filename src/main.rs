@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use axum::{
     Router,
@@ -19,12 +19,19 @@ use urlencoding::encode;
 
 include!(concat!(env!("OUT_DIR"), "/index_html.rs"));
 
+mod auth;
 mod config;
 mod database;
+mod events;
 mod invitee;
 mod komga;
 mod navidrome;
+mod oidc;
+mod ratelimit;
+mod reconcile;
 mod routes;
+mod scheduler;
+mod webauthn;
 
 #[derive(Clone)]
 pub struct AppState {
@@ -32,6 +39,7 @@ pub struct AppState {
     pub config: Arc<config::Config>,
     pub komga: Arc<KomgaClient>,
     pub navidrome: Option<Arc<Mutex<navidrome::NavidromeClient>>>,
+    pub limiter: Arc<ratelimit::RateLimiter>,
 }
 
 #[tokio::main]
@@ -45,6 +53,17 @@ async fn main() {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    // `k-librarian hash-token [token]` prints a hash to put in the config instead of the token
+    if std::env::args().nth(1).as_deref() == Some("hash-token") {
+        hash_token_command();
+        return;
+    }
+    // `k-librarian totp-secret` prints a secret to ask for a two-factor code with the token
+    if std::env::args().nth(1).as_deref() == Some("totp-secret") {
+        totp_secret_command();
+        return;
+    }
+
     let version = env!("CARGO_PKG_VERSION");
 
     tracing::info!("📚 K-Librarian v{}", version);
@@ -59,6 +78,11 @@ async fn main() {
         std::process::exit(1);
     });
     tracing::info!("  ✨ Configuration is valid");
+    if !auth::is_hashed_token(&config.token) {
+        tracing::warn!(
+            "  ⚠️ The admin token is stored in plaintext, run `k-librarian hash-token` and put the hash in the config instead"
+        );
+    }
 
     println!("🔌 Connecting to database at: {}", config.db_path.display());
     let db = database::LocalDatabase::new(&config.db_path)
@@ -105,6 +129,20 @@ async fn main() {
                         );
                         std::process::exit(1);
                     }
+                    let capabilities = client.capabilities();
+                    tracing::info!(
+                        "  🔎 Navidrome version: {}",
+                        capabilities.version.as_deref().unwrap_or("unknown")
+                    );
+                    match capabilities.library_access {
+                        Some(true) => {}
+                        Some(false) => tracing::warn!(
+                            "  ⚠️ Navidrome does not support per-user library access, library restrictions will be skipped"
+                        ),
+                        None => tracing::warn!(
+                            "  ⚠️ Could not check if Navidrome supports per-user library access, will ask again when needed"
+                        ),
+                    }
                     Some(Arc::new(Mutex::new(client)))
                 }
                 Err(e) => {
@@ -119,13 +157,18 @@ async fn main() {
         }
     };
 
+    let limiter = ratelimit::RateLimiter::new(config.rate_limit.clone());
     let state = AppState {
         db: Arc::new(db),
         config: Arc::new(config),
         komga: Arc::new(komga_client),
         navidrome: navidrome_client,
+        limiter: Arc::new(limiter),
     };
 
+    tracing::info!("⏰ Starting background scheduler");
+    scheduler::spawn(state.clone());
+
     let assets_dir = ServeDir::new("assets/assets");
 
     let app: Router = Router::new()
@@ -154,7 +197,58 @@ async fn main() {
         "🚀 Fast serving at: http://{}",
         listener.local_addr().unwrap()
     );
-    axum::serve(listener, app).await.unwrap()
+    // the address of the client is needed to only trust the headers of the reverse proxy
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap()
+}
+
+/// Hash the token given as argument, or read from stdin to keep it out of the shell history
+fn hash_token_command() {
+    let token = match std::env::args().nth(2) {
+        Some(token) => token,
+        None => {
+            eprintln!("Enter the admin token to hash:");
+            let mut token = String::new();
+            if let Err(e) = std::io::stdin().read_line(&mut token) {
+                eprintln!("💥 Failed to read the token: {e}");
+                std::process::exit(1);
+            }
+            token.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+
+    if token.trim().is_empty() {
+        eprintln!("💥 The token cannot be empty");
+        std::process::exit(1);
+    }
+
+    match auth::hash_password(&token) {
+        Ok(hash) => println!("{hash}"),
+        Err(e) => {
+            eprintln!("💥 Failed to hash the token: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Print a new TOTP secret for the token, with the link to add it to an authenticator app
+fn totp_secret_command() {
+    let secret = auth::generate_totp_secret();
+
+    match auth::totp(&secret, auth::SHARED_TOKEN_TOTP_ACCOUNT) {
+        Ok(totp) => {
+            println!("token-totp-secret = \"{secret}\"");
+            println!("{}", totp.get_url());
+        }
+        Err(e) => {
+            eprintln!("💥 Failed to create the TOTP secret: {e}");
+            std::process::exit(1);
+        }
+    }
 }
 
 async fn handle_404(url: Uri) -> Redirect {
@@ -168,3 +262,46 @@ async fn handle_404(url: Uri) -> Redirect {
 async fn index(_: State<AppState>) -> impl IntoResponse {
     Html(INDEX_HTML)
 }
+
+/// An app state backed by a fresh database, for the tests
+#[cfg(test)]
+pub mod testing {
+    use std::sync::Arc;
+
+    use crate::{AppState, config::Config, database::LocalDatabase, komga::KomgaClient, ratelimit};
+
+    pub async fn state(komga_url: &str) -> AppState {
+        let config = Config::from_str(&format!(
+            r#"
+            host = "127.0.0.1"
+            port = 5148
+            token = "test-token"
+            db-path = "unused.sqlite"
+
+            [komga]
+            host = "{komga_url}"
+            username = "service@example.com"
+            password = "service-password"
+
+            [navidrome]
+            host = "http://127.0.0.1:1"
+            username = "service"
+            password = "service-password"
+            "#
+        ))
+        .unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("k-librarian-{}.sqlite", uuid::Uuid::new_v4()));
+        let db = LocalDatabase::new(&path).await.unwrap();
+        db.setup().await.unwrap();
+
+        AppState {
+            db: Arc::new(db),
+            komga: Arc::new(KomgaClient::instance(&config.komga)),
+            navidrome: None,
+            limiter: Arc::new(ratelimit::RateLimiter::new(config.rate_limit.clone())),
+            config: Arc::new(config),
+        }
+    }
+}
@@ -1,6 +1,10 @@
 use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use rand::{Rng, distr::Alphanumeric};
 
-use crate::{config::NavidromeConfig, database::NavidromeInviteOption};
+use crate::{
+    config::NavidromeConfig,
+    database::{NavidromeInviteOption, NavidromeProfileOption},
+};
 
 const USER_AGENT: &str = concat!(
     "K-Librarian/",
@@ -11,17 +15,43 @@ const USER_AGENT: &str = concat!(
 #[derive(Debug, serde::Deserialize)]
 struct NavidromeLoginResponse {
     token: String,
+    #[serde(rename = "subsonicSalt", default)]
+    subsonic_salt: Option<String>,
+    #[serde(rename = "subsonicToken", default)]
+    subsonic_token: Option<String>,
+}
+
+/// Features of the Navidrome server that differ between versions
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct NavidromeCapabilities {
+    /// The server version, if the server reported it
+    pub version: Option<String>,
+    /// Whether the server supports restricting users to specific libraries (0.58+), empty while
+    /// the server has not given a clear answer yet
+    #[serde(rename = "libraryAccess")]
+    pub library_access: Option<bool>,
 }
 
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct NavidromeMinimalLibrary {
-    id: u64,
-    name: String,
+    pub id: u64,
+    pub name: String,
 }
 
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct NavidromeUser {
     pub id: String,
+    #[serde(rename = "userName", default)]
+    pub username: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub email: String,
+    #[serde(rename = "isAdmin", default)]
+    pub is_admin: bool,
+    /// Only returned by servers that support per-user library access
+    #[serde(default)]
+    pub libraries: Option<Vec<NavidromeMinimalLibrary>>,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -54,6 +84,40 @@ impl NavidromeUserCreate {
             username,
         }
     }
+
+    /// Use a display name different from the username
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+}
+
+/// The full user sent back to Navidrome when updating, Navidrome replaces the whole user
+#[derive(Debug, serde::Serialize)]
+pub struct NavidromeUserUpdate {
+    pub id: String,
+    #[serde(rename = "userName")]
+    pub username: String,
+    pub name: String,
+    pub email: String,
+    #[serde(rename = "isAdmin")]
+    pub is_admin: bool,
+    /// Only sent when changing the password
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+impl From<NavidromeUser> for NavidromeUserUpdate {
+    fn from(user: NavidromeUser) -> Self {
+        Self {
+            id: user.id,
+            username: user.username,
+            name: user.name,
+            email: user.email,
+            is_admin: user.is_admin,
+            password: None,
+        }
+    }
 }
 
 #[derive(serde::Serialize, Debug)]
@@ -70,11 +134,20 @@ impl From<NavidromeInviteOption> for NavidromeUserCreateOption {
     }
 }
 
+impl From<NavidromeProfileOption> for NavidromeUserCreateOption {
+    fn from(option: NavidromeProfileOption) -> Self {
+        NavidromeUserCreateOption {
+            library_ids: option.library_ids,
+        }
+    }
+}
+
 pub struct NavidromeClient {
     client: reqwest::Client,
     config: NavidromeConfig,
     token: String,
     claims: MinimalJwtClaims,
+    capabilities: NavidromeCapabilities,
 }
 
 impl NavidromeClient {
@@ -98,18 +171,104 @@ impl NavidromeClient {
         // decode jwt
         let decoded_claims = decode_jwt(&login_json.token)?;
 
-        Ok(Self {
+        let mut navidrome = Self {
             client,
             config: config.clone(),
-            token: login_json.token,
+            token: login_json.token.clone(),
             claims: decoded_claims,
-        })
+            capabilities: NavidromeCapabilities {
+                version: None,
+                library_access: None,
+            },
+        };
+        navidrome.capabilities = navidrome.detect_capabilities(&login_json).await;
+
+        Ok(navidrome)
+    }
+
+    async fn detect_capabilities(
+        &mut self,
+        login: &NavidromeLoginResponse,
+    ) -> NavidromeCapabilities {
+        // the Subsonic ping reports the server version, authenticated with the salt from the login
+        let version = match (&login.subsonic_salt, &login.subsonic_token) {
+            (Some(salt), Some(token)) => {
+                let ping_url = format!("{}/rest/ping", self.config.host);
+                let response = self
+                    .client
+                    .get(&ping_url)
+                    .query(&[
+                        ("u", self.config.username.as_str()),
+                        ("s", salt.as_str()),
+                        ("t", token.as_str()),
+                        ("v", "1.16.1"),
+                        ("c", "k-librarian"),
+                        ("f", "json"),
+                    ])
+                    .send()
+                    .await;
+
+                match response {
+                    Ok(response) => {
+                        response
+                            .json::<serde_json::Value>()
+                            .await
+                            .ok()
+                            .and_then(|ping| {
+                                ping["subsonic-response"]["serverVersion"]
+                                    .as_str()
+                                    .map(|version| version.to_string())
+                            })
+                    }
+                    Err(_) => None,
+                }
+            }
+            _ => None,
+        };
+
+        NavidromeCapabilities {
+            version,
+            library_access: self.probe_library_access().await,
+        }
+    }
+
+    /// Older servers do not have the per-user library endpoint at all, any other failure says
+    /// nothing about the server and leaves the answer empty
+    async fn probe_library_access(&self) -> Option<bool> {
+        let library_url = format!("{}/api/user/{}/library", self.config.host, self.claims.uid);
+        let response = self
+            .client
+            .get(&library_url)
+            .header("x-nd-authorization", self.token())
+            .send()
+            .await
+            .ok()?;
+
+        match response.status() {
+            status if status.is_success() => Some(true),
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::METHOD_NOT_ALLOWED => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Whether the server supports restricting users to specific libraries, asking it again when
+    /// it did not give a clear answer before. Empty when it still does not.
+    pub async fn library_access(&mut self) -> Option<bool> {
+        if self.capabilities.library_access.is_none() {
+            self.capabilities.library_access = self.probe_library_access().await;
+        }
+
+        self.capabilities.library_access
     }
 
     pub fn claims(&self) -> &MinimalJwtClaims {
         &self.claims
     }
 
+    pub fn capabilities(&self) -> &NavidromeCapabilities {
+        &self.capabilities
+    }
+
     fn token(&self) -> String {
         format!("Bearer {}", self.token)
     }
@@ -141,6 +300,60 @@ impl NavidromeClient {
         Ok(libraries)
     }
 
+    pub async fn list_users(&mut self) -> Result<Vec<NavidromeUser>, NavidromeError> {
+        let url = format!("{}/api/user", self.config.host);
+        let response = self
+            .client
+            .get(&url)
+            .query(&[
+                ("_end", "-1"),
+                ("_start", "0"),
+                ("_sort", "userName"),
+                ("_order", "asc"),
+            ])
+            .header("x-nd-authorization", self.token())
+            .send()
+            .await?;
+
+        // get x-nd-authorization header
+        if let Some(auth_header) = response.headers().get("x-nd-authorization")
+            && let Ok(auth_value) = auth_header.to_str()
+        {
+            self.token = auth_value.to_string();
+        }
+
+        let users: Vec<NavidromeUser> = response.json().await?;
+
+        Ok(users)
+    }
+
+    pub async fn get_user(
+        &mut self,
+        user_id: &str,
+    ) -> Result<Option<NavidromeUser>, NavidromeError> {
+        let url = format!("{}/api/user/{}", self.config.host, user_id);
+        let response = self
+            .client
+            .get(&url)
+            .header("x-nd-authorization", self.token())
+            .send()
+            .await?;
+
+        // get x-nd-authorization header
+        if let Some(auth_header) = response.headers().get("x-nd-authorization")
+            && let Ok(auth_value) = auth_header.to_str()
+        {
+            self.token = auth_value.to_string();
+        }
+
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let user = response.json::<NavidromeUser>().await?;
+        Ok(Some(user))
+    }
+
     pub async fn create_user(
         &mut self,
         user: NavidromeUserCreate,
@@ -170,6 +383,10 @@ impl NavidromeClient {
         user_id: &str,
         option: &NavidromeUserCreateOption,
     ) -> Result<(), NavidromeError> {
+        if self.library_access().await == Some(false) {
+            return Err(NavidromeError::Unsupported("per-user library access"));
+        }
+
         let url = format!("{}/api/user/{}/library", self.config.host, user_id);
         let response = self
             .client
@@ -192,6 +409,98 @@ impl NavidromeClient {
             Err(NavidromeError::ApplyUserRestriction)
         }
     }
+
+    pub async fn update_user(
+        &mut self,
+        user: &NavidromeUserUpdate,
+    ) -> Result<NavidromeUser, NavidromeError> {
+        let url = format!("{}/api/user/{}", self.config.host, user.id);
+        let response = self
+            .client
+            .put(&url)
+            .json(user)
+            .header("x-nd-authorization", self.token())
+            .send()
+            .await?;
+
+        // get x-nd-authorization header
+        if let Some(auth_header) = response.headers().get("x-nd-authorization")
+            && let Ok(auth_value) = auth_header.to_str()
+        {
+            self.token = auth_value.to_string();
+        }
+
+        if !response.status().is_success() {
+            return Err(NavidromeError::UpdateUser);
+        }
+
+        let resp = response.json::<NavidromeUser>().await?;
+        Ok(resp)
+    }
+
+    /// Take every library away from the user, or lock them out with a random password when the
+    /// server cannot restrict libraries per user or taking the libraries away failed
+    pub async fn revoke_user_access(&mut self, user_id: &str) -> Result<(), NavidromeError> {
+        if self.library_access().await != Some(false) {
+            let option = NavidromeUserCreateOption {
+                library_ids: vec![],
+            };
+
+            match self.apply_user_library(user_id, &option).await {
+                Ok(()) => return Ok(()),
+                Err(e) => tracing::warn!(
+                    "Failed to take the libraries away from Navidrome user {}, resetting their password instead: {}",
+                    user_id,
+                    e
+                ),
+            }
+        }
+
+        self.reset_password(user_id).await
+    }
+
+    async fn reset_password(&mut self, user_id: &str) -> Result<(), NavidromeError> {
+        // a user that is already gone has no access left to take away
+        let Some(user) = self.get_user(user_id).await? else {
+            return Ok(());
+        };
+
+        let password: String = rand::rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let update = NavidromeUserUpdate {
+            password: Some(password),
+            ..user.into()
+        };
+
+        self.update_user(&update).await?;
+        Ok(())
+    }
+
+    pub async fn delete_user(&mut self, user_id: &str) -> Result<(), NavidromeError> {
+        let url = format!("{}/api/user/{}", self.config.host, user_id);
+        let response = self
+            .client
+            .delete(&url)
+            .header("x-nd-authorization", self.token())
+            .send()
+            .await?;
+
+        // get x-nd-authorization header
+        if let Some(auth_header) = response.headers().get("x-nd-authorization")
+            && let Ok(auth_value) = auth_header.to_str()
+        {
+            self.token = auth_value.to_string();
+        }
+
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(NavidromeError::DeleteUser)
+        }
+    }
 }
 
 #[derive(serde::Deserialize)]
@@ -232,6 +541,12 @@ pub enum NavidromeError {
     JWTDecode(&'static str),
     #[error("failed to apply user restriction")]
     ApplyUserRestriction,
+    #[error("server does not support {0}")]
+    Unsupported(&'static str),
+    #[error("failed to update user")]
+    UpdateUser,
+    #[error("failed to delete user")]
+    DeleteUser,
     #[error("unknown error occurred")]
     Unknown,
 }
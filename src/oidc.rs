@@ -0,0 +1,465 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use openidconnect::{
+    AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, OAuth2TokenResponse,
+    PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, Scope,
+    core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata},
+};
+
+use crate::config::OidcConfig;
+
+#[derive(thiserror::Error, Debug)]
+pub enum OidcError {
+    #[error("failed to create HTTP client: {0}")]
+    HttpClient(#[from] reqwest::Error),
+    #[error("invalid OIDC configuration: {0}")]
+    Configuration(String),
+    #[error("failed to discover the provider: {0}")]
+    Discovery(String),
+    #[error("failed to exchange the authorization code: {0}")]
+    Exchange(String),
+    #[error("the provider did not return an ID token")]
+    MissingIdToken,
+    #[error("invalid ID token: {0}")]
+    InvalidIdToken(String),
+    #[error("failed to get the user info: {0}")]
+    UserInfo(String),
+}
+
+/// A login that was sent to the provider, kept until it comes back to the callback
+pub struct OidcLogin {
+    pub url: String,
+    pub state: String,
+    pub pkce_verifier: String,
+    pub nonce: String,
+}
+
+/// The user the provider vouched for
+pub struct OidcIdentity {
+    pub subject: String,
+    pub username: String,
+    pub groups: Vec<String>,
+}
+
+/// The provider only gets called from the server, and should never redirect us somewhere else
+fn http_client() -> Result<reqwest::Client, OidcError> {
+    Ok(reqwest::ClientBuilder::new()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?)
+}
+
+async fn provider_metadata(
+    config: &OidcConfig,
+    http_client: &reqwest::Client,
+) -> Result<CoreProviderMetadata, OidcError> {
+    let issuer = IssuerUrl::new(config.issuer.trim_end_matches('/').to_string())
+        .map_err(|e| OidcError::Configuration(e.to_string()))?;
+
+    CoreProviderMetadata::discover_async(issuer, http_client)
+        .await
+        .map_err(|e| OidcError::Discovery(e.to_string()))
+}
+
+fn client(
+    config: &OidcConfig,
+    metadata: CoreProviderMetadata,
+    redirect_url: &str,
+) -> Result<
+    CoreClient<
+        openidconnect::EndpointSet,
+        openidconnect::EndpointNotSet,
+        openidconnect::EndpointNotSet,
+        openidconnect::EndpointNotSet,
+        openidconnect::EndpointMaybeSet,
+        openidconnect::EndpointMaybeSet,
+    >,
+    OidcError,
+> {
+    let redirect_url = RedirectUrl::new(redirect_url.to_string())
+        .map_err(|e| OidcError::Configuration(e.to_string()))?;
+
+    Ok(CoreClient::from_provider_metadata(
+        metadata,
+        ClientId::new(config.client_id.clone()),
+        config.client_secret.clone().map(ClientSecret::new),
+    )
+    .set_redirect_uri(redirect_url))
+}
+
+/// Build the URL to send the admin to, using the authorization code flow with PKCE
+pub async fn start_login(config: &OidcConfig, redirect_url: &str) -> Result<OidcLogin, OidcError> {
+    let metadata = provider_metadata(config, &http_client()?).await?;
+    start_login_with(config, metadata, redirect_url)
+}
+
+/// Same as [`start_login`], with the provider metadata already known
+fn start_login_with(
+    config: &OidcConfig,
+    metadata: CoreProviderMetadata,
+    redirect_url: &str,
+) -> Result<OidcLogin, OidcError> {
+    let client = client(config, metadata, redirect_url)?;
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+    let (url, state, nonce) = client
+        .authorize_url(
+            CoreAuthenticationFlow::AuthorizationCode,
+            CsrfToken::new_random,
+            Nonce::new_random,
+        )
+        .add_scopes(config.scopes.iter().cloned().map(Scope::new))
+        .set_pkce_challenge(pkce_challenge)
+        .url();
+
+    Ok(OidcLogin {
+        url: url.to_string(),
+        state: state.secret().clone(),
+        pkce_verifier: pkce_verifier.secret().clone(),
+        nonce: nonce.secret().clone(),
+    })
+}
+
+/// Exchange the code from the callback and check the ID token it comes with
+pub async fn finish_login(
+    config: &OidcConfig,
+    redirect_url: &str,
+    code: &str,
+    pkce_verifier: &str,
+    nonce: &str,
+) -> Result<OidcIdentity, OidcError> {
+    let http_client = http_client()?;
+    let metadata = provider_metadata(config, &http_client).await?;
+    finish_login_with(
+        config,
+        metadata,
+        &http_client,
+        redirect_url,
+        code,
+        pkce_verifier,
+        nonce,
+    )
+    .await
+}
+
+/// Same as [`finish_login`], with the provider metadata already known
+async fn finish_login_with(
+    config: &OidcConfig,
+    metadata: CoreProviderMetadata,
+    http_client: &reqwest::Client,
+    redirect_url: &str,
+    code: &str,
+    pkce_verifier: &str,
+    nonce: &str,
+) -> Result<OidcIdentity, OidcError> {
+    let userinfo_url = metadata.userinfo_endpoint().cloned();
+    let client = client(config, metadata, redirect_url)?;
+
+    let token_response = client
+        .exchange_code(AuthorizationCode::new(code.to_string()))
+        .map_err(|e| OidcError::Configuration(e.to_string()))?
+        .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier.to_string()))
+        .request_async(http_client)
+        .await
+        .map_err(|e| OidcError::Exchange(e.to_string()))?;
+
+    let id_token = token_response
+        .extra_fields()
+        .id_token()
+        .ok_or(OidcError::MissingIdToken)?;
+    let claims = id_token
+        .claims(&client.id_token_verifier(), &Nonce::new(nonce.to_string()))
+        .map_err(|e| OidcError::InvalidIdToken(e.to_string()))?;
+
+    let subject = claims.subject().to_string();
+    let username = claims
+        .preferred_username()
+        .map(|username| username.to_string())
+        .or_else(|| claims.email().map(|email| email.to_string()))
+        .unwrap_or_else(|| subject.clone());
+
+    // the signature was checked above, so the payload can be read for the claims the library does not know
+    let mut groups = id_token
+        .to_string()
+        .split('.')
+        .nth(1)
+        .and_then(|payload| URL_SAFE_NO_PAD.decode(payload).ok())
+        .and_then(|payload| serde_json::from_slice::<serde_json::Value>(&payload).ok())
+        .and_then(|payload| read_groups(&payload, &config.groups_claim));
+
+    // some providers only put the groups in the user info
+    if groups.is_none()
+        && let Some(userinfo_url) = userinfo_url
+    {
+        let userinfo: serde_json::Value = http_client
+            .get(userinfo_url.url().clone())
+            .bearer_auth(token_response.access_token().secret())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| OidcError::UserInfo(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| OidcError::UserInfo(e.to_string()))?;
+
+        // the user info has to be about the same user as the ID token
+        if userinfo.get("sub").and_then(|sub| sub.as_str()) != Some(subject.as_str()) {
+            return Err(OidcError::UserInfo(
+                "subject does not match the ID token".to_string(),
+            ));
+        }
+
+        groups = read_groups(&userinfo, &config.groups_claim);
+    }
+
+    Ok(OidcIdentity {
+        subject,
+        username,
+        groups: groups.unwrap_or_default(),
+    })
+}
+
+/// Read a groups claim, which is usually a list but can be a single string
+fn read_groups(claims: &serde_json::Value, claim: &str) -> Option<Vec<String>> {
+    match claims.get(claim)? {
+        serde_json::Value::Array(groups) => Some(
+            groups
+                .iter()
+                .filter_map(|group| group.as_str().map(str::to_string))
+                .collect(),
+        ),
+        serde_json::Value::String(group) => Some(vec![group.clone()]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use axum::{Form, Json, Router, extract::State, http::StatusCode, routing::post};
+    use openidconnect::{
+        AccessToken, AdditionalClaims, Audience, AuthUrl, EmptyAdditionalProviderMetadata,
+        EndUserUsername, IdToken, IdTokenClaims, JsonWebKeySetUrl, ResponseTypes, StandardClaims,
+        SubjectIdentifier, TokenUrl,
+        core::{
+            CoreGenderClaim, CoreHmacKey, CoreJweContentEncryptionAlgorithm,
+            CoreJwsSigningAlgorithm, CoreResponseType, CoreSubjectIdentifierType,
+        },
+    };
+    use sha2::{Digest, Sha256};
+    use tokio::sync::Mutex;
+
+    use super::*;
+
+    const CLIENT_ID: &str = "k-librarian";
+    const CLIENT_SECRET: &str = "a-client-secret-long-enough-for-hmac";
+    const REDIRECT_URL: &str = "https://invite.example.com/api/auth/oidc/callback";
+    const CODE: &str = "stub-code";
+
+    #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+    struct GroupsClaims {
+        groups: Vec<String>,
+    }
+    impl AdditionalClaims for GroupsClaims {}
+
+    /// What the stub provider expects from the token request, known once the login started
+    #[derive(Default)]
+    struct Expected {
+        nonce: String,
+        code_challenge: String,
+    }
+
+    struct StubProvider {
+        issuer: String,
+        expected: Arc<Mutex<Expected>>,
+    }
+
+    fn test_config(issuer: &str) -> OidcConfig {
+        OidcConfig {
+            issuer: issuer.to_string(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: Some(CLIENT_SECRET.to_string()),
+            name: None,
+            scopes: vec!["profile".to_string(), "groups".to_string()],
+            groups_claim: "groups".to_string(),
+            roles: HashMap::new(),
+        }
+    }
+
+    /// A provider with only a token endpoint, signing its ID tokens with the client secret
+    async fn stub_provider() -> StubProvider {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let expected = Arc::new(Mutex::new(Expected::default()));
+
+        let router = Router::new()
+            .route("/token", post(token_endpoint))
+            .with_state((issuer.clone(), expected.clone()));
+        tokio::spawn(async move { axum::serve(listener, router).await });
+
+        StubProvider { issuer, expected }
+    }
+
+    async fn token_endpoint(
+        State((issuer, expected)): State<(String, Arc<Mutex<Expected>>)>,
+        Form(form): Form<HashMap<String, String>>,
+    ) -> (StatusCode, Json<serde_json::Value>) {
+        let expected = expected.lock().await;
+        let verifier = form.get("code_verifier").cloned().unwrap_or_default();
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+        if form.get("code").map(String::as_str) != Some(CODE)
+            || challenge != expected.code_challenge
+        {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({ "error": "invalid_grant" })),
+            );
+        }
+
+        let now = chrono::Utc::now();
+        let claims = IdTokenClaims::new(
+            IssuerUrl::new(issuer).unwrap(),
+            vec![Audience::new(CLIENT_ID.to_string())],
+            now + chrono::Duration::minutes(5),
+            now,
+            StandardClaims::new(SubjectIdentifier::new("user-1".to_string()))
+                .set_preferred_username(Some(EndUserUsername::new("alice".to_string()))),
+            GroupsClaims {
+                groups: vec!["k-librarian-owners".to_string(), "users".to_string()],
+            },
+        )
+        .set_nonce(Some(Nonce::new(expected.nonce.clone())));
+
+        let access_token = AccessToken::new("stub-access-token".to_string());
+        let id_token = IdToken::<
+            GroupsClaims,
+            CoreGenderClaim,
+            CoreJweContentEncryptionAlgorithm,
+            CoreJwsSigningAlgorithm,
+        >::new(
+            claims,
+            &CoreHmacKey::new(CLIENT_SECRET.as_bytes()),
+            CoreJwsSigningAlgorithm::HmacSha256,
+            Some(&access_token),
+            None,
+        )
+        .unwrap();
+
+        (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "access_token": access_token.secret(),
+                "token_type": "bearer",
+                "expires_in": 300,
+                "id_token": id_token.to_string(),
+            })),
+        )
+    }
+
+    fn stub_metadata(issuer: &str) -> CoreProviderMetadata {
+        CoreProviderMetadata::new(
+            IssuerUrl::new(issuer.to_string()).unwrap(),
+            AuthUrl::new(format!("{issuer}/authorize")).unwrap(),
+            JsonWebKeySetUrl::new(format!("{issuer}/jwks")).unwrap(),
+            vec![ResponseTypes::new(vec![CoreResponseType::Code])],
+            vec![CoreSubjectIdentifierType::Public],
+            vec![CoreJwsSigningAlgorithm::HmacSha256],
+            EmptyAdditionalProviderMetadata {},
+        )
+        .set_token_endpoint(Some(TokenUrl::new(format!("{issuer}/token")).unwrap()))
+    }
+
+    /// Start a login and tell the stub provider what to expect from it
+    async fn start(provider: &StubProvider) -> OidcLogin {
+        let config = test_config(&provider.issuer);
+        let login =
+            start_login_with(&config, stub_metadata(&provider.issuer), REDIRECT_URL).unwrap();
+
+        let url = reqwest::Url::parse(&login.url).unwrap();
+        let query: HashMap<_, _> = url.query_pairs().into_owned().collect();
+        assert_eq!(query["state"], login.state);
+        assert_eq!(query["nonce"], login.nonce);
+        assert_eq!(query["code_challenge_method"], "S256");
+        assert_eq!(query["redirect_uri"], REDIRECT_URL);
+
+        *provider.expected.lock().await = Expected {
+            nonce: login.nonce.clone(),
+            code_challenge: query["code_challenge"].clone(),
+        };
+
+        login
+    }
+
+    async fn finish(
+        provider: &StubProvider,
+        pkce_verifier: &str,
+        nonce: &str,
+    ) -> Result<OidcIdentity, OidcError> {
+        finish_login_with(
+            &test_config(&provider.issuer),
+            stub_metadata(&provider.issuer),
+            &http_client().unwrap(),
+            REDIRECT_URL,
+            CODE,
+            pkce_verifier,
+            nonce,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn logs_in_against_a_stub_provider() {
+        let provider = stub_provider().await;
+        let login = start(&provider).await;
+
+        let identity = finish(&provider, &login.pkce_verifier, &login.nonce)
+            .await
+            .unwrap();
+        assert_eq!(identity.subject, "user-1");
+        assert_eq!(identity.username, "alice");
+        assert_eq!(identity.groups, vec!["k-librarian-owners", "users"]);
+    }
+
+    #[tokio::test]
+    async fn refuses_an_id_token_for_another_nonce() {
+        let provider = stub_provider().await;
+        let login = start(&provider).await;
+
+        let result = finish(&provider, &login.pkce_verifier, "another-nonce").await;
+        assert!(matches!(result, Err(OidcError::InvalidIdToken(_))));
+    }
+
+    #[tokio::test]
+    async fn refuses_a_wrong_pkce_verifier() {
+        let provider = stub_provider().await;
+        let login = start(&provider).await;
+
+        let result = finish(
+            &provider,
+            "a-verifier-that-does-not-match-the-challenge-at-all",
+            &login.nonce,
+        )
+        .await;
+        assert!(matches!(result, Err(OidcError::Exchange(_))));
+    }
+
+    #[test]
+    fn reads_groups_as_a_list_or_a_single_string() {
+        let claims = serde_json::json!({
+            "groups": ["owners", 3, "inviters"],
+            "role": "owners",
+            "other": { "nested": true },
+        });
+
+        assert_eq!(
+            read_groups(&claims, "groups"),
+            Some(vec!["owners".to_string(), "inviters".to_string()])
+        );
+        assert_eq!(
+            read_groups(&claims, "role"),
+            Some(vec!["owners".to_string()])
+        );
+        assert_eq!(read_groups(&claims, "other"), None);
+        assert_eq!(read_groups(&claims, "missing"), None);
+    }
+}
@@ -0,0 +1,339 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::http::HeaderMap;
+
+use crate::config::{Config, RateLimitConfig};
+
+/// How long to wait when the client already has as many guesses in flight as it has attempts left
+const IN_FLIGHT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Marks a response as a failed guess of a credential, counted against the client
+#[derive(Clone, Copy, Debug)]
+pub struct FailedAttempt;
+
+/// Failed attempts of one client, or of everyone together
+struct Attempts {
+    failures: u32,
+    window_start: Instant,
+    last_failure: Instant,
+    /// How many lockouts in a row, each one lasts twice as long as the one before
+    lockouts: u32,
+    locked_until: Option<Instant>,
+    /// Guesses being checked right now, they might all turn out to be failures
+    in_flight: u32,
+}
+
+impl Attempts {
+    fn new(now: Instant) -> Self {
+        Self {
+            failures: 0,
+            window_start: now,
+            last_failure: now,
+            lockouts: 0,
+            locked_until: None,
+            in_flight: 0,
+        }
+    }
+
+    fn retry_after(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .map(|until| until.saturating_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Whether one more guess could go over the limit once every guess in flight has failed
+    fn is_full(&self, now: Instant, max_attempts: u32, config: &RateLimitConfig) -> bool {
+        let failures = if now.duration_since(self.window_start) > Duration::from_secs(config.window)
+        {
+            0
+        } else {
+            self.failures
+        };
+
+        failures + self.in_flight >= max_attempts
+    }
+
+    /// Count a failure, returns how long the client is locked out when it went over the limit
+    fn fail(
+        &mut self,
+        now: Instant,
+        max_attempts: u32,
+        config: &RateLimitConfig,
+    ) -> Option<Duration> {
+        // a client that stayed quiet long enough starts over with the shortest lockout
+        if now.duration_since(self.last_failure) > Duration::from_secs(config.max_lockout) {
+            self.lockouts = 0;
+        }
+        if now.duration_since(self.window_start) > Duration::from_secs(config.window) {
+            self.failures = 0;
+            self.window_start = now;
+        }
+
+        self.last_failure = now;
+        self.failures += 1;
+        if self.failures < max_attempts {
+            return None;
+        }
+
+        let lockout = config
+            .lockout
+            .saturating_mul(1 << self.lockouts.min(32))
+            .min(config.max_lockout);
+        let lockout = Duration::from_secs(lockout);
+
+        self.lockouts += 1;
+        self.failures = 0;
+        self.window_start = now;
+        self.locked_until = Some(now + lockout);
+
+        Some(lockout)
+    }
+
+    /// Nothing about this client is remembered anymore once its lockouts are forgiven
+    fn is_stale(&self, now: Instant, config: &RateLimitConfig) -> bool {
+        self.retry_after(now).is_none()
+            && self.in_flight == 0
+            && now.duration_since(self.last_failure)
+                > Duration::from_secs(config.window.max(config.max_lockout))
+    }
+}
+
+struct LimiterState {
+    clients: HashMap<IpAddr, Attempts>,
+    global: Attempts,
+}
+
+/// Keeps the failed attempts in memory, they are forgotten on restart
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(LimiterState {
+                clients: HashMap::new(),
+                global: Attempts::new(Instant::now()),
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    /// How long the client has to wait when either it or everyone is locked out
+    pub fn locked_out(&self, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        let client = state
+            .clients
+            .get(&ip)
+            .and_then(|attempts| attempts.retry_after(now));
+        client.max(state.global.retry_after(now))
+    }
+
+    /// Reserve a guess for the client, or tell how long it has to wait when either it or everyone
+    /// is locked out. Guesses in flight count against the limit, so a burst of concurrent requests
+    /// cannot get past it before the first failures are recorded.
+    pub fn reserve(&self, ip: IpAddr) -> Result<Reservation<'_>, Duration> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        let client = state
+            .clients
+            .get(&ip)
+            .and_then(|attempts| attempts.retry_after(now));
+        if let Some(retry_after) = client.max(state.global.retry_after(now)) {
+            return Err(retry_after);
+        }
+
+        let client_full = state
+            .clients
+            .get(&ip)
+            .is_some_and(|attempts| attempts.is_full(now, self.config.max_attempts, &self.config));
+        let global_full = state
+            .global
+            .is_full(now, self.config.global_max_attempts, &self.config);
+        if client_full || global_full {
+            // the guesses in flight are answered quickly, one of them might lock the client out
+            return Err(IN_FLIGHT_RETRY_AFTER);
+        }
+
+        state
+            .clients
+            .entry(ip)
+            .or_insert_with(|| Attempts::new(now))
+            .in_flight += 1;
+        state.global.in_flight += 1;
+
+        Ok(Reservation {
+            limiter: self,
+            ip,
+            failed: false,
+        })
+    }
+
+    fn release(&self, ip: IpAddr, failed: bool) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(client) = state.clients.get_mut(&ip) {
+            client.in_flight = client.in_flight.saturating_sub(1);
+        }
+        state.global.in_flight = state.global.in_flight.saturating_sub(1);
+
+        state
+            .clients
+            .retain(|_, attempts| !attempts.is_stale(now, &self.config));
+
+        if !failed {
+            return;
+        }
+
+        let client = state
+            .clients
+            .entry(ip)
+            .or_insert_with(|| Attempts::new(now));
+        if let Some(lockout) = client.fail(now, self.config.max_attempts, &self.config) {
+            tracing::warn!(
+                "Locked out {} for {}s after {} failed attempts",
+                ip,
+                lockout.as_secs(),
+                self.config.max_attempts
+            );
+        }
+
+        if let Some(lockout) = state
+            .global
+            .fail(now, self.config.global_max_attempts, &self.config)
+        {
+            tracing::warn!(
+                "Refusing all attempts for {}s after {} failed attempts from everyone",
+                lockout.as_secs(),
+                self.config.global_max_attempts
+            );
+        }
+    }
+}
+
+/// A guess that is being checked, released when dropped
+pub struct Reservation<'a> {
+    limiter: &'a RateLimiter,
+    ip: IpAddr,
+    failed: bool,
+}
+
+impl Reservation<'_> {
+    /// The guess was wrong, count it against the client
+    pub fn fail(mut self) {
+        self.failed = true;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip, self.failed);
+    }
+}
+
+/// The address of the client, taken from `X-Forwarded-For` when the request comes from a
+/// trusted proxy. Every proxy appends the address it got the request from, so the header is read
+/// from the end until an address that is not one of our proxies.
+pub fn client_ip(config: &Config, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let peer = peer.to_canonical();
+    if !config.is_trusted_proxy(peer) {
+        return peer;
+    }
+
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+
+    let mut client = peer;
+    for entry in forwarded.into_iter().rev() {
+        // anything we cannot read was written by the client, so stop at the last good address
+        let Ok(ip) = entry.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = ip.to_canonical();
+        if !config.is_trusted_proxy(client) {
+            break;
+        }
+    }
+
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_attempts: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitConfig {
+            max_attempts,
+            ..RateLimitConfig::default()
+        })
+    }
+
+    #[test]
+    fn guesses_in_flight_count_against_the_limit() {
+        let limiter = limiter(3);
+        let ip = IpAddr::from([192, 0, 2, 1]);
+
+        let in_flight: Vec<_> = (0..3).map(|_| limiter.reserve(ip).unwrap()).collect();
+        assert_eq!(limiter.reserve(ip).err(), Some(IN_FLIGHT_RETRY_AFTER));
+
+        // another client is not held back
+        drop(limiter.reserve(IpAddr::from([192, 0, 2, 2])).unwrap());
+
+        for reservation in in_flight {
+            reservation.fail();
+        }
+        let retry_after = limiter.reserve(ip).err().unwrap();
+        assert!(retry_after > IN_FLIGHT_RETRY_AFTER);
+    }
+
+    #[test]
+    fn successful_guesses_give_their_attempt_back() {
+        let limiter = limiter(2);
+        let ip = IpAddr::from([192, 0, 2, 1]);
+
+        limiter.reserve(ip).unwrap().fail();
+        for _ in 0..10 {
+            drop(limiter.reserve(ip).unwrap());
+        }
+        limiter.reserve(ip).unwrap().fail();
+        assert!(limiter.reserve(ip).is_err());
+    }
+
+    #[test]
+    fn forgets_quiet_clients_after_a_success() {
+        let limiter = RateLimiter::new(RateLimitConfig {
+            window: 0,
+            max_lockout: 0,
+            ..RateLimitConfig::default()
+        });
+
+        limiter
+            .reserve(IpAddr::from([192, 0, 2, 1]))
+            .unwrap()
+            .fail();
+        std::thread::sleep(Duration::from_millis(5));
+        drop(limiter.reserve(IpAddr::from([192, 0, 2, 2])).unwrap());
+
+        let state = limiter.state.lock().unwrap();
+        assert!(!state.clients.contains_key(&IpAddr::from([192, 0, 2, 1])));
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    AppState,
+    database::{KomgaProfileOption, LocalDatabaseError, ProvisionedUser, UserDrift},
+    komga::{KomgaError, KomgaUser, KomgaUserCreateOptionSharedLibraries},
+    navidrome::{NavidromeError, NavidromeUser},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReconcileError {
+    #[error("failed to communicate with the database: {0}")]
+    Database(#[from] LocalDatabaseError),
+    #[error("failed to fetch users from Komga: {0}")]
+    Komga(#[from] KomgaError),
+    #[error("failed to fetch users from Navidrome: {0}")]
+    Navidrome(#[from] NavidromeError),
+}
+
+/// The result of a reconciliation run
+#[derive(serde::Serialize, Default)]
+pub struct ReconcileSummary {
+    pub checked: usize,
+    pub drifted: usize,
+    pub failed: usize,
+    /// Why whole servers could not be checked, their users are counted as failed
+    pub errors: Vec<String>,
+}
+
+/// The result of importing upstream users
+#[derive(serde::Serialize, Default)]
+pub struct ImportSummary {
+    pub imported: u64,
+    pub skipped: u64,
+}
+
+#[derive(serde::Deserialize)]
+struct NavidromeRestrictions {
+    #[serde(rename = "isAdmin")]
+    is_admin: bool,
+    #[serde(rename = "libraryIds")]
+    library_ids: Vec<u64>,
+}
+
+fn as_set<T: Clone + Eq + std::hash::Hash>(values: &[T]) -> HashSet<T> {
+    values.iter().cloned().collect()
+}
+
+fn diff_komga_user(expected: &KomgaProfileOption, actual: &KomgaUser) -> Vec<UserDrift> {
+    let mut drift = vec![];
+
+    if let Some(roles) = &expected.roles
+        && as_set(roles) != as_set(&actual.roles)
+    {
+        drift.push(UserDrift::new("roles", roles, &actual.roles));
+    }
+
+    if let Some(shared) = &expected.shared_libraries {
+        if shared.all != actual.shared_all_libraries {
+            drift.push(UserDrift::new(
+                "sharedLibraries.all",
+                shared.all,
+                actual.shared_all_libraries,
+            ));
+        }
+        if !shared.all && as_set(&shared.library_ids) != as_set(&actual.shared_libraries_ids) {
+            drift.push(UserDrift::new(
+                "sharedLibraries.libraryIds",
+                &shared.library_ids,
+                &actual.shared_libraries_ids,
+            ));
+        }
+    }
+
+    if let Some(labels) = &expected.labels_allow
+        && as_set(labels) != as_set(&actual.labels_allow)
+    {
+        drift.push(UserDrift::new("labelsAllow", labels, &actual.labels_allow));
+    }
+
+    if let Some(labels) = &expected.labels_exclude
+        && as_set(labels) != as_set(&actual.labels_exclude)
+    {
+        drift.push(UserDrift::new(
+            "labelsExclude",
+            labels,
+            &actual.labels_exclude,
+        ));
+    }
+
+    drift
+}
+
+fn diff_navidrome_user(expected: &NavidromeRestrictions, actual: &NavidromeUser) -> Vec<UserDrift> {
+    let mut drift = vec![];
+
+    if expected.is_admin != actual.is_admin {
+        drift.push(UserDrift::new(
+            "isAdmin",
+            expected.is_admin,
+            actual.is_admin,
+        ));
+    }
+
+    // admins always see every library, and older servers do not report libraries at all
+    if !expected.is_admin
+        && !expected.library_ids.is_empty()
+        && let Some(libraries) = &actual.libraries
+    {
+        let actual_ids: Vec<u64> = libraries.iter().map(|library| library.id).collect();
+        if as_set(&expected.library_ids) != as_set(&actual_ids) {
+            drift.push(UserDrift::new(
+                "libraryIds",
+                &expected.library_ids,
+                actual_ids,
+            ));
+        }
+    }
+
+    drift
+}
+
+async fn check_navidrome_user(
+    state: &AppState,
+    user: &ProvisionedUser,
+) -> Result<Vec<UserDrift>, NavidromeError> {
+    let Some(navidrome) = &state.navidrome else {
+        return Ok(vec![]);
+    };
+
+    let mut client = navidrome.lock().await;
+    let drift = match client.get_user(&user.id).await? {
+        None => vec![UserDrift::deleted()],
+        Some(actual) => {
+            match user
+                .restrictions
+                .clone()
+                .map(serde_json::from_value::<NavidromeRestrictions>)
+            {
+                Some(Ok(expected)) => diff_navidrome_user(&expected, &actual),
+                _ => vec![],
+            }
+        }
+    };
+
+    Ok(drift)
+}
+
+/// Compare every registered user against the upstream server and store the drift found
+pub async fn reconcile_users(state: &AppState) -> Result<ReconcileSummary, ReconcileError> {
+    let users = state.db.get_active_users().await?;
+
+    let mut summary = ReconcileSummary::default();
+
+    // Komga does not have a single user endpoint, so fetch everyone once. When it cannot be
+    // reached the Navidrome users are still checked.
+    let komga_users: Option<HashMap<String, KomgaUser>> = if users.iter().any(|u| u.kind == "komga")
+    {
+        match state.komga.list_users().await {
+            Ok(komga_users) => Some(
+                komga_users
+                    .into_iter()
+                    .map(|user| (user.id.clone(), user))
+                    .collect(),
+            ),
+            Err(e) => {
+                tracing::error!("Failed to fetch users from Komga: {}", e);
+                summary.errors.push(ReconcileError::from(e).to_string());
+                None
+            }
+        }
+    } else {
+        Some(HashMap::new())
+    };
+
+    let mut navidrome_failed = false;
+    for user in users {
+        let drift = match user.kind.as_str() {
+            "komga" => {
+                let Some(komga_users) = &komga_users else {
+                    summary.failed += 1;
+                    continue;
+                };
+
+                match komga_users.get(&user.id) {
+                    None => vec![UserDrift::deleted()],
+                    Some(actual) => match user
+                        .restrictions
+                        .clone()
+                        .map(serde_json::from_value::<KomgaProfileOption>)
+                    {
+                        Some(Ok(expected)) => diff_komga_user(&expected, actual),
+                        _ => vec![],
+                    },
+                }
+            }
+            "navidrome" => match check_navidrome_user(state, &user).await {
+                Ok(drift) => drift,
+                Err(e) => {
+                    tracing::error!("[navidrome / {}] Failed to check user: {}", user.id, e);
+                    summary.failed += 1;
+                    // the first error tells enough, the others are in the log
+                    if !navidrome_failed {
+                        navidrome_failed = true;
+                        summary.errors.push(ReconcileError::from(e).to_string());
+                    }
+                    continue;
+                }
+            },
+            _ => continue,
+        };
+
+        if !drift.is_empty() {
+            tracing::warn!(
+                "[{} / {}] User has drifted from its granted restrictions",
+                user.kind,
+                user.id
+            );
+            summary.drifted += 1;
+        }
+
+        state
+            .db
+            .set_user_drift(&user.kind, &user.id, &drift)
+            .await?;
+        summary.checked += 1;
+    }
+
+    Ok(summary)
+}
+
+/// Record every upstream user that k-librarian does not know about yet, with their current restrictions
+pub async fn import_users(state: &AppState) -> Result<ImportSummary, ReconcileError> {
+    let mut users = vec![];
+
+    for user in state.komga.list_users().await? {
+        // never manage the account k-librarian itself is using
+        if user.email == state.config.komga.username {
+            continue;
+        }
+
+        let restrictions = serde_json::to_value(KomgaProfileOption {
+            labels_allow: Some(user.labels_allow),
+            labels_exclude: Some(user.labels_exclude),
+            shared_libraries: Some(KomgaUserCreateOptionSharedLibraries {
+                all: user.shared_all_libraries,
+                library_ids: user.shared_libraries_ids,
+            }),
+            roles: Some(user.roles),
+        })
+        .unwrap();
+
+        users.push(ProvisionedUser::imported(
+            "komga",
+            &user.id,
+            &user.email,
+            restrictions,
+        ));
+    }
+
+    if let (Some(navidrome), Some(config)) = (&state.navidrome, &state.config.navidrome) {
+        let mut client = navidrome.lock().await;
+        for user in client.list_users().await? {
+            if user.username == config.username {
+                continue;
+            }
+
+            let library_ids: Vec<u64> = user
+                .libraries
+                .as_ref()
+                .map(|libraries| libraries.iter().map(|library| library.id).collect())
+                .unwrap_or_default();
+            let restrictions = serde_json::json!({
+                "isAdmin": user.is_admin,
+                "libraryIds": library_ids,
+            });
+
+            users.push(ProvisionedUser::imported(
+                "navidrome",
+                &user.id,
+                &user.username,
+                restrictions,
+            ));
+        }
+    }
+
+    let imported = state.db.import_users(&users).await?;
+    tracing::info!(
+        "Imported {} users, {} were already known",
+        imported,
+        users.len() as u64 - imported
+    );
+
+    Ok(ImportSummary {
+        imported,
+        skipped: users.len() as u64 - imported,
+    })
+}
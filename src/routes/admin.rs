@@ -0,0 +1,388 @@
+use axum::{
+    Extension, Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use garde::Validate;
+use tracing::{error, info};
+
+use crate::{
+    AppState,
+    auth::{generate_api_key, hash_password, hash_token},
+    database::{Admin, AdminRole, ApiKey, ApiKeyScope, unix_now},
+    routes::middleware::{AdminIdentity, auth_middleware},
+};
+
+#[derive(serde::Deserialize, garde::Validate)]
+pub struct AdminCreateParams {
+    #[garde(length(min = 3, max = 32), custom(validate_admin_username))]
+    username: String,
+    #[garde(length(min = 8))]
+    password: String,
+    #[garde(skip)]
+    role: AdminRole,
+}
+
+#[derive(serde::Deserialize, garde::Validate)]
+pub struct AdminUpdateParams {
+    #[garde(inner(length(min = 8)))]
+    password: Option<String>,
+    #[garde(skip)]
+    role: Option<AdminRole>,
+    /// Turn off two-factor authentication, for an admin who lost their device
+    #[serde(rename = "resetTotp", default)]
+    #[garde(skip)]
+    reset_totp: bool,
+}
+
+#[derive(serde::Deserialize, garde::Validate)]
+pub struct ApiKeyCreateParams {
+    #[garde(length(min = 1, max = 64))]
+    name: String,
+    #[garde(length(min = 1))]
+    scopes: Vec<ApiKeyScope>,
+    #[serde(rename = "expiresAt")]
+    #[garde(skip)]
+    expires_at: Option<u64>,
+}
+
+fn validate_admin_username(value: &str, _: &()) -> garde::Result {
+    // only check alphanumeric, dash, dot, and underscore
+    if value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        Ok(())
+    } else {
+        Err(garde::Error::new(
+            "Username can only contain alphanumeric characters, dashes, dots, and underscores",
+        ))
+    }
+}
+
+fn error_response(status: StatusCode, error: String) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        status,
+        Json(serde_json::json!({
+            "ok": false,
+            "error": error
+        })),
+    )
+}
+
+fn validation_error(e: garde::Report) -> (StatusCode, Json<serde_json::Value>) {
+    let mut format_err = String::new();
+    for (field, err) in e.iter() {
+        format_err.push_str(&format!("- {field}: {err}"));
+        format_err.push('\n');
+    }
+
+    error_response(
+        StatusCode::BAD_REQUEST,
+        format!("Invalid request:\n{}", format_err),
+    )
+}
+
+async fn get_all_admins(State(state): State<AppState>) -> impl IntoResponse {
+    match state.db.get_all_admins().await {
+        Ok(admins) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": admins
+            })),
+        ),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to get admins: {}", e),
+        ),
+    }
+}
+
+async fn create_admin(
+    State(state): State<AppState>,
+    Json(params): Json<AdminCreateParams>,
+) -> impl IntoResponse {
+    if let Err(e) = params.validate() {
+        return validation_error(e);
+    }
+
+    match state.db.get_admin_by_username(&params.username).await {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            return error_response(
+                StatusCode::CONFLICT,
+                format!("Admin already exists: {}", params.username),
+            );
+        }
+        Err(e) => {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to get admin: {}", e),
+            );
+        }
+    }
+
+    let password_hash = match hash_password(&params.password) {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("Failed to hash password: {}", e);
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to hash password".to_string(),
+            );
+        }
+    };
+
+    let admin = Admin {
+        id: uuid::Uuid::new_v4(),
+        username: params.username,
+        role: params.role,
+        password_hash,
+        created_at: Some(crate::database::unix_now()),
+        totp_secret: None,
+        totp_enabled: false,
+        totp_last_step: None,
+        recovery_codes: Vec::new(),
+        oidc_subject: None,
+    };
+
+    match state.db.add_admin(&admin).await {
+        Ok(_) => {
+            info!(
+                "Created admin {} as {}",
+                admin.username,
+                admin.role.as_str()
+            );
+            (
+                StatusCode::OK,
+                Json(serde_json::json!({
+                    "ok": true,
+                    "data": admin
+                })),
+            )
+        }
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to create admin: {}", e),
+        ),
+    }
+}
+
+async fn update_admin(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path(id): Path<uuid::Uuid>,
+    Json(params): Json<AdminUpdateParams>,
+) -> impl IntoResponse {
+    if let Err(e) = params.validate() {
+        return validation_error(e);
+    }
+
+    // an owner demoting themselves could leave nobody able to manage the admins
+    if identity.id == Some(id) && params.role.is_some_and(|role| role != AdminRole::Owner) {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "You cannot change your own role".to_string(),
+        );
+    }
+
+    let mut admin = match state.db.get_admin(id).await {
+        Ok(Some(admin)) => admin,
+        Ok(None) => {
+            return error_response(StatusCode::NOT_FOUND, format!("Admin not found: {}", id));
+        }
+        Err(e) => {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to get admin: {}", e),
+            );
+        }
+    };
+
+    let password_hash = match params.password.as_deref().map(hash_password).transpose() {
+        Ok(hash) => hash,
+        Err(e) => {
+            error!("Failed to hash password: {}", e);
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to hash password".to_string(),
+            );
+        }
+    };
+
+    if let Err(e) = state
+        .db
+        .update_admin(id, password_hash.as_deref(), params.role)
+        .await
+    {
+        return error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to update admin: {}", e),
+        );
+    }
+
+    if params.reset_totp {
+        admin.totp_secret = None;
+        admin.totp_enabled = false;
+        admin.totp_last_step = None;
+        admin.recovery_codes.clear();
+        if let Err(e) = state.db.update_admin_totp(&admin).await {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to reset two-factor authentication: {}", e),
+            );
+        }
+        info!("Reset two-factor authentication of {}", admin.username);
+    }
+
+    // a new password or a lower role should apply right away
+    if let Err(e) = state.db.delete_admin_sessions(id).await {
+        error!("Failed to end the sessions of admin {}: {}", id, e);
+    }
+
+    match state.db.get_admin(id).await {
+        Ok(Some(admin)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": admin
+            })),
+        ),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, format!("Admin not found: {}", id)),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to get admin: {}", e),
+        ),
+    }
+}
+
+async fn delete_admin(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    if identity.id == Some(id) {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "You cannot delete your own account".to_string(),
+        );
+    }
+
+    match state.db.delete_admin(id).await {
+        Ok(_) => {
+            info!("Deleted admin {}", id);
+            (StatusCode::OK, Json(serde_json::json!({ "ok": true })))
+        }
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to delete admin: {}", e),
+        ),
+    }
+}
+
+async fn get_all_api_keys(State(state): State<AppState>) -> impl IntoResponse {
+    match state.db.get_all_api_keys().await {
+        Ok(keys) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": keys
+            })),
+        ),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to get API keys: {}", e),
+        ),
+    }
+}
+
+async fn create_api_key(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Json(params): Json<ApiKeyCreateParams>,
+) -> impl IntoResponse {
+    if let Err(e) = params.validate() {
+        return validation_error(e);
+    }
+
+    if params.expires_at.is_some_and(|at| at <= unix_now()) {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "The expiry date must be in the future".to_string(),
+        );
+    }
+
+    let mut scopes: Vec<ApiKeyScope> = Vec::new();
+    for scope in params.scopes {
+        if !scopes.contains(&scope) {
+            scopes.push(scope);
+        }
+    }
+
+    let key = generate_api_key();
+    let api_key = ApiKey {
+        id: uuid::Uuid::new_v4(),
+        name: params.name,
+        scopes,
+        key_hash: hash_token(&key),
+        created_by: identity.username,
+        created_at: unix_now(),
+        expires_at: params.expires_at,
+        last_used_at: None,
+    };
+
+    match state.db.add_api_key(&api_key).await {
+        Ok(_) => {
+            info!("Created API key {}", api_key.name);
+            // the key itself is only shown this once
+            let mut data = serde_json::to_value(&api_key).unwrap_or_default();
+            data["key"] = serde_json::Value::String(key);
+            (
+                StatusCode::OK,
+                Json(serde_json::json!({
+                    "ok": true,
+                    "data": data
+                })),
+            )
+        }
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to create API key: {}", e),
+        ),
+    }
+}
+
+async fn delete_api_key(
+    State(state): State<AppState>,
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    match state.db.delete_api_key(id).await {
+        Ok(true) => {
+            info!("Revoked API key {}", id);
+            (StatusCode::OK, Json(serde_json::json!({ "ok": true })))
+        }
+        Ok(false) => error_response(StatusCode::NOT_FOUND, format!("API key not found: {}", id)),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to revoke API key: {}", e),
+        ),
+    }
+}
+
+pub fn admin_routes(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/", axum::routing::get(get_all_admins).post(create_admin))
+        .route(
+            "/{id}",
+            axum::routing::patch(update_admin).delete(delete_admin),
+        )
+        .route(
+            "/keys",
+            axum::routing::get(get_all_api_keys).post(create_api_key),
+        )
+        .route("/keys/{id}", axum::routing::delete(delete_api_key))
+        .with_state(state.clone())
+        .layer(axum::middleware::from_fn_with_state(state, auth_middleware))
+}
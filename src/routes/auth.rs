@@ -1,43 +1,1509 @@
-use axum::{Json, Router, extract::State, http::StatusCode, response::IntoResponse};
+use std::net::SocketAddr;
 
-use crate::AppState;
+use axum::{
+    Extension, Json, Router,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{
+        HeaderMap, StatusCode,
+        header::{LOCATION, SET_COOKIE},
+    },
+    response::{AppendHeaders, IntoResponse, Response},
+};
 
+use crate::{
+    AppState,
+    auth::{
+        OIDC_STATE_COOKIE, SESSION_DURATION, cookie_value, find_recovery_code, generate_csrf_token,
+        generate_recovery_codes, generate_session_token, generate_totp_secret, hash_token,
+        oidc_state_cookie, role_for_groups, session_cookie, totp, totp_qr_code,
+        verify_missing_password, verify_password, verify_shared_token, verify_shared_token_totp,
+        verify_totp,
+    },
+    database::{Admin, AdminRole, LocalDatabaseError, Passkey, Session, unix_now},
+    oidc::{OidcIdentity, finish_login, start_login},
+    ratelimit::FailedAttempt,
+    routes::middleware::{AdminIdentity, ProxyLogin, auth_middleware},
+    webauthn::{
+        AttestedCredential, AuthenticatorData, ES256, WebauthnError,
+        attestation_authenticator_data, decode, encode, generate_challenge, verify_client_data,
+        verify_sign_count, verify_signature,
+    },
+};
+
+/// How long a passkey ceremony can take, in seconds
+const PASSKEY_CHALLENGE_TIMEOUT: u64 = 5 * 60;
+/// How long a login can stay at the OpenID Connect provider, in seconds
+const OIDC_LOGIN_TIMEOUT: u64 = 10 * 60;
+
+#[derive(serde::Deserialize)]
+pub struct PasskeyRegisterForm {
+    #[serde(rename = "challengeId")]
+    challenge_id: uuid::Uuid,
+    name: Option<String>,
+    credential: PasskeyCredential<PasskeyAttestationResponse>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasskeyLoginForm {
+    #[serde(rename = "challengeId")]
+    challenge_id: uuid::Uuid,
+    credential: PasskeyCredential<PasskeyAssertionResponse>,
+}
+
+/// A `PublicKeyCredential` from the browser, with every buffer base64url encoded
+#[derive(serde::Deserialize)]
+pub struct PasskeyCredential<R> {
+    id: String,
+    response: R,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasskeyAttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    attestation_object: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct PasskeyAssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    authenticator_data: String,
+    signature: String,
+    #[serde(rename = "userHandle")]
+    user_handle: Option<String>,
+}
+
+/// What the OpenID Connect provider sends back to the callback
+#[derive(serde::Deserialize)]
+pub struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Either the shared token, or the username and password of an admin account
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct LoginForm {
-    token: String,
+    token: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    /// A TOTP or recovery code, once two-factor authentication is enabled
+    code: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+pub struct TotpCodeForm {
+    code: String,
 }
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct LoginResponse {
     ok: bool,
     error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<LoginSession>,
+    /// The password was right, but a TOTP code is needed as well
+    #[serde(
+        rename = "totpRequired",
+        default,
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    totp_required: bool,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct LoginSession {
+    username: Option<String>,
+    role: AdminRole,
+    /// Has to be sent in the `X-CSRF-Token` header for every change, the session itself is a cookie
+    #[serde(rename = "csrfToken")]
+    csrf_token: String,
+    #[serde(rename = "expiresAt")]
+    expires_at: u64,
+}
+
+fn login_error(status: StatusCode, error: &str) -> Response {
+    (
+        status,
+        Json(LoginResponse {
+            ok: false,
+            error: Some(error.to_string()),
+            data: None,
+            totp_required: false,
+        }),
+    )
+        .into_response()
+}
+
+/// A wrong credential, which counts towards the rate limit of the client
+fn failed_login(error: &str) -> Response {
+    let mut response = login_error(StatusCode::UNAUTHORIZED, error);
+    response.extensions_mut().insert(FailedAttempt);
+    response
+}
+
+/// Only mark the cookie as secure when we know the panel is served over HTTPS
+fn secure_cookies(state: &AppState) -> bool {
+    state
+        .config
+        .public_url
+        .as_deref()
+        .is_some_and(|url| url.starts_with("https://"))
+}
+
+/// Store a new session, returns the cookie to hand to the browser with the CSRF token
+/// and the expiry of the session
+async fn create_session(
+    state: &AppState,
+    admin_id: Option<uuid::Uuid>,
+) -> Result<(String, String, u64), LocalDatabaseError> {
+    let token = generate_session_token();
+    let csrf_token = generate_csrf_token();
+    let expires_at = unix_now() + SESSION_DURATION;
+
+    state
+        .db
+        .add_session(&hash_token(&token), admin_id, &csrf_token, expires_at)
+        .await?;
+
+    Ok((
+        session_cookie(&token, SESSION_DURATION, secure_cookies(state)),
+        csrf_token,
+        expires_at,
+    ))
+}
+
+/// Start a new session and hand it to the browser as a cookie
+async fn start_session(
+    state: &AppState,
+    admin_id: Option<uuid::Uuid>,
+    username: Option<String>,
+    role: AdminRole,
+) -> Response {
+    let (cookie, csrf_token, expires_at) = match create_session(state, admin_id).await {
+        Ok(session) => session,
+        Err(e) => {
+            tracing::error!("Failed to create session: {}", e);
+            return login_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to log in");
+        }
+    };
+
+    (
+        StatusCode::OK,
+        [(SET_COOKIE, cookie)],
+        Json(LoginResponse {
+            ok: true,
+            error: None,
+            data: Some(LoginSession {
+                username,
+                role,
+                csrf_token,
+                expires_at,
+            }),
+            totp_required: false,
+        }),
+    )
+        .into_response()
+}
+
+/// The credentials were right, but a TOTP code is needed as well
+fn totp_required() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        Json(LoginResponse {
+            ok: false,
+            error: Some("A two-factor code is required".to_string()),
+            data: None,
+            totp_required: true,
+        }),
+    )
+        .into_response()
+}
+
+async fn auth_login(State(state): State<AppState>, Json(payload): Json<LoginForm>) -> Response {
+    let code = payload.code.filter(|code| !code.trim().is_empty());
+
+    if let Some(token) = payload.token {
+        if !verify_shared_token(&token, &state.config.token) {
+            return failed_login("Invalid token");
+        }
+
+        if let Some(secret) = &state.config.token_totp_secret {
+            let Some(code) = code else {
+                return totp_required();
+            };
+
+            match verify_shared_token_totp(secret, &code) {
+                Ok(true) => {}
+                Ok(false) => return failed_login("Invalid two-factor code"),
+                Err(e) => {
+                    tracing::error!("Failed to read TOTP of the shared token: {}", e);
+                    return login_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to check code");
+                }
+            }
+        }
+
+        tracing::info!("Logged in with the shared token");
+        return start_session(&state, None, None, AdminRole::Owner).await;
+    }
+
+    let (Some(username), Some(password)) = (payload.username, payload.password) else {
+        return login_error(
+            StatusCode::BAD_REQUEST,
+            "Either a token or a username and password is required",
+        );
+    };
+
+    let mut admin = match state.db.get_admin_by_username(&username).await {
+        // admins from the OpenID Connect provider have no password
+        Ok(Some(admin))
+            if !admin.password_hash.is_empty()
+                && verify_password(&password, &admin.password_hash) =>
+        {
+            admin
+        }
+        Ok(admin) => {
+            if admin.is_none_or(|admin| admin.password_hash.is_empty()) {
+                verify_missing_password(&password);
+            }
+            return failed_login("Invalid username or password");
+        }
+        Err(e) => {
+            tracing::error!("Failed to get admin {}: {}", username, e);
+            return login_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to log in");
+        }
+    };
+
+    if admin.totp_enabled {
+        let Some(code) = code else {
+            return totp_required();
+        };
+
+        if let Err(response) = check_second_factor(&state, &mut admin, &code).await {
+            return response;
+        }
+    }
+
+    tracing::info!("Admin {} logged in", admin.username);
+    start_session(&state, Some(admin.id), Some(admin.username), admin.role).await
+}
+
+/// Accept either a TOTP code or one of the recovery codes, which is used up
+async fn check_second_factor(
+    state: &AppState,
+    admin: &mut Admin,
+    code: &str,
+) -> Result<(), Response> {
+    let totp = admin
+        .totp_secret
+        .as_deref()
+        .map(|secret| totp(secret, &admin.username))
+        .transpose()
+        .map_err(|e| {
+            tracing::error!("Failed to read TOTP of {}: {}", admin.username, e);
+            login_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to check code")
+        })?;
+
+    // the code is only used up when no other login got to it first
+    let claimed =
+        if let Some(step) = totp.and_then(|totp| verify_totp(&totp, code, admin.totp_last_step)) {
+            admin.totp_last_step = Some(step);
+            state.db.claim_totp_step(admin.id, step).await
+        } else if let Some(position) = find_recovery_code(&admin.recovery_codes, code) {
+            let current = admin.recovery_codes.clone();
+            admin.recovery_codes.remove(position);
+            let replaced = state
+                .db
+                .replace_recovery_codes(admin.id, &current, &admin.recovery_codes)
+                .await;
+            if matches!(replaced, Ok(true)) {
+                tracing::warn!(
+                    "Admin {} used a recovery code, {} left",
+                    admin.username,
+                    admin.recovery_codes.len()
+                );
+            }
+            replaced
+        } else {
+            return Err(failed_login("Invalid two-factor code"));
+        };
+
+    match claimed {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(failed_login("Invalid two-factor code")),
+        Err(e) => {
+            tracing::error!("Failed to update TOTP of {}: {}", admin.username, e);
+            Err(login_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to check code",
+            ))
+        }
+    }
+}
+
+/// The account of the logged in admin, the shared token has none
+async fn current_admin(state: &AppState, identity: &AdminIdentity) -> Result<Admin, Response> {
+    let Some(id) = identity.id else {
+        return Err(login_error(
+            StatusCode::BAD_REQUEST,
+            "Two-factor authentication needs an admin account, not the shared token",
+        ));
+    };
+
+    match state.db.get_admin(id).await {
+        Ok(Some(admin)) => Ok(admin),
+        Ok(None) => Err(login_error(StatusCode::NOT_FOUND, "Admin not found")),
+        Err(e) => {
+            tracing::error!("Failed to get admin {}: {}", id, e);
+            Err(login_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to get admin",
+            ))
+        }
+    }
 }
 
-async fn auth_login(
+/// Start the enrollment with a new secret, only enabled once a code is confirmed
+async fn totp_setup(
     State(state): State<AppState>,
-    Json(payload): Json<LoginForm>,
-) -> impl IntoResponse {
-    if state.config.token == payload.token {
-        (
+    Extension(identity): Extension<AdminIdentity>,
+) -> Response {
+    let mut admin = match current_admin(&state, &identity).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+
+    if admin.totp_enabled {
+        return login_error(
+            StatusCode::CONFLICT,
+            "Two-factor authentication is already enabled",
+        );
+    }
+
+    let secret = generate_totp_secret();
+    let (uri, qr_code) = match totp(&secret, &admin.username)
+        .and_then(|totp| Ok((totp.get_url(), totp_qr_code(&totp)?)))
+    {
+        Ok(result) => result,
+        Err(e) => {
+            tracing::error!("Failed to create TOTP for {}: {}", admin.username, e);
+            return login_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to create two-factor secret",
+            );
+        }
+    };
+
+    admin.totp_secret = Some(secret.clone());
+    admin.totp_last_step = None;
+    admin.recovery_codes.clear();
+    if let Err(e) = state.db.update_admin_totp(&admin).await {
+        tracing::error!("Failed to store TOTP of {}: {}", admin.username, e);
+        return login_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to create two-factor secret",
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "data": {
+                "secret": secret,
+                "uri": uri,
+                "qrCode": qr_code,
+            }
+        })),
+    )
+        .into_response()
+}
+
+async fn totp_enable(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Json(form): Json<TotpCodeForm>,
+) -> Response {
+    let mut admin = match current_admin(&state, &identity).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+
+    if admin.totp_enabled {
+        return login_error(
+            StatusCode::CONFLICT,
+            "Two-factor authentication is already enabled",
+        );
+    }
+
+    let Some(totp) = admin
+        .totp_secret
+        .as_deref()
+        .and_then(|secret| totp(secret, &admin.username).ok())
+    else {
+        return login_error(StatusCode::BAD_REQUEST, "Start the two-factor setup first");
+    };
+
+    let Some(step) = verify_totp(&totp, &form.code, None) else {
+        return login_error(StatusCode::BAD_REQUEST, "Invalid two-factor code");
+    };
+
+    let (recovery_codes, hashes) = generate_recovery_codes();
+    admin.totp_enabled = true;
+    admin.totp_last_step = Some(step);
+    admin.recovery_codes = hashes;
+    if let Err(e) = state.db.update_admin_totp(&admin).await {
+        tracing::error!("Failed to enable TOTP of {}: {}", admin.username, e);
+        return login_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to enable two-factor authentication",
+        );
+    }
+
+    tracing::info!("Admin {} enabled two-factor authentication", admin.username);
+    // the recovery codes are only shown this once
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "data": {
+                "recoveryCodes": recovery_codes,
+            }
+        })),
+    )
+        .into_response()
+}
+
+async fn totp_disable(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Json(form): Json<TotpCodeForm>,
+) -> Response {
+    let mut admin = match current_admin(&state, &identity).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+
+    if !admin.totp_enabled {
+        return login_error(
+            StatusCode::BAD_REQUEST,
+            "Two-factor authentication is not enabled",
+        );
+    }
+
+    if let Err(response) = check_second_factor(&state, &mut admin, &form.code).await {
+        return response;
+    }
+
+    admin.totp_secret = None;
+    admin.totp_enabled = false;
+    admin.totp_last_step = None;
+    admin.recovery_codes.clear();
+    if let Err(e) = state.db.update_admin_totp(&admin).await {
+        tracing::error!("Failed to disable TOTP of {}: {}", admin.username, e);
+        return login_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to disable two-factor authentication",
+        );
+    }
+
+    tracing::info!(
+        "Admin {} disabled two-factor authentication",
+        admin.username
+    );
+    (StatusCode::OK, Json(serde_json::json!({ "ok": true }))).into_response()
+}
+
+/// The origin the admin panel is served from and the relying party ID derived from it.
+/// Only the configured public URL is used, the request headers are up to the client.
+fn relying_party(state: &AppState) -> Option<(String, String)> {
+    let origin = state.config.public_url.as_deref()?.trim_end_matches('/');
+    let rp_id = reqwest::Url::parse(origin).ok()?.host_str()?.to_string();
+
+    Some((origin.to_string(), rp_id))
+}
+
+fn passkeys_unavailable() -> Response {
+    login_error(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Passkeys need the public-url of the panel to be set in the config",
+    )
+}
+
+fn passkey_error(e: WebauthnError) -> Response {
+    tracing::warn!("Rejected passkey: {}", e);
+    login_error(StatusCode::BAD_REQUEST, &format!("Invalid passkey: {}", e))
+}
+
+/// Check a new credential, returns it with its signature counter
+fn verify_registration(
+    response: &PasskeyAttestationResponse,
+    challenge: &str,
+    origin: &str,
+    rp_id: &str,
+) -> Result<(AttestedCredential, u32), WebauthnError> {
+    let client_data = decode(&response.client_data_json)?;
+    verify_client_data(&client_data, "webauthn.create", challenge, origin)?;
+
+    let auth_data = attestation_authenticator_data(&decode(&response.attestation_object)?)?;
+    let auth_data = AuthenticatorData::parse(&auth_data)?;
+    auth_data.verify(rp_id)?;
+
+    auth_data
+        .credential
+        .map(|credential| (credential, auth_data.sign_count))
+        .ok_or(WebauthnError::InvalidAuthenticatorData)
+}
+
+/// Check a login with a passkey, returns the new signature counter
+fn verify_assertion(
+    response: &PasskeyAssertionResponse,
+    passkey: &Passkey,
+    challenge: &str,
+    origin: &str,
+    rp_id: &str,
+) -> Result<u32, WebauthnError> {
+    let client_data = decode(&response.client_data_json)?;
+    verify_client_data(&client_data, "webauthn.get", challenge, origin)?;
+
+    let raw_auth_data = decode(&response.authenticator_data)?;
+    let auth_data = AuthenticatorData::parse(&raw_auth_data)?;
+    auth_data.verify(rp_id)?;
+
+    verify_signature(
+        &decode(&passkey.public_key)?,
+        &raw_auth_data,
+        &client_data,
+        &decode(&response.signature)?,
+    )?;
+    verify_sign_count(passkey.sign_count, auth_data.sign_count)?;
+
+    Ok(auth_data.sign_count)
+}
+
+async fn passkey_register_start(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+) -> Response {
+    let admin = match current_admin(&state, &identity).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+    let Some((_, rp_id)) = relying_party(&state) else {
+        return passkeys_unavailable();
+    };
+
+    let existing = match state.db.get_admin_passkeys(admin.id).await {
+        Ok(passkeys) => passkeys,
+        Err(e) => {
+            tracing::error!("Failed to get passkeys of {}: {}", admin.username, e);
+            return login_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to get passkeys");
+        }
+    };
+
+    let challenge_id = uuid::Uuid::new_v4();
+    let challenge = generate_challenge();
+    if let Err(e) = state
+        .db
+        .add_passkey_challenge(
+            challenge_id,
+            Some(admin.id),
+            "register",
+            &challenge,
+            unix_now() + PASSKEY_CHALLENGE_TIMEOUT,
+        )
+        .await
+    {
+        tracing::error!("Failed to store passkey challenge: {}", e);
+        return login_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to start passkey registration",
+        );
+    }
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "data": {
+                "challengeId": challenge_id,
+                "publicKey": {
+                    "challenge": challenge,
+                    "rp": { "id": rp_id, "name": "K-Librarian" },
+                    "user": {
+                        "id": encode(admin.id.as_bytes()),
+                        "name": admin.username,
+                        "displayName": admin.username,
+                    },
+                    "pubKeyCredParams": [{ "type": "public-key", "alg": ES256 }],
+                    "authenticatorSelection": {
+                        "residentKey": "required",
+                        "userVerification": "required",
+                    },
+                    "excludeCredentials": existing
+                        .iter()
+                        .map(|passkey| serde_json::json!({ "type": "public-key", "id": passkey.id }))
+                        .collect::<Vec<_>>(),
+                    "attestation": "none",
+                    "timeout": PASSKEY_CHALLENGE_TIMEOUT * 1000,
+                }
+            }
+        })),
+    )
+        .into_response()
+}
+
+async fn passkey_register_finish(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Json(form): Json<PasskeyRegisterForm>,
+) -> Response {
+    let admin = match current_admin(&state, &identity).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+    let Some((origin, rp_id)) = relying_party(&state) else {
+        return passkeys_unavailable();
+    };
+
+    let challenge = match state
+        .db
+        .take_passkey_challenge(form.challenge_id, "register")
+        .await
+    {
+        Ok(Some((Some(admin_id), challenge))) if admin_id == admin.id => challenge,
+        Ok(_) => {
+            return login_error(
+                StatusCode::BAD_REQUEST,
+                "The passkey registration has expired, please try again",
+            );
+        }
+        Err(e) => {
+            tracing::error!("Failed to get passkey challenge: {}", e);
+            return login_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to register passkey",
+            );
+        }
+    };
+
+    let (credential, sign_count) =
+        match verify_registration(&form.credential.response, &challenge, &origin, &rp_id) {
+            Ok(credential) => credential,
+            Err(e) => return passkey_error(e),
+        };
+
+    let name = form
+        .name
+        .map(|name| name.trim().chars().take(64).collect::<String>())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "Passkey".to_string());
+    let passkey = Passkey {
+        id: encode(&credential.id),
+        admin_id: admin.id,
+        name,
+        public_key: encode(&credential.public_key),
+        sign_count,
+        created_at: unix_now(),
+        last_used_at: None,
+    };
+
+    if passkey.id != form.credential.id.trim_end_matches('=') {
+        return passkey_error(WebauthnError::InvalidAuthenticatorData);
+    }
+
+    match state.db.get_passkey(&passkey.id).await {
+        Ok(None) => {}
+        Ok(Some(_)) => {
+            return login_error(StatusCode::CONFLICT, "This passkey is already registered");
+        }
+        Err(e) => {
+            tracing::error!("Failed to get passkey: {}", e);
+            return login_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to register passkey",
+            );
+        }
+    }
+
+    if let Err(e) = state.db.add_passkey(&passkey).await {
+        tracing::error!("Failed to store passkey of {}: {}", admin.username, e);
+        return login_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to register passkey",
+        );
+    }
+
+    tracing::info!(
+        "Admin {} registered passkey {}",
+        admin.username,
+        passkey.name
+    );
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "data": passkey
+        })),
+    )
+        .into_response()
+}
+
+/// Start a passkey login, the browser lets the admin pick one of their discoverable passkeys
+async fn passkey_login_start(State(state): State<AppState>) -> Response {
+    let Some((_, rp_id)) = relying_party(&state) else {
+        return passkeys_unavailable();
+    };
+
+    let challenge_id = uuid::Uuid::new_v4();
+    let challenge = generate_challenge();
+    if let Err(e) = state
+        .db
+        .add_passkey_challenge(
+            challenge_id,
+            None,
+            "login",
+            &challenge,
+            unix_now() + PASSKEY_CHALLENGE_TIMEOUT,
+        )
+        .await
+    {
+        tracing::error!("Failed to store passkey challenge: {}", e);
+        return login_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to log in");
+    }
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "data": {
+                "challengeId": challenge_id,
+                "publicKey": {
+                    "challenge": challenge,
+                    "rpId": rp_id,
+                    "userVerification": "required",
+                    "timeout": PASSKEY_CHALLENGE_TIMEOUT * 1000,
+                }
+            }
+        })),
+    )
+        .into_response()
+}
+
+async fn passkey_login_finish(
+    State(state): State<AppState>,
+    Json(form): Json<PasskeyLoginForm>,
+) -> Response {
+    let Some((origin, rp_id)) = relying_party(&state) else {
+        return passkeys_unavailable();
+    };
+
+    let challenge = match state
+        .db
+        .take_passkey_challenge(form.challenge_id, "login")
+        .await
+    {
+        Ok(Some((_, challenge))) => challenge,
+        Ok(None) => {
+            return login_error(
+                StatusCode::BAD_REQUEST,
+                "The passkey login has expired, please try again",
+            );
+        }
+        Err(e) => {
+            tracing::error!("Failed to get passkey challenge: {}", e);
+            return login_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to log in");
+        }
+    };
+
+    let passkey = match state
+        .db
+        .get_passkey(form.credential.id.trim_end_matches('='))
+        .await
+    {
+        Ok(Some(passkey)) => passkey,
+        Ok(None) => return failed_login("Unknown passkey"),
+        Err(e) => {
+            tracing::error!("Failed to get passkey: {}", e);
+            return login_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to log in");
+        }
+    };
+
+    let response = &form.credential.response;
+    let sign_count = match verify_assertion(response, &passkey, &challenge, &origin, &rp_id) {
+        Ok(sign_count) => sign_count,
+        Err(e) => {
+            tracing::warn!("Rejected passkey {}: {}", passkey.id, e);
+            return failed_login("Invalid passkey");
+        }
+    };
+
+    // the user handle is the admin ID we gave when registering
+    if let Some(user_handle) = &response.user_handle
+        && decode(user_handle).ok().as_deref() != Some(passkey.admin_id.as_bytes().as_slice())
+    {
+        return failed_login("Invalid passkey");
+    }
+
+    if let Err(e) = state.db.touch_passkey(&passkey.id, sign_count).await {
+        tracing::error!("Failed to update passkey {}: {}", passkey.id, e);
+    }
+
+    let admin = match state.db.get_admin(passkey.admin_id).await {
+        Ok(Some(admin)) => admin,
+        Ok(None) => return failed_login("Unknown passkey"),
+        Err(e) => {
+            tracing::error!("Failed to get admin {}: {}", passkey.admin_id, e);
+            return login_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to log in");
+        }
+    };
+
+    tracing::info!("Admin {} logged in with a passkey", admin.username);
+    start_session(&state, Some(admin.id), Some(admin.username), admin.role).await
+}
+
+async fn get_passkeys(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+) -> Response {
+    let admin = match current_admin(&state, &identity).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+
+    match state.db.get_admin_passkeys(admin.id).await {
+        Ok(passkeys) => (
             StatusCode::OK,
-            Json(LoginResponse {
-                ok: true,
-                error: None,
-            }),
+            Json(serde_json::json!({
+                "ok": true,
+                "data": passkeys
+            })),
         )
-    } else {
-        (
-            StatusCode::UNAUTHORIZED,
-            Json(LoginResponse {
-                ok: false,
-                error: Some("Invalid token".to_string()),
-            }),
+            .into_response(),
+        Err(e) => {
+            tracing::error!("Failed to get passkeys of {}: {}", admin.username, e);
+            login_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to get passkeys")
+        }
+    }
+}
+
+async fn delete_passkey(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path(id): Path<String>,
+) -> Response {
+    let admin = match current_admin(&state, &identity).await {
+        Ok(admin) => admin,
+        Err(response) => return response,
+    };
+
+    match state.db.delete_passkey(admin.id, &id).await {
+        Ok(true) => {
+            tracing::info!("Admin {} removed passkey {}", admin.username, id);
+            (StatusCode::OK, Json(serde_json::json!({ "ok": true }))).into_response()
+        }
+        Ok(false) => login_error(StatusCode::NOT_FOUND, "Passkey not found"),
+        Err(e) => {
+            tracing::error!("Failed to delete passkey {}: {}", id, e);
+            login_error(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to delete passkey",
+            )
+        }
+    }
+}
+
+fn oidc_redirect_url(state: &AppState, peer: SocketAddr, headers: &HeaderMap) -> String {
+    format!(
+        "{}/api/auth/oidc/callback",
+        super::invite::public_url(state, peer, headers)
+    )
+}
+
+/// Send the browser back to the admin panel with an error to show
+fn oidc_failed(state: &AppState, error: &str) -> Response {
+    (
+        StatusCode::SEE_OTHER,
+        AppendHeaders([
+            (
+                LOCATION,
+                format!("/admin?error={}", urlencoding::encode(error)),
+            ),
+            (SET_COOKIE, oidc_state_cookie("", 0, secure_cookies(state))),
+        ]),
+    )
+        .into_response()
+}
+
+/// Whether the login form should offer the OpenID Connect provider
+async fn oidc_status(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "data": {
+                "enabled": state.config.oidc.is_some(),
+                "name": state.config.oidc.as_ref().and_then(|oidc| oidc.name.clone()),
+            }
+        })),
+    )
+}
+
+async fn oidc_login(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(config) = &state.config.oidc else {
+        return login_error(StatusCode::NOT_FOUND, "OpenID Connect is not configured");
+    };
+
+    let login = match start_login(config, &oidc_redirect_url(&state, peer, &headers)).await {
+        Ok(login) => login,
+        Err(e) => {
+            tracing::error!("Failed to start OpenID Connect login: {}", e);
+            return oidc_failed(&state, "Failed to reach the login provider");
+        }
+    };
+
+    if let Err(e) = state
+        .db
+        .add_oidc_login(
+            &login.state,
+            &login.pkce_verifier,
+            &login.nonce,
+            unix_now() + OIDC_LOGIN_TIMEOUT,
         )
+        .await
+    {
+        tracing::error!("Failed to store OpenID Connect login: {}", e);
+        return oidc_failed(&state, "Failed to log in");
+    }
+
+    (
+        StatusCode::SEE_OTHER,
+        AppendHeaders([
+            (LOCATION, login.url),
+            (
+                SET_COOKIE,
+                oidc_state_cookie(&login.state, OIDC_LOGIN_TIMEOUT, secure_cookies(&state)),
+            ),
+        ]),
+    )
+        .into_response()
+}
+
+/// Find the admin linked to the provider account, or create it on the first login.
+/// The role follows the groups on every login, so removing someone from a group demotes them.
+async fn oidc_admin(
+    state: &AppState,
+    identity: &OidcIdentity,
+    role: AdminRole,
+) -> Result<Admin, String> {
+    let internal_error = |e: LocalDatabaseError| {
+        tracing::error!("Failed to get admin for {}: {}", identity.subject, e);
+        "Failed to log in".to_string()
+    };
+
+    if let Some(mut admin) = state
+        .db
+        .get_admin_by_oidc_subject(&identity.subject)
+        .await
+        .map_err(internal_error)?
+    {
+        if admin.role != role {
+            state
+                .db
+                .update_admin(admin.id, None, Some(role))
+                .await
+                .map_err(internal_error)?;
+            admin.role = role;
+        }
+
+        return Ok(admin);
+    }
+
+    // never link to an existing local admin, the provider does not prove it is the same person
+    if state
+        .db
+        .get_admin_by_username(&identity.username)
+        .await
+        .map_err(internal_error)?
+        .is_some()
+    {
+        return Err(format!(
+            "The username {} is already used by another admin",
+            identity.username
+        ));
+    }
+
+    let admin = Admin {
+        id: uuid::Uuid::new_v4(),
+        username: identity.username.clone(),
+        role,
+        password_hash: String::new(),
+        created_at: Some(unix_now()),
+        totp_secret: None,
+        totp_enabled: false,
+        totp_last_step: None,
+        recovery_codes: Vec::new(),
+        oidc_subject: Some(identity.subject.clone()),
+    };
+    state.db.add_admin(&admin).await.map_err(internal_error)?;
+    tracing::info!(
+        "Created admin {} from the login provider as {}",
+        admin.username,
+        admin.role.as_str()
+    );
+
+    Ok(admin)
+}
+
+async fn oidc_callback(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<OidcCallbackQuery>,
+) -> Response {
+    let Some(config) = &state.config.oidc else {
+        return login_error(StatusCode::NOT_FOUND, "OpenID Connect is not configured");
+    };
+
+    if let Some(error) = query.error {
+        tracing::warn!(
+            "Login provider returned an error: {} {}",
+            error,
+            query.error_description.as_deref().unwrap_or_default()
+        );
+        return oidc_failed(&state, "The login provider refused the login");
     }
+
+    // the state has to come back to the same browser that started the login
+    let (Some(code), Some(login_state)) = (query.code, query.state) else {
+        return oidc_failed(&state, "The login provider sent an incomplete response");
+    };
+    if cookie_value(&headers, OIDC_STATE_COOKIE) != Some(login_state.as_str()) {
+        return oidc_failed(&state, "The login was started in another browser");
+    }
+
+    let (pkce_verifier, nonce) = match state.db.take_oidc_login(&login_state).await {
+        Ok(Some(login)) => login,
+        Ok(None) => return oidc_failed(&state, "The login has expired, please try again"),
+        Err(e) => {
+            tracing::error!("Failed to get OpenID Connect login: {}", e);
+            return oidc_failed(&state, "Failed to log in");
+        }
+    };
+
+    let identity = match finish_login(
+        config,
+        &oidc_redirect_url(&state, peer, &headers),
+        &code,
+        &pkce_verifier,
+        &nonce,
+    )
+    .await
+    {
+        Ok(identity) => identity,
+        Err(e) => {
+            tracing::error!("Failed to finish OpenID Connect login: {}", e);
+            return oidc_failed(&state, "The login provider could not confirm the login");
+        }
+    };
+
+    let Some(role) = role_for_groups(&config.roles, &identity.groups) else {
+        tracing::warn!(
+            "{} logged in at the provider but is not in any admin group",
+            identity.username
+        );
+        return oidc_failed(&state, "Your account is not allowed to use the admin panel");
+    };
+
+    let admin = match oidc_admin(&state, &identity, role).await {
+        Ok(admin) => admin,
+        Err(error) => return oidc_failed(&state, &error),
+    };
+
+    let (cookie, _, _) = match create_session(&state, Some(admin.id)).await {
+        Ok(session) => session,
+        Err(e) => {
+            tracing::error!("Failed to create session: {}", e);
+            return oidc_failed(&state, "Failed to log in");
+        }
+    };
+
+    tracing::info!("Admin {} logged in with OpenID Connect", admin.username);
+    (
+        StatusCode::SEE_OTHER,
+        AppendHeaders([
+            (LOCATION, "/admin?login=oidc".to_string()),
+            (SET_COOKIE, cookie),
+            (SET_COOKIE, oidc_state_cookie("", 0, secure_cookies(&state))),
+        ]),
+    )
+        .into_response()
+}
+
+async fn auth_logout(
+    State(state): State<AppState>,
+    session: Option<Extension<Session>>,
+) -> impl IntoResponse {
+    // a bearer token has no session to end
+    if let Some(Extension(session)) = session
+        && let Err(e) = state.db.delete_session(&session.token_hash).await
+    {
+        tracing::error!("Failed to delete session: {}", e);
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [(SET_COOKIE, session_cookie("", 0, secure_cookies(&state)))],
+            Json(serde_json::json!({
+                "ok": false,
+                "error": "Failed to log out"
+            })),
+        );
+    }
+
+    (
+        StatusCode::OK,
+        [(SET_COOKIE, session_cookie("", 0, secure_cookies(&state)))],
+        Json(serde_json::json!({ "ok": true })),
+    )
+}
+
+async fn auth_me(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    session: Option<Extension<Session>>,
+    proxy_login: Option<Extension<ProxyLogin>>,
+) -> impl IntoResponse {
+    let totp_enabled = match identity.id {
+        Some(id) => matches!(state.db.get_admin(id).await, Ok(Some(admin)) if admin.totp_enabled),
+        None => false,
+    };
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "data": {
+                "id": identity.id,
+                "username": identity.username,
+                "role": identity.role,
+                "scopes": identity.scopes,
+                "totpEnabled": totp_enabled,
+                "csrfToken": session
+                    .as_ref()
+                    .map(|session| &session.csrf_token)
+                    .or(proxy_login.as_ref().map(|login| &login.csrf_token)),
+                "expiresAt": session.as_ref().map(|session| session.expires_at),
+            }
+        })),
+    )
 }
 
 pub fn auth_routes(state: AppState) -> Router<AppState> {
     Router::new()
+        .route("/logout", axum::routing::post(auth_logout))
+        .route("/me", axum::routing::get(auth_me))
+        .route("/totp/setup", axum::routing::post(totp_setup))
+        .route("/totp/enable", axum::routing::post(totp_enable))
+        .route("/totp/disable", axum::routing::post(totp_disable))
+        .route("/passkeys", axum::routing::get(get_passkeys))
+        .route("/passkeys/{id}", axum::routing::delete(delete_passkey))
+        .route(
+            "/passkeys/register/start",
+            axum::routing::post(passkey_register_start),
+        )
+        .route(
+            "/passkeys/register/finish",
+            axum::routing::post(passkey_register_finish),
+        )
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ))
         .route("/login", axum::routing::post(auth_login))
+        .route("/oidc", axum::routing::get(oidc_status))
+        .route("/oidc/login", axum::routing::get(oidc_login))
+        .route("/oidc/callback", axum::routing::get(oidc_callback))
+        .route(
+            "/passkeys/login/start",
+            axum::routing::post(passkey_login_start),
+        )
+        .route(
+            "/passkeys/login/finish",
+            axum::routing::post(passkey_login_finish),
+        )
         .with_state(state)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::webauthn::testing::{FLAGS_VERIFIED, SoftwareAuthenticator};
+
+    const RP_ID: &str = "invite.example.com";
+    const ORIGIN: &str = "https://invite.example.com";
+
+    fn login_form(token: Option<&str>, username: Option<&str>, code: Option<&str>) -> LoginForm {
+        LoginForm {
+            token: token.map(str::to_string),
+            username: username.map(str::to_string),
+            password: username.map(|_| "password".to_string()),
+            code: code.map(str::to_string),
+        }
+    }
+
+    #[tokio::test]
+    async fn accepts_a_totp_code_only_once_at_the_same_time() {
+        let state = crate::testing::state("http://127.0.0.1:1").await;
+        let secret = generate_totp_secret();
+        let admin = Admin {
+            id: uuid::Uuid::new_v4(),
+            username: "someone".to_string(),
+            role: AdminRole::Owner,
+            password_hash: crate::auth::hash_password("password").unwrap(),
+            created_at: None,
+            totp_secret: Some(secret.clone()),
+            totp_enabled: true,
+            totp_last_step: None,
+            recovery_codes: Vec::new(),
+            oidc_subject: None,
+        };
+        state.db.add_admin(&admin).await.unwrap();
+        state.db.update_admin_totp(&admin).await.unwrap();
+
+        let code = totp(&secret, "someone")
+            .unwrap()
+            .generate_current()
+            .unwrap();
+        let login = || {
+            auth_login(
+                State(state.clone()),
+                Json(login_form(None, Some("someone"), Some(&code))),
+            )
+        };
+        let (first, second) = tokio::join!(login(), login());
+
+        let mut statuses = [first.status(), second.status()];
+        statuses.sort();
+        assert_eq!(statuses, [StatusCode::OK, StatusCode::UNAUTHORIZED]);
+    }
+
+    #[tokio::test]
+    async fn asks_for_a_code_with_the_shared_token_once_it_has_a_secret() {
+        let mut state = crate::testing::state("http://127.0.0.1:1").await;
+        let secret = generate_totp_secret();
+        state.config = std::sync::Arc::new(crate::config::Config {
+            token_totp_secret: Some(secret.clone()),
+            ..(*state.config).clone()
+        });
+
+        let code = totp(&secret, crate::auth::SHARED_TOKEN_TOTP_ACCOUNT)
+            .unwrap()
+            .generate_current()
+            .unwrap();
+        let login = |code: Option<&str>| {
+            auth_login(
+                State(state.clone()),
+                Json(login_form(Some("test-token"), None, code)),
+            )
+        };
+
+        let response = login(None).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: LoginResponse = serde_json::from_slice(&body).unwrap();
+        assert!(body.totp_required);
+
+        assert_eq!(login(Some(&code)).await.status(), StatusCode::OK);
+        // the same code cannot be used twice
+        assert_eq!(login(Some(&code)).await.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_log_in_an_unknown_admin() {
+        let state = crate::testing::state("http://127.0.0.1:1").await;
+
+        let response = auth_login(State(state), Json(login_form(None, Some("nobody"), None))).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    /// A state with the public URL set, and an admin with the passkey of the authenticator
+    async fn passkey_state(authenticator: &SoftwareAuthenticator) -> (AppState, Passkey) {
+        let mut state = crate::testing::state("http://127.0.0.1:1").await;
+        state.config = std::sync::Arc::new(crate::config::Config {
+            public_url: Some(ORIGIN.to_string()),
+            ..(*state.config).clone()
+        });
+
+        let admin = Admin {
+            id: uuid::Uuid::new_v4(),
+            username: "someone".to_string(),
+            role: AdminRole::Owner,
+            password_hash: String::new(),
+            created_at: None,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_step: None,
+            recovery_codes: Vec::new(),
+            oidc_subject: None,
+        };
+        state.db.add_admin(&admin).await.unwrap();
+
+        let passkey = Passkey {
+            id: encode(&authenticator.credential_id),
+            admin_id: admin.id,
+            name: "Software".to_string(),
+            public_key: encode(&authenticator.public_key()),
+            sign_count: 0,
+            created_at: 0,
+            last_used_at: None,
+        };
+        state.db.add_passkey(&passkey).await.unwrap();
+
+        (state, passkey)
+    }
+
+    /// Start a login, returns the ID of the challenge and the challenge itself
+    async fn start_login(state: &AppState) -> (uuid::Uuid, String) {
+        let response = passkey_login_start(State(state.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let data = &body["data"];
+
+        (
+            serde_json::from_value(data["challengeId"].clone()).unwrap(),
+            data["publicKey"]["challenge"].as_str().unwrap().to_string(),
+        )
+    }
+
+    async fn finish_login(
+        state: &AppState,
+        authenticator: &mut SoftwareAuthenticator,
+        credential_id: &str,
+        challenge_id: uuid::Uuid,
+        challenge: &str,
+    ) -> Response {
+        let client_data = SoftwareAuthenticator::client_data("webauthn.get", challenge, ORIGIN);
+        let (auth_data, signature) = authenticator.assert(RP_ID, FLAGS_VERIFIED, &client_data);
+
+        passkey_login_finish(
+            State(state.clone()),
+            Json(PasskeyLoginForm {
+                challenge_id,
+                credential: PasskeyCredential {
+                    id: credential_id.to_string(),
+                    response: PasskeyAssertionResponse {
+                        client_data_json: encode(&client_data),
+                        authenticator_data: encode(&auth_data),
+                        signature: encode(&signature),
+                        user_handle: None,
+                    },
+                },
+            }),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn uses_up_the_challenge_of_a_passkey_login() {
+        let mut authenticator = SoftwareAuthenticator::new();
+        let (state, passkey) = passkey_state(&authenticator).await;
+
+        let (challenge_id, challenge) = start_login(&state).await;
+        let login = finish_login(
+            &state,
+            &mut authenticator,
+            &passkey.id,
+            challenge_id,
+            &challenge,
+        )
+        .await;
+        assert_eq!(login.status(), StatusCode::OK);
+        let stored = state.db.get_passkey(&passkey.id).await.unwrap().unwrap();
+        assert_eq!(stored.sign_count, 1);
+
+        let replay = finish_login(
+            &state,
+            &mut authenticator,
+            &passkey.id,
+            challenge_id,
+            &challenge,
+        )
+        .await;
+        assert_eq!(replay.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn refuses_an_expired_passkey_challenge() {
+        let mut authenticator = SoftwareAuthenticator::new();
+        let (state, passkey) = passkey_state(&authenticator).await;
+
+        let challenge_id = uuid::Uuid::new_v4();
+        state
+            .db
+            .add_passkey_challenge(challenge_id, None, "login", "old-challenge", unix_now() - 1)
+            .await
+            .unwrap();
+
+        let login = finish_login(
+            &state,
+            &mut authenticator,
+            &passkey.id,
+            challenge_id,
+            "old-challenge",
+        )
+        .await;
+        assert_eq!(login.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn refuses_an_unknown_passkey() {
+        let mut authenticator = SoftwareAuthenticator::new();
+        let (state, _) = passkey_state(&authenticator).await;
+
+        let (challenge_id, challenge) = start_login(&state).await;
+        let login = finish_login(
+            &state,
+            &mut authenticator,
+            &encode(b"another-credential"),
+            challenge_id,
+            &challenge,
+        )
+        .await;
+        assert_eq!(login.status(), StatusCode::UNAUTHORIZED);
+        assert!(login.extensions().get::<FailedAttempt>().is_some());
+    }
+
+    #[tokio::test]
+    async fn refuses_a_passkey_whose_counter_went_back() {
+        let mut authenticator = SoftwareAuthenticator::new();
+        let (state, passkey) = passkey_state(&authenticator).await;
+        // a cloned authenticator would report a counter the server has already seen
+        state.db.touch_passkey(&passkey.id, 10).await.unwrap();
+
+        let (challenge_id, challenge) = start_login(&state).await;
+        let login = finish_login(
+            &state,
+            &mut authenticator,
+            &passkey.id,
+            challenge_id,
+            &challenge,
+        )
+        .await;
+        assert_eq!(login.status(), StatusCode::UNAUTHORIZED);
+        let stored = state.db.get_passkey(&passkey.id).await.unwrap().unwrap();
+        assert_eq!(stored.sign_count, 10);
+    }
+}
@@ -1,8 +1,10 @@
+use std::net::SocketAddr;
+
 use axum::{
-    Json, Router,
-    extract::{Path, State},
-    http::{HeaderMap, StatusCode},
-    response::IntoResponse,
+    Extension, Json, Router,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
 };
 use garde::Validate;
 use serde_json::Value;
@@ -10,17 +12,20 @@ use tracing::{error, info};
 
 use crate::{
     AppState,
-    database::{InviteToken, KomgaInviteOption, NavidromeInviteOption, TokenId},
-    invitee::{InviteTokenApplicationPayload, create_user_in},
-    routes::middleware::auth_middleware,
+    database::{
+        AdminRole, InviteFilter, InviteToken, KomgaInviteOption, NavidromeInviteOption, TokenId,
+    },
+    invitee::{InviteTokenApplicationPayload, UserCreationError, redeem_invite},
+    routes::middleware::{AdminIdentity, auth_middleware},
 };
 
-#[derive(serde::Serialize, serde::Deserialize)]
-pub struct InviteQuery {
-    token: String,
-}
+/// How many invites a single bulk request can create
+const MAX_BULK_INVITES: usize = 500;
+const MAX_INVITE_TAGS: usize = 20;
+/// The largest page the invite list can return at once
+const MAX_INVITES_PER_PAGE: u64 = 200;
 
-#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
 #[serde(tag = "kind")]
 pub enum InviteRequestParams {
     #[serde(rename = "komga")]
@@ -29,20 +34,136 @@ pub enum InviteRequestParams {
     Navidrome(NavidromeInviteOption),
 }
 
+impl InviteRequestParams {
+    fn into_invite(self) -> InviteToken {
+        match self {
+            InviteRequestParams::Komga(komga_option) => InviteToken::create_komga(komga_option),
+            InviteRequestParams::Navidrome(navidrome_option) => {
+                InviteToken::create_navidrome(navidrome_option)
+            }
+        }
+    }
+}
+
+#[derive(serde::Deserialize, garde::Validate)]
+pub struct InviteCreatePayload {
+    #[serde(flatten)]
+    #[garde(skip)]
+    option: InviteRequestParams,
+    #[garde(inner(length(max = 256)))]
+    note: Option<String>,
+    #[serde(default)]
+    #[garde(length(max = MAX_INVITE_TAGS), inner(length(max = 32)))]
+    tags: Vec<String>,
+}
+
+#[derive(serde::Deserialize, garde::Validate)]
+pub struct BulkInviteRequest {
+    #[garde(skip)]
+    option: InviteRequestParams,
+    /// Applied to every created invite
+    #[serde(default)]
+    #[garde(length(max = MAX_INVITE_TAGS), inner(length(max = 32)))]
+    tags: Vec<String>,
+    /// Only needed when no entries are given
+    #[garde(inner(range(min = 1, max = MAX_BULK_INVITES)))]
+    count: Option<usize>,
+    #[serde(default)]
+    #[garde(length(max = MAX_BULK_INVITES), dive)]
+    entries: Vec<BulkInviteEntry>,
+}
+
+#[derive(serde::Deserialize, garde::Validate, Default)]
+pub struct BulkInviteEntry {
+    #[garde(inner(length(max = 256)))]
+    note: Option<String>,
+    #[garde(inner(email))]
+    email: Option<String>,
+}
+
+#[derive(serde::Deserialize, garde::Validate)]
+pub struct InviteUpdatePayload {
+    /// Options to change, the missing ones keep their current value
+    #[garde(skip)]
+    option: Option<serde_json::Map<String, Value>>,
+    #[garde(skip)]
+    paused: Option<bool>,
+    /// An empty note removes it
+    #[garde(inner(length(max = 256)))]
+    note: Option<String>,
+    #[garde(inner(length(max = MAX_INVITE_TAGS), inner(length(max = 32))))]
+    tags: Option<Vec<String>>,
+}
+
+/// Trim the tags and drop the empty and duplicated ones
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = vec![];
+    for tag in tags {
+        let tag = tag.trim();
+        if !tag.is_empty() && !normalized.iter().any(|t| t == tag) {
+            normalized.push(tag.to_string());
+        }
+    }
+    normalized
+}
+
+fn validation_error(e: garde::Report) -> String {
+    let mut format_err = String::new();
+    for (field, err) in e.iter() {
+        format_err.push_str(&format!("- {field}: {err}"));
+        format_err.push('\n');
+    }
+
+    format!("Invalid request:\n{}", format_err)
+}
+
+#[derive(serde::Deserialize)]
+pub struct BulkInviteQuery {
+    /// Either `json` (the default) or `csv`
+    format: Option<String>,
+}
+
 pub async fn create_invite_token(
     State(state): State<AppState>,
-    Json(option): Json<InviteRequestParams>,
+    Extension(identity): Extension<AdminIdentity>,
+    Json(payload): Json<InviteCreatePayload>,
 ) -> impl IntoResponse {
-    let generated_token = match option {
-        InviteRequestParams::Komga(komga_option) => InviteToken::create_komga(komga_option),
-        InviteRequestParams::Navidrome(navidrome_option) => {
-            InviteToken::create_navidrome(navidrome_option)
-        }
-    };
-
     let mut headers = HeaderMap::new();
     headers.insert("Content-Type", "application/json".parse().unwrap());
 
+    if let Err(e) = payload.validate() {
+        let wrapped_json: Value = serde_json::json!({
+            "ok": false,
+            "error": validation_error(e)
+        });
+
+        return (
+            StatusCode::BAD_REQUEST,
+            headers,
+            serde_json::to_string(&wrapped_json).unwrap(),
+        );
+    }
+
+    let generated_token = payload
+        .option
+        .into_invite()
+        .with_note_and_email(payload.note.filter(|note| !note.trim().is_empty()), None)
+        .with_tags(normalize_tags(payload.tags))
+        .with_creator(identity.username.clone());
+
+    if let Some((status, error)) = check_invite(&state, &identity, &generated_token).await {
+        let wrapped_json: Value = serde_json::json!({
+            "ok": false,
+            "error": error
+        });
+
+        return (
+            status,
+            headers,
+            serde_json::to_string(&wrapped_json).unwrap(),
+        );
+    }
+
     // store the token in SQL
     match state.db.add_invite(&generated_token).await {
         Ok(_) => {
@@ -76,6 +197,277 @@ pub async fn create_invite_token(
     }
 }
 
+/// Check everything an invite references, returning the error to send back if something is wrong.
+///
+/// Only the owners can invite server admins or hand over an existing account, since redeeming it
+/// resets the credentials of that account.
+async fn check_invite(
+    state: &AppState,
+    identity: &AdminIdentity,
+    invite: &InviteToken,
+) -> Option<(StatusCode, String)> {
+    let is_owner = identity.role == AdminRole::Owner && identity.scopes.is_none();
+    if !is_owner && invite.grants_admin() {
+        return Some((
+            StatusCode::FORBIDDEN,
+            "Only owners can create invites for admin accounts".to_string(),
+        ));
+    }
+    if !is_owner && invite.handover_user_id().is_some() {
+        return Some((
+            StatusCode::FORBIDDEN,
+            "Only owners can hand over existing accounts".to_string(),
+        ));
+    }
+
+    // Komga keeps the email an account was created with, so handing over is Navidrome only
+    if matches!(invite, InviteToken::Komga { .. }) && invite.handover_user_id().is_some() {
+        return Some((
+            StatusCode::BAD_REQUEST,
+            "Only Navidrome accounts can be handed over, Komga does not allow changing their email"
+                .to_string(),
+        ));
+    }
+
+    // make sure the referenced profile exists and is for the same server
+    if let Some(profile_id) = invite.profile_id() {
+        match state.db.get_profile(profile_id).await {
+            Ok(Some(profile)) if !is_owner && profile.grants_admin() => {
+                return Some((
+                    StatusCode::FORBIDDEN,
+                    "Only owners can create invites for admin accounts".to_string(),
+                ));
+            }
+            Ok(Some(profile)) if profile.kind() == invite.kind() => {}
+            Ok(_) => {
+                return Some((
+                    StatusCode::BAD_REQUEST,
+                    format!("Restriction profile not found: {}", profile_id),
+                ));
+            }
+            Err(error) => {
+                return Some((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Failed to get restriction profile: {}", error),
+                ));
+            }
+        }
+    }
+
+    // make sure the account to hand over exists and is not one of our service accounts
+    if let Some(user_id) = invite.handover_user_id() {
+        match handover_user_exists(state, user_id).await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Some((
+                    StatusCode::BAD_REQUEST,
+                    format!("User to hand over not found: {}", user_id),
+                ));
+            }
+            Err(error) => {
+                return Some((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("Failed to get user to hand over: {}", error),
+                ));
+            }
+        }
+    }
+
+    None
+}
+
+/// The base URL invite links are built from, without a trailing slash. The forwarded headers are
+/// only read from a trusted proxy, anyone else could point the links to their own site.
+pub(super) fn public_url(state: &AppState, peer: SocketAddr, headers: &HeaderMap) -> String {
+    if let Some(public_url) = &state.config.public_url {
+        return public_url.trim_end_matches('/').to_string();
+    }
+
+    let forwarded = |name: &str| {
+        headers
+            .get(name)
+            .filter(|_| state.config.is_trusted_proxy(peer.ip()))
+    };
+    let host = forwarded("x-forwarded-host")
+        .or_else(|| headers.get("host"))
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{}:{}", state.config.host, state.config.port));
+    let scheme = forwarded("x-forwarded-proto")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("http");
+
+    format!("{scheme}://{host}")
+}
+
+/// Quote a CSV field when it contains a separator, a quote or a line break. Values that a
+/// spreadsheet would run as a formula get a leading `'`, so opening the file runs nothing.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{value}")
+    } else {
+        value.to_string()
+    };
+
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+pub async fn create_bulk_invite_tokens(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Query(query): Query<BulkInviteQuery>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request_headers: HeaderMap,
+    Json(request): Json<BulkInviteRequest>,
+) -> Response {
+    let error_response = |status: StatusCode, error: String| {
+        (
+            status,
+            Json(serde_json::json!({
+                "ok": false,
+                "error": error
+            })),
+        )
+            .into_response()
+    };
+
+    if let Err(e) = request.validate() {
+        return error_response(StatusCode::BAD_REQUEST, validation_error(e));
+    }
+
+    let as_csv = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("csv") => true,
+        Some(format) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("Unknown export format: {}", format),
+            );
+        }
+    };
+
+    let entries = match (request.count, request.entries.is_empty()) {
+        (Some(count), true) => (0..count).map(|_| BulkInviteEntry::default()).collect(),
+        (None, false) => request.entries,
+        (Some(count), false) if count == request.entries.len() => request.entries,
+        (Some(_), false) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "The count does not match the number of entries".to_string(),
+            );
+        }
+        (None, true) => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "Either a count or a list of entries is required".to_string(),
+            );
+        }
+    };
+
+    let template = request.option.clone().into_invite();
+    if template.handover_user_id().is_some() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "A single account cannot be handed over by several invites".to_string(),
+        );
+    }
+    if let Some((status, error)) = check_invite(&state, &identity, &template).await {
+        return error_response(status, error);
+    }
+
+    // every invite needs its own token, so build them again from the same options
+    let tags = normalize_tags(request.tags);
+    let invites: Vec<InviteToken> = entries
+        .into_iter()
+        .map(|entry| {
+            request
+                .option
+                .clone()
+                .into_invite()
+                .with_note_and_email(entry.note, entry.email)
+                .with_tags(tags.clone())
+                .with_creator(identity.username.clone())
+        })
+        .collect();
+
+    if let Err(error) = state.db.add_invites(&invites).await {
+        return error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to create invite tokens: {}", error),
+        );
+    }
+
+    info!(
+        "Created {} invites in bulk by {}",
+        invites.len(),
+        identity.username.as_deref().unwrap_or("shared token")
+    );
+
+    let base_url = public_url(&state, peer, &request_headers);
+    let invite_url = |invite: &InviteToken| format!("{}/invite?token={}", base_url, invite.token());
+
+    if as_csv {
+        let mut csv = String::from("token,kind,url,note,email\n");
+        for invite in &invites {
+            let meta = invite.meta();
+            csv.push_str(&format!(
+                "{},{},{},{},{}\n",
+                invite.token(),
+                invite.kind(),
+                csv_field(&invite_url(invite)),
+                csv_field(meta.note.as_deref().unwrap_or_default()),
+                csv_field(meta.email.as_deref().unwrap_or_default()),
+            ));
+        }
+
+        return (
+            StatusCode::OK,
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"invites.csv\"",
+                ),
+            ],
+            csv,
+        )
+            .into_response();
+    }
+
+    let data: Vec<Value> = invites
+        .iter()
+        .map(|invite| {
+            let mut value = serde_json::to_value(invite).unwrap();
+            value["url"] = Value::String(invite_url(invite));
+            value
+        })
+        .collect();
+
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ok": true,
+            "data": data
+        })),
+    )
+        .into_response()
+}
+
+async fn handover_user_exists(state: &AppState, user_id: &str) -> Result<bool, String> {
+    let (Some(navidrome), Some(config)) = (&state.navidrome, &state.config.navidrome) else {
+        return Err("Navidrome is not configured".to_string());
+    };
+
+    let mut client = navidrome.lock().await;
+    let user = client.get_user(user_id).await.map_err(|e| e.to_string())?;
+
+    Ok(user.is_some_and(|user| user.username != config.username))
+}
+
 pub async fn get_invite_config(State(state): State<AppState>) -> impl IntoResponse {
     // Get all the options available in Komga
     let labels = match state.komga.get_sharing_labels().await {
@@ -117,30 +509,39 @@ pub async fn get_invite_config(State(state): State<AppState>) -> impl IntoRespon
         }
     };
 
+    let mut navidrome_library_access = false;
     let navidrome_libraries = match &state.navidrome {
         Some(navidrome) => {
             let mut client = navidrome.lock().await;
-            match client.get_library().await {
-                Ok(libraries) => {
-                    drop(client); // release the lock early
-                    Some(libraries)
-                }
-                Err(_) => {
-                    drop(client); // release the lock early
-                    let mut headers = HeaderMap::new();
-                    headers.insert("Content-Type", "application/json".parse().unwrap());
+            // try the libraries when the server did not say, failing there tells the admin more
+            navidrome_library_access = client.library_access().await != Some(false);
+            if !navidrome_library_access {
+                // nothing to choose from when the server cannot restrict libraries
+                drop(client);
+                Some(vec![])
+            } else {
+                match client.get_library().await {
+                    Ok(libraries) => {
+                        drop(client); // release the lock early
+                        Some(libraries)
+                    }
+                    Err(_) => {
+                        drop(client); // release the lock early
+                        let mut headers = HeaderMap::new();
+                        headers.insert("Content-Type", "application/json".parse().unwrap());
 
-                    // wrap the json in a {"ok": true, "data": {}} object
-                    let wrapped_json: Value = serde_json::json!({
-                        "ok": false,
-                        "error": "Failed to get libraries from Navidrome"
-                    });
+                        // wrap the json in a {"ok": true, "data": {}} object
+                        let wrapped_json: Value = serde_json::json!({
+                            "ok": false,
+                            "error": "Failed to get libraries from Navidrome"
+                        });
 
-                    return (
-                        StatusCode::SERVICE_UNAVAILABLE,
-                        headers,
-                        serde_json::to_string(&wrapped_json).unwrap(),
-                    );
+                        return (
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            headers,
+                            serde_json::to_string(&wrapped_json).unwrap(),
+                        );
+                    }
                 }
             }
         }
@@ -161,6 +562,7 @@ pub async fn get_invite_config(State(state): State<AppState>) -> impl IntoRespon
             },
             "navidrome": {
                 "active": navidrome_libraries.is_some(),
+                "libraryAccess": navidrome_library_access,
                 "libraries": navidrome_libraries.unwrap_or_default()
             },
         }
@@ -217,7 +619,7 @@ pub async fn get_invite_token(
                 // wrap the json in a {"ok": true, "data": {}} object
                 let wrapped_json: Value = serde_json::json!({
                     "ok": true,
-                    "data": data,
+                    "data": data.public_view(),
                 });
 
                 (
@@ -256,6 +658,119 @@ pub async fn get_invite_token(
     }
 }
 
+pub async fn update_invite_token(
+    State(state): State<AppState>,
+    Extension(identity): Extension<AdminIdentity>,
+    Path(token): Path<TokenId>,
+    Json(payload): Json<InviteUpdatePayload>,
+) -> impl IntoResponse {
+    let error_response = |status: StatusCode, error: String| {
+        (
+            status,
+            Json(serde_json::json!({
+                "ok": false,
+                "error": error
+            })),
+        )
+    };
+
+    if let Err(e) = payload.validate() {
+        return error_response(StatusCode::BAD_REQUEST, validation_error(e));
+    }
+    if (payload.option.is_some() || payload.note.is_some() || payload.tags.is_some())
+        && !identity.can_edit_invites()
+    {
+        return error_response(
+            StatusCode::FORBIDDEN,
+            "This API key can only pause invites".to_string(),
+        );
+    }
+
+    let mut invite = match state.db.get_invite(token).await {
+        Ok(Some(invite)) => invite,
+        Ok(None) => {
+            return error_response(StatusCode::NOT_FOUND, "Invite token not found".to_string());
+        }
+        Err(error) => {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to get invite token: {}", error),
+            );
+        }
+    };
+
+    if let Some(changes) = payload.option {
+        // the handed over account is stored with the invite, it cannot be swapped afterwards
+        if let Some(user_id) = changes.get("userId")
+            && user_id.as_str() != invite.handover_user_id()
+        {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "The account handed over by an invite cannot be changed".to_string(),
+            );
+        }
+
+        if let Err(error) = invite.merge_option(changes) {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                format!("Invalid invite options: {}", error),
+            );
+        }
+        if let Some((status, error)) = check_invite(&state, &identity, &invite).await {
+            return error_response(status, error);
+        }
+        if let Err(error) = state.db.update_invite_option(&invite).await {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to update invite token: {}", error),
+            );
+        }
+    }
+
+    if payload.note.is_some() || payload.tags.is_some() {
+        let tags = payload.tags.map(normalize_tags);
+        if let Err(error) = state
+            .db
+            .set_invite_note_and_tags(
+                token,
+                payload.note.as_deref().map(str::trim),
+                tags.as_deref(),
+            )
+            .await
+        {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to update invite token: {}", error),
+            );
+        }
+    }
+
+    if let Some(paused) = payload.paused
+        && let Err(error) = state.db.set_invite_paused(token, paused).await
+    {
+        return error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to update invite token: {}", error),
+        );
+    }
+
+    info!("Updated invite token: {}", token);
+    match state.db.get_invite(token).await {
+        Ok(Some(invite)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": invite
+            })),
+        ),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, "Invite token not found".to_string()),
+        Err(error) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to get invite token: {}", error),
+        ),
+    }
+}
+
 pub async fn delete_invite_token(
     State(state): State<AppState>,
     Path(token): Path<TokenId>,
@@ -293,22 +808,18 @@ pub async fn delete_invite_token(
 pub async fn apply_invite_token(
     State(state): State<AppState>,
     Path(token): Path<TokenId>,
-    Json(request): Json<InviteTokenApplicationPayload>,
+    request: Option<Json<InviteTokenApplicationPayload>>,
 ) -> impl IntoResponse {
-    if let Err(e) = request.validate() {
+    // invites with generated credentials are redeemed without a body
+    let request = request.map(|Json(request)| request);
+    if let Some(Err(e)) = request.as_ref().map(|request| request.validate()) {
         let mut headers = HeaderMap::new();
         headers.insert("Content-Type", "application/json".parse().unwrap());
 
-        let mut format_err = String::new();
-        for (field, err) in e.iter() {
-            format_err.push_str(&format!("- {field}: {err}"));
-            format_err.push('\n');
-        }
-
         // wrap the json in a {"ok": true, "data": {}} object
         let wrapped_json: Value = serde_json::json!({
             "ok": false,
-            "error": format!("Invalid request:\n{}", format_err)
+            "error": validation_error(e)
         });
 
         return (
@@ -338,15 +849,26 @@ pub async fn apply_invite_token(
                 );
             }
 
+            if data.is_paused() {
+                let wrapped_json: Value = serde_json::json!({
+                    "ok": false,
+                    "error": "Invite token is paused"
+                });
+
+                return (
+                    StatusCode::FORBIDDEN,
+                    headers,
+                    serde_json::to_string(&wrapped_json).unwrap(),
+                );
+            }
+
             // Create user in Komga
-            match create_user_in(&state, &data, &request).await {
-                Ok(target_host) => {
+            match redeem_invite(&state, &data, request).await {
+                Ok(redemption) => {
                     // wrap the json in a {"ok": true, "data": {}} object
                     let wrapped_json: Value = serde_json::json!({
                         "ok": true,
-                        "data": {
-                            "host": target_host,
-                        }
+                        "data": redemption,
                     });
                     (
                         StatusCode::OK,
@@ -356,12 +878,17 @@ pub async fn apply_invite_token(
                 }
                 Err(e) => {
                     error!("Failed to create user in Komga: {}", e);
+                    let status = match e {
+                        UserCreationError::MissingCredentials
+                        | UserCreationError::EmailMismatch => StatusCode::BAD_REQUEST,
+                        _ => StatusCode::INTERNAL_SERVER_ERROR,
+                    };
                     let wrapped_json: Value = serde_json::json!({
                         "ok": false,
                         "error": format!("Failed to create user: {}", e)
                     });
                     (
-                        StatusCode::INTERNAL_SERVER_ERROR,
+                        status,
                         headers,
                         serde_json::to_string(&wrapped_json).unwrap(),
                     )
@@ -397,17 +924,49 @@ pub async fn apply_invite_token(
     }
 }
 
-pub async fn get_all_invite_token(State(state): State<AppState>) -> impl IntoResponse {
-    let tokens = state.db.get_all_invites().await;
+pub async fn get_all_invite_token(
+    State(state): State<AppState>,
+    Query(filter): Query<InviteFilter>,
+) -> impl IntoResponse {
     let mut headers = HeaderMap::new();
     headers.insert("Content-Type", "application/json".parse().unwrap());
 
-    match tokens {
-        Ok(tokens) => {
+    if filter
+        .per_page
+        .is_some_and(|per_page| per_page == 0 || per_page > MAX_INVITES_PER_PAGE)
+    {
+        let wrapped_json: Value = serde_json::json!({
+            "ok": false,
+            "error": format!("perPage must be between 1 and {}", MAX_INVITES_PER_PAGE)
+        });
+
+        return (
+            StatusCode::BAD_REQUEST,
+            headers,
+            serde_json::to_string(&wrapped_json).unwrap(),
+        );
+    }
+
+    if filter.offset().is_none() {
+        let wrapped_json: Value = serde_json::json!({
+            "ok": false,
+            "error": "page is too large"
+        });
+
+        return (
+            StatusCode::BAD_REQUEST,
+            headers,
+            serde_json::to_string(&wrapped_json).unwrap(),
+        );
+    }
+
+    match state.db.search_invites(&filter).await {
+        Ok((tokens, total)) => {
             // wrap the json in a {"ok": true, "data": {}} object
             let wrapped_json: Value = serde_json::json!({
                 "ok": true,
                 "data": tokens,
+                "total": total,
             });
 
             (
@@ -460,11 +1019,79 @@ pub fn invite_routes(state: AppState) -> Router<AppState> {
         )
         .route(
             "/{token}",
-            axum::routing::get(get_invite_token).delete(delete_invite_token),
+            axum::routing::get(get_invite_token)
+                .patch(update_invite_token)
+                .delete(delete_invite_token),
         )
+        .route("/bulk", axum::routing::post(create_bulk_invite_tokens))
         .route("/{token}/apply", axum::routing::post(apply_invite_token))
         .route("/config", axum::routing::get(get_invite_config))
         .route("/info", axum::routing::get(get_info))
         .with_state(state.clone())
         .layer(axum::middleware::from_fn_with_state(state, auth_middleware))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn shows_the_invitee_only_the_public_view() {
+        let state = crate::testing::state("http://127.0.0.1:1").await;
+        let invite = InviteToken::create_navidrome(NavidromeInviteOption {
+            is_admin: false,
+            expire_at: None,
+            library_ids: vec![],
+            access_duration: None,
+            access_expiry_action: Default::default(),
+            profile_id: None,
+            user_id: None,
+            generate_credentials: false,
+        })
+        .with_note_and_email(
+            Some("for the book club".to_string()),
+            Some("reader@example.com".to_string()),
+        )
+        .with_tags(vec!["club".to_string()])
+        .with_creator(Some("admin".to_string()));
+        state.db.add_invite(&invite).await.unwrap();
+
+        let response = get_invite_token(State(state), Path(invite.token()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let json: Value = serde_json::from_slice(&body).unwrap();
+        let data = json["data"].as_object().unwrap();
+        let mut keys = data.keys().map(String::as_str).collect::<Vec<_>>();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            [
+                "emailRequired",
+                "expiresAt",
+                "handover",
+                "kind",
+                "paused",
+                "token"
+            ]
+        );
+        assert_eq!(data["emailRequired"], true);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_page_past_the_largest_offset() {
+        let state = crate::testing::state("http://127.0.0.1:1").await;
+        let filter: InviteFilter =
+            serde_json::from_value(serde_json::json!({ "page": u64::MAX, "perPage": 200 }))
+                .unwrap();
+
+        let response = get_all_invite_token(State(state), Query(filter))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
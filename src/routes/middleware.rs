@@ -1,63 +1,595 @@
+use std::{net::SocketAddr, time::Duration};
+
 use axum::{
     Json,
-    extract::{Request, State},
-    http::{HeaderMap, StatusCode},
+    extract::{ConnectInfo, OriginalUri, Request, State},
+    http::{
+        HeaderMap, HeaderValue, Method, StatusCode,
+        header::{AUTHORIZATION, RETRY_AFTER},
+    },
     middleware::Next,
     response::{IntoResponse, Response},
 };
 
-use crate::AppState;
-
-pub async fn auth_middleware(State(state): State<AppState>, req: Request, next: Next) -> Response {
-    let token = state.config.token.clone();
-    let headers: &HeaderMap = req.headers();
-
-    if let Some(auth_header) = headers.get(axum::http::header::AUTHORIZATION) {
-        if let Ok(auth_value) = auth_header.to_str() {
-            // Check if starts with "Bearer "
-            if let Some(token_value) = auth_value.strip_prefix("Bearer ") {
-                // Skip "Bearer "
-                if token_value == token {
-                    next.run(req).await
-                } else {
-                    (
+use crate::{
+    AppState,
+    auth::{
+        API_KEY_PREFIX, CSRF_HEADER, SESSION_COOKIE, SESSION_IDLE_TIMEOUT, cookie_value,
+        hash_token, proxy_csrf_token, role_for_groups, verify_shared_token,
+    },
+    database::{AdminRole, ApiKeyScope, Session},
+    ratelimit::{FailedAttempt, client_ip},
+};
+
+/// Who is doing the request, available to the handlers behind `auth_middleware`
+#[derive(Clone, Debug)]
+pub struct AdminIdentity {
+    /// Empty when using the shared token from the config
+    pub id: Option<uuid::Uuid>,
+    pub username: Option<String>,
+    pub role: AdminRole,
+    /// Only set for API keys, which are limited to these scopes instead of the role
+    pub scopes: Option<Vec<ApiKeyScope>>,
+}
+
+/// A bearer token the rate limiter already found to be valid, so it is not looked up twice
+#[derive(Clone)]
+struct VerifiedBearer(AdminIdentity);
+
+/// Set for requests logged in by the reverse proxy, which have no session to keep a CSRF token in
+#[derive(Clone, Debug)]
+pub struct ProxyLogin {
+    pub csrf_token: String,
+}
+
+impl AdminIdentity {
+    fn shared_token() -> Self {
+        Self {
+            id: None,
+            username: None,
+            role: AdminRole::Owner,
+            scopes: None,
+        }
+    }
+
+    /// Whether the options, note and tags of invites can be changed, the `revoke` scope alone
+    /// only allows pausing them
+    pub fn can_edit_invites(&self) -> bool {
+        self.scopes
+            .as_ref()
+            .is_none_or(|scopes| scopes.contains(&ApiKeyScope::CreateInvite))
+    }
+
+    /// Whether the role of this admin allows the request
+    fn allows(&self, method: &Method, path: &str) -> bool {
+        let path = path.trim_end_matches('/');
+
+        if let Some(scopes) = &self.scopes {
+            return scopes
+                .iter()
+                .any(|scope| scope_allows(*scope, method, path))
+                || path == "/api/auth/me";
+        }
+
+        match self.role {
+            AdminRole::Owner => true,
+            // everyone can look at and end their own session
+            _ if path.starts_with("/api/auth") => true,
+            // the admin accounts are only visible to the owners
+            _ if path.starts_with("/api/admin") => false,
+            // the users and profiles are only visible to the owners
+            _ if !(path == "/api/invite" || path.starts_with("/api/invite/")) => false,
+            AdminRole::Inviter => {
+                method == Method::GET
+                    || (method == Method::POST
+                        && matches!(path, "/api/invite" | "/api/invite/bulk"))
+            }
+            AdminRole::Viewer => method == Method::GET,
+        }
+    }
+}
+
+/// Whether an API key scope allows the request, keys can never manage the admins or other keys
+fn scope_allows(scope: ApiKeyScope, method: &Method, path: &str) -> bool {
+    let is_invite = path == "/api/invite" || path.starts_with("/api/invite/");
+    let is_invite_token = is_invite_token_path(path) && !path.ends_with("/apply");
+
+    match scope {
+        ApiKeyScope::CreateInvite => {
+            (method == Method::POST && matches!(path, "/api/invite" | "/api/invite/bulk"))
+                || (method == Method::PATCH && is_invite_token)
+        }
+        ApiKeyScope::ReadInvites => method == Method::GET && is_invite,
+        ApiKeyScope::Revoke => {
+            (method == Method::DELETE || method == Method::PATCH) && is_invite_token
+        }
+        ApiKeyScope::Config => {
+            (method == Method::GET && matches!(path, "/api/invite/config" | "/api/invite/info"))
+                || path == "/api/profile"
+                || path.starts_with("/api/profile/")
+        }
+    }
+}
+
+/// Whether the path is about a single invite token, including applying it
+fn is_invite_token_path(path: &str) -> bool {
+    path.strip_prefix("/api/invite/")
+        .and_then(|rest| rest.split('/').next())
+        .is_some_and(|token| !matches!(token, "" | "bulk" | "config" | "info"))
+}
+
+fn unauthorized(status: StatusCode, error: &str) -> Response {
+    (
+        status,
+        Json(serde_json::json!({
+            "ok": false,
+            "error": error
+        })),
+    )
+        .into_response()
+}
+
+/// Find the admin behind an API key, and remember when the key was last used
+async fn resolve_api_key(
+    state: &AppState,
+    token_value: &str,
+) -> Result<Option<AdminIdentity>, Response> {
+    let key = match state.db.get_api_key_by_hash(&hash_token(token_value)).await {
+        Ok(Some(key)) => key,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            tracing::error!("Failed to get API key: {}", e);
+            return Err(unauthorized(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to check API key",
+            ));
+        }
+    };
+
+    if let Err(e) = state.db.touch_api_key(key.id).await {
+        tracing::error!("Failed to update last use of API key {}: {}", key.id, e);
+    }
+
+    Ok(Some(AdminIdentity {
+        id: None,
+        username: Some(format!("key:{}", key.name)),
+        role: AdminRole::Viewer,
+        scopes: Some(key.scopes),
+    }))
+}
+
+/// Find the admin behind a bearer token, either the shared token or an API key
+pub(super) async fn resolve_identity(
+    state: &AppState,
+    token_value: &str,
+) -> Result<Option<AdminIdentity>, Response> {
+    if verify_shared_token(token_value, &state.config.token) {
+        // a bearer token cannot carry a two-factor code, scripts have API keys for that
+        if state.config.token_totp_secret.is_some() {
+            return Err(unauthorized(
+                StatusCode::UNAUTHORIZED,
+                "The admin token needs a two-factor code, log in to the admin panel or use an API key",
+            ));
+        }
+
+        return Ok(Some(AdminIdentity::shared_token()));
+    }
+
+    if token_value.starts_with(API_KEY_PREFIX) {
+        return resolve_api_key(state, token_value).await;
+    }
+
+    Ok(None)
+}
+
+/// Find the admin named by the reverse proxy, when proxy auth is set up and the request comes from it.
+/// Returns the refusal for a user in none of the admin groups, which only applies when the request
+/// is not authenticated some other way.
+async fn resolve_proxy_user(
+    state: &AppState,
+    peer: Option<SocketAddr>,
+    headers: &HeaderMap,
+) -> Result<Option<AdminIdentity>, Response> {
+    let Some(proxy_auth) = &state.config.proxy_auth else {
+        return Ok(None);
+    };
+    // anyone can set these headers, so only listen to the proxy
+    if !peer.is_some_and(|peer| proxy_auth.is_trusted(peer.ip())) {
+        return Ok(None);
+    }
+
+    let Some(username) = headers
+        .get(proxy_auth.user_header.as_str())
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|username| !username.is_empty())
+    else {
+        return Ok(None);
+    };
+    let groups: Vec<String> = headers
+        .get_all(proxy_auth.groups_header.as_str())
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|group| group.trim().to_string())
+        .filter(|group| !group.is_empty())
+        .collect();
+
+    let Some(role) = role_for_groups(&proxy_auth.roles, &groups) else {
+        tracing::warn!(
+            "{} was logged in by the reverse proxy but is not in any admin group",
+            username
+        );
+        return Err(unauthorized(
+            StatusCode::FORBIDDEN,
+            "Your account is not allowed to use the admin panel",
+        ));
+    };
+
+    Ok(Some(AdminIdentity {
+        id: None,
+        username: Some(username.to_string()),
+        role,
+        scopes: None,
+    }))
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// Find the admin behind a session cookie
+async fn resolve_session(
+    state: &AppState,
+    cookie: &str,
+) -> Result<Option<(AdminIdentity, Session)>, Response> {
+    let session = match state
+        .db
+        .get_session(&hash_token(cookie), SESSION_IDLE_TIMEOUT)
+        .await
+    {
+        Ok(Some(session)) => session,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            tracing::error!("Failed to get session: {}", e);
+            return Err(unauthorized(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to check session",
+            ));
+        }
+    };
+
+    let Some(admin_id) = session.admin_id else {
+        return Ok(Some((AdminIdentity::shared_token(), session)));
+    };
+
+    match state.db.get_admin(admin_id).await {
+        Ok(admin) => Ok(admin.map(|admin| {
+            (
+                AdminIdentity {
+                    id: Some(admin.id),
+                    username: Some(admin.username),
+                    role: admin.role,
+                    scopes: None,
+                },
+                session,
+            )
+        })),
+        Err(e) => {
+            tracing::error!("Failed to get admin {}: {}", admin_id, e);
+            Err(unauthorized(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to check session",
+            ))
+        }
+    }
+}
+
+/// Accepts either a bearer token, for scripts, the user given by a trusted reverse proxy,
+/// or the session cookie of the admin panel.
+///
+/// Requests with the cookie or through the proxy that change something also need the CSRF token
+/// in the `X-CSRF-Token` header, which another site cannot read.
+pub async fn auth_middleware(
+    State(state): State<AppState>,
+    OriginalUri(original_uri): OriginalUri,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| *peer);
+    // a proxy user without an admin role can still log in with a token or a session
+    let (proxy_user, proxy_refusal) = match resolve_proxy_user(&state, peer, req.headers()).await {
+        Ok(identity) => (identity, None),
+        Err(response) => (None, Some(response)),
+    };
+
+    let verified = req
+        .extensions_mut()
+        .remove::<VerifiedBearer>()
+        .map(|VerifiedBearer(identity)| identity);
+
+    let identity = if let Some(auth_header) = req.headers().get(AUTHORIZATION) {
+        let Ok(auth_value) = auth_header.to_str() else {
+            return unauthorized(
+                StatusCode::UNAUTHORIZED,
+                "Invalid authorization header format",
+            );
+        };
+        // Check if starts with "Bearer "
+        let Some(token_value) = auth_value.strip_prefix("Bearer ") else {
+            // reject if it doesn't start with "Bearer "
+            return unauthorized(
+                StatusCode::UNAUTHORIZED,
+                "Invalid authorization format. Expected 'Bearer <token>'",
+            );
+        };
+
+        let resolved = match verified {
+            Some(identity) => Ok(Some(identity)),
+            None => resolve_identity(&state, token_value).await,
+        };
+
+        match resolved {
+            Ok(Some(identity)) => identity,
+            Ok(None) => {
+                let mut response = unauthorized(StatusCode::UNAUTHORIZED, "Invalid token");
+                response.extensions_mut().insert(FailedAttempt);
+                return response;
+            }
+            Err(response) => return response,
+        }
+    } else if let Some(identity) = proxy_user {
+        let csrf_token = proxy_csrf_token(identity.username.as_deref().unwrap_or_default());
+        if !is_safe_method(req.method())
+            && req
+                .headers()
+                .get(CSRF_HEADER)
+                .and_then(|value| value.to_str().ok())
+                != Some(&csrf_token)
+        {
+            return unauthorized(StatusCode::FORBIDDEN, "Missing or invalid CSRF token");
+        }
+
+        req.extensions_mut().insert(ProxyLogin { csrf_token });
+        identity
+    } else if let Some(cookie) = cookie_value(req.headers(), SESSION_COOKIE) {
+        let (identity, session) = match resolve_session(&state, cookie).await {
+            Ok(Some(found)) => found,
+            Ok(None) => {
+                return proxy_refusal.unwrap_or_else(|| {
+                    unauthorized(
                         StatusCode::UNAUTHORIZED,
-                        Json(serde_json::json!({
-                            "ok": false,
-                            "error": "Invalid token"
-                        })),
+                        "Your session has expired, please log in again",
                     )
-                        .into_response()
-                }
-            } else {
-                // reject if it doesn't start with "Bearer "
-                (
-                    StatusCode::UNAUTHORIZED,
-                    Json(serde_json::json!({
-                        "ok": false,
-                        "error": "Invalid authorization format. Expected 'Bearer <token>'"
-                    })),
-                )
-                    .into_response()
+                });
             }
-        } else {
-            (
+            Err(response) => return response,
+        };
+
+        let csrf_token = req
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok());
+        if !is_safe_method(req.method())
+            && (session.csrf_token.is_empty() || csrf_token != Some(&session.csrf_token))
+        {
+            return unauthorized(StatusCode::FORBIDDEN, "Missing or invalid CSRF token");
+        }
+
+        req.extensions_mut().insert(session);
+        identity
+    } else {
+        return proxy_refusal.unwrap_or_else(|| {
+            unauthorized(
                 StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({
-                    "ok": false,
-                    "error": "Invalid authorization header format"
-                })),
+                "Unauthorized access. No authorization header provided.",
+            )
+        });
+    };
+
+    if !identity.allows(req.method(), original_uri.path()) {
+        return unauthorized(
+            StatusCode::FORBIDDEN,
+            "Your role does not allow this action",
+        );
+    }
+
+    req.extensions_mut().insert(identity);
+    next.run(req).await
+}
+
+/// Whether the request tries a credential or an invite token, either of which could be guessed
+fn is_guess(method: &Method, path: &str, headers: &HeaderMap) -> bool {
+    headers.contains_key(AUTHORIZATION)
+        || (method == Method::POST
+            && matches!(path, "/api/auth/login" | "/api/auth/passkeys/login/finish"))
+        || is_invite_token_path(path)
+}
+
+/// Refuses guesses of credentials and invite tokens from clients that failed too often.
+///
+/// Responses marked with [`FailedAttempt`] count as a failure, and so does an unknown invite token.
+/// Other requests are never limited, so a lockout leaves the admins with a session cookie alone.
+pub async fn rate_limit_middleware(
+    State(state): State<AppState>,
+    OriginalUri(original_uri): OriginalUri,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let path = original_uri.path().trim_end_matches('/');
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(peer)| peer.ip());
+
+    let Some(peer) =
+        peer.filter(|_| state.limiter.is_enabled() && is_guess(req.method(), path, req.headers()))
+    else {
+        return next.run(req).await;
+    };
+
+    let ip = client_ip(&state.config, peer, req.headers());
+    if let Some(retry_after) = state.limiter.locked_out(ip) {
+        return too_many_attempts(retry_after);
+    }
+
+    // a valid token is no guess, it should not wait for the guesses in flight to be answered
+    let bearer = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if let Some(token_value) = bearer
+        && let Ok(Some(identity)) = resolve_identity(&state, token_value).await
+    {
+        req.extensions_mut().insert(VerifiedBearer(identity));
+        return next.run(req).await;
+    }
+
+    let reservation = match state.limiter.reserve(ip) {
+        Ok(reservation) => reservation,
+        Err(retry_after) => return too_many_attempts(retry_after),
+    };
+
+    let is_invite_token = is_invite_token_path(path);
+    let response = next.run(req).await;
+
+    if response.extensions().get::<FailedAttempt>().is_some()
+        || (is_invite_token && response.status() == StatusCode::NOT_FOUND)
+    {
+        reservation.fail();
+    }
+
+    response
+}
+
+fn too_many_attempts(retry_after: Duration) -> Response {
+    // round up, retrying a bit too early would only be refused again
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    let mut response = unauthorized(
+        StatusCode::TOO_MANY_REQUESTS,
+        &format!("Too many failed attempts, try again in {seconds} seconds"),
+    );
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::database::Admin;
+
+    use super::*;
+
+    /// Serve the API like the server does, returning its URL
+    async fn serve(state: AppState) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let router = axum::Router::new()
+            .nest("/api", crate::routes::api(state.clone()))
+            .with_state(state);
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                router.into_make_service_with_connect_info::<SocketAddr>(),
             )
-                .into_response()
+            .await
+        });
+
+        url
+    }
+
+    /// Log in as a new admin with the given role, returning its session cookie
+    async fn log_in_as(state: &AppState, role: AdminRole) -> String {
+        let admin = Admin {
+            id: uuid::Uuid::new_v4(),
+            username: "someone".to_string(),
+            role,
+            password_hash: String::new(),
+            created_at: None,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_step: None,
+            recovery_codes: Vec::new(),
+            oidc_subject: None,
+        };
+        state.db.add_admin(&admin).await.unwrap();
+        state
+            .db
+            .add_session(&hash_token("cookie"), Some(admin.id), "csrf", u64::MAX / 2)
+            .await
+            .unwrap();
+
+        format!("{SESSION_COOKIE}=cookie")
+    }
+
+    #[tokio::test]
+    async fn keeps_viewers_to_the_invites() {
+        let state = crate::testing::state("http://127.0.0.1:1").await;
+        let cookie = log_in_as(&state, AdminRole::Viewer).await;
+        let url = serve(state).await;
+        let client = reqwest::Client::new();
+
+        for path in ["/api/user", "/api/profile", "/api/invite"] {
+            let res = client
+                .get(format!("{url}{path}"))
+                .header("Cookie", &cookie)
+                .send()
+                .await
+                .unwrap();
+            let expected = if path == "/api/invite" {
+                StatusCode::OK
+            } else {
+                StatusCode::FORBIDDEN
+            };
+            assert_eq!(res.status().as_u16(), expected.as_u16(), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_the_shared_bearer_token_once_it_needs_a_code() {
+        let mut state = crate::testing::state("http://127.0.0.1:1").await;
+        state.config = std::sync::Arc::new(crate::config::Config {
+            token_totp_secret: Some(crate::auth::generate_totp_secret()),
+            ..(*state.config).clone()
+        });
+        let url = serve(state).await;
+
+        let res = reqwest::Client::new()
+            .get(format!("{url}/api/invite"))
+            .bearer_auth("test-token")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status().as_u16(), StatusCode::UNAUTHORIZED.as_u16());
+    }
+
+    #[tokio::test]
+    async fn valid_tokens_do_not_wait_for_guesses_in_flight() {
+        let state = crate::testing::state("http://127.0.0.1:1").await;
+        let limiter = state.limiter.clone();
+        let url = serve(state).await;
+        let client = reqwest::Client::new();
+
+        // as many guesses in flight as the client has attempts
+        let ip = std::net::IpAddr::from([127, 0, 0, 1]);
+        let _in_flight: Vec<_> = (0..5).map(|_| limiter.reserve(ip).unwrap()).collect();
+
+        for (token, expected) in [
+            ("test-token", StatusCode::OK),
+            ("wrong-token", StatusCode::TOO_MANY_REQUESTS),
+        ] {
+            let res = client
+                .get(format!("{url}/api/invite"))
+                .bearer_auth(token)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status().as_u16(), expected.as_u16(), "{token}");
         }
-    } else {
-        (
-            StatusCode::UNAUTHORIZED,
-            Json(serde_json::json!({
-                "ok": false,
-                "error": "Unauthorized access. No authorization header provided."
-            })),
-        )
-            .into_response()
     }
 }
@@ -2,13 +2,23 @@ use axum::Router;
 
 use crate::AppState;
 
+pub mod admin;
 pub mod auth;
 pub mod invite;
 pub(super) mod middleware;
+pub mod profile;
+pub mod user;
 
 pub fn api(state: AppState) -> Router<AppState> {
     Router::new()
+        .nest("/admin", admin::admin_routes(state.clone()))
         .nest("/auth", auth::auth_routes(state.clone()))
         .nest("/invite", invite::invite_routes(state.clone()))
+        .nest("/profile", profile::profile_routes(state.clone()))
+        .nest("/user", user::user_routes(state.clone()))
+        .layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            middleware::rate_limit_middleware,
+        ))
         .with_state(state.clone())
 }
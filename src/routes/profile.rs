@@ -0,0 +1,353 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use tracing::{error, info};
+
+use crate::{
+    AppState,
+    database::{KomgaProfileOption, NavidromeProfileOption, RestrictionProfile},
+    routes::middleware::auth_middleware,
+};
+
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "kind")]
+pub enum ProfileRequestParams {
+    #[serde(rename = "komga")]
+    Komga {
+        name: String,
+        option: KomgaProfileOption,
+    },
+    #[serde(rename = "navidrome")]
+    Navidrome {
+        name: String,
+        option: NavidromeProfileOption,
+    },
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ProfileUpdateParams {
+    name: Option<String>,
+    /// The new restrictions, the shape depends on the kind of the profile
+    option: Option<serde_json::Value>,
+}
+
+#[derive(serde::Serialize)]
+pub struct ProfilePushFailure {
+    id: String,
+    error: String,
+}
+
+/// What happened to the users of a profile when its restrictions were pushed to them
+#[derive(Default)]
+pub struct ProfilePush {
+    applied: usize,
+    /// Users left as they are, since the profile has nothing to apply to them
+    skipped: usize,
+    failed: Vec<ProfilePushFailure>,
+}
+
+fn error_response(status: StatusCode, error: String) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        status,
+        Json(serde_json::json!({
+            "ok": false,
+            "error": error
+        })),
+    )
+}
+
+/// Apply the profile restrictions to every user that was created from it
+async fn push_profile_to_users(
+    state: &AppState,
+    profile: &RestrictionProfile,
+) -> Result<ProfilePush, crate::database::LocalDatabaseError> {
+    let users = state.db.get_users_by_profile(profile.id()).await?;
+
+    let mut push = ProfilePush::default();
+    for user in users {
+        // like on invites, no libraries means the user keeps the ones Navidrome gave them
+        if let RestrictionProfile::Navidrome { option, .. } = profile
+            && option.library_ids.is_empty()
+        {
+            push.skipped += 1;
+            continue;
+        }
+
+        let result = match profile {
+            RestrictionProfile::Komga { option, .. } => state
+                .komga
+                .apply_user_restriction(&user.id, &option.clone().into())
+                .await
+                .map_err(|e| e.to_string()),
+            RestrictionProfile::Navidrome { option, .. } => match &state.navidrome {
+                Some(navidrome) => {
+                    let mut client = navidrome.lock().await;
+                    client
+                        .apply_user_library(&user.id, &option.clone().into())
+                        .await
+                        .map_err(|e| e.to_string())
+                }
+                None => Err("Navidrome is not configured".to_string()),
+            },
+        };
+
+        // keep the registry in sync with what the user has been granted now
+        let result = match result {
+            Ok(_) => {
+                let restrictions = match profile {
+                    RestrictionProfile::Komga { option, .. } => {
+                        serde_json::to_value(option).unwrap()
+                    }
+                    RestrictionProfile::Navidrome { option, .. } => {
+                        let mut restrictions = user.restrictions.clone().unwrap_or_default();
+                        restrictions["libraryIds"] = serde_json::json!(option.library_ids);
+                        restrictions
+                    }
+                };
+
+                state
+                    .db
+                    .set_user_restrictions(&user.kind, &user.id, &restrictions)
+                    .await
+                    .map_err(|e| e.to_string())
+            }
+            Err(error) => Err(error),
+        };
+
+        match result {
+            Ok(_) => push.applied += 1,
+            Err(error) => {
+                error!(
+                    "[{} / {}] Failed to apply profile {}: {}",
+                    user.kind,
+                    user.id,
+                    profile.id(),
+                    error
+                );
+                push.failed.push(ProfilePushFailure { id: user.id, error });
+            }
+        }
+    }
+
+    Ok(push)
+}
+
+pub async fn get_all_profiles(State(state): State<AppState>) -> impl IntoResponse {
+    match state.db.get_all_profiles().await {
+        Ok(profiles) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": profiles,
+            })),
+        ),
+        Err(e) => {
+            error!("Failed to get restriction profiles: {}", e);
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to get restriction profiles: {}", e),
+            )
+        }
+    }
+}
+
+pub async fn create_profile(
+    State(state): State<AppState>,
+    Json(params): Json<ProfileRequestParams>,
+) -> impl IntoResponse {
+    let profile = match params {
+        ProfileRequestParams::Komga { name, option } => RestrictionProfile::Komga {
+            id: uuid::Uuid::new_v4(),
+            name,
+            option,
+        },
+        ProfileRequestParams::Navidrome { name, option } => RestrictionProfile::Navidrome {
+            id: uuid::Uuid::new_v4(),
+            name,
+            option,
+        },
+    };
+
+    if profile.name().trim().is_empty() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "Profile name cannot be empty".to_string(),
+        );
+    }
+
+    match state.db.add_profile(&profile).await {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": profile,
+            })),
+        ),
+        Err(e) => {
+            error!("Failed to create restriction profile: {}", e);
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to create restriction profile: {}", e),
+            )
+        }
+    }
+}
+
+pub async fn get_profile(
+    State(state): State<AppState>,
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    match state.db.get_profile(id).await {
+        Ok(Some(profile)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": profile,
+            })),
+        ),
+        Ok(None) => error_response(
+            StatusCode::NOT_FOUND,
+            "Restriction profile not found".to_string(),
+        ),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to get restriction profile: {}", e),
+        ),
+    }
+}
+
+pub async fn update_profile(
+    State(state): State<AppState>,
+    Path(id): Path<uuid::Uuid>,
+    Json(params): Json<ProfileUpdateParams>,
+) -> impl IntoResponse {
+    let mut profile = match state.db.get_profile(id).await {
+        Ok(Some(profile)) => profile,
+        Ok(None) => {
+            return error_response(
+                StatusCode::NOT_FOUND,
+                "Restriction profile not found".to_string(),
+            );
+        }
+        Err(e) => {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to get restriction profile: {}", e),
+            );
+        }
+    };
+
+    let option_result = match (&mut profile, params.option) {
+        (RestrictionProfile::Komga { option, .. }, Some(new_option)) => {
+            serde_json::from_value(new_option).map(|new_option| *option = new_option)
+        }
+        (RestrictionProfile::Navidrome { option, .. }, Some(new_option)) => {
+            serde_json::from_value(new_option).map(|new_option| *option = new_option)
+        }
+        (_, None) => Ok(()),
+    };
+    if let Err(e) = option_result {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid restriction profile option: {}", e),
+        );
+    }
+
+    if let Some(new_name) = params.name {
+        if new_name.trim().is_empty() {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "Profile name cannot be empty".to_string(),
+            );
+        }
+
+        match &mut profile {
+            RestrictionProfile::Komga { name, .. } => *name = new_name,
+            RestrictionProfile::Navidrome { name, .. } => *name = new_name,
+        }
+    }
+
+    if let Err(e) = state.db.update_profile(&profile).await {
+        error!("[{}] Failed to update restriction profile: {}", id, e);
+        return error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to update restriction profile: {}", e),
+        );
+    }
+
+    info!("[{}] Pushing restriction profile to existing users", id);
+    match push_profile_to_users(&state, &profile).await {
+        Ok(push) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": {
+                    "profile": profile,
+                    "applied": push.applied,
+                    "skipped": push.skipped,
+                    "failed": push.failed,
+                }
+            })),
+        ),
+        Err(e) => {
+            error!(
+                "[{}] Failed to get users for restriction profile: {}",
+                id, e
+            );
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Profile updated, but failed to apply to users: {}", e),
+            )
+        }
+    }
+}
+
+pub async fn delete_profile(
+    State(state): State<AppState>,
+    Path(id): Path<uuid::Uuid>,
+) -> impl IntoResponse {
+    // pending invites would fail to redeem without their profile
+    match state.db.get_all_invites().await {
+        Ok(invites) => {
+            if invites.iter().any(|invite| invite.profile_id() == Some(id)) {
+                return error_response(
+                    StatusCode::CONFLICT,
+                    "Restriction profile is still used by an invite".to_string(),
+                );
+            }
+        }
+        Err(e) => {
+            return error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to get invite tokens: {}", e),
+            );
+        }
+    }
+
+    match state.db.delete_profile(id).await {
+        Ok(_) => (StatusCode::OK, Json(serde_json::json!({ "ok": true }))),
+        Err(e) => error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to delete restriction profile: {}", e),
+        ),
+    }
+}
+
+pub fn profile_routes(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route(
+            "/",
+            axum::routing::get(get_all_profiles).post(create_profile),
+        )
+        .route(
+            "/{id}",
+            axum::routing::get(get_profile)
+                .patch(update_profile)
+                .delete(delete_profile),
+        )
+        .with_state(state.clone())
+        .layer(axum::middleware::from_fn_with_state(state, auth_middleware))
+}
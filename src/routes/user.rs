@@ -0,0 +1,632 @@
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use garde::Validate;
+use tracing::{error, info};
+
+use crate::{
+    AppState,
+    database::{ProvisionedUser, unix_now},
+    komga::KomgaError,
+    navidrome::{NavidromeClient, NavidromeUserUpdate},
+    routes::middleware::auth_middleware,
+};
+
+#[derive(serde::Serialize, serde::Deserialize, garde::Validate)]
+pub struct NavidromeUserUpdatePayload {
+    #[garde(skip)]
+    name: Option<String>,
+    #[garde(email)]
+    email: Option<String>,
+    #[serde(rename = "isAdmin")]
+    #[garde(skip)]
+    is_admin: Option<bool>,
+    #[garde(length(min = 6))]
+    password: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExtendAccessPayload {
+    /// Extend the access by this many seconds
+    duration: Option<u64>,
+    /// Or set the access expiry to this exact unix timestamp
+    #[serde(rename = "expiresAt")]
+    expires_at: Option<u64>,
+}
+
+impl ExtendAccessPayload {
+    fn new_expiry(&self, user: &ProvisionedUser) -> Option<u64> {
+        match (self.expires_at, self.duration) {
+            (Some(expires_at), _) => Some(expires_at),
+            (None, Some(duration)) => {
+                // extend from whichever is later, so extending an active user adds to the remaining time
+                let base = user.access_expires_at.unwrap_or(0).max(unix_now());
+                Some(base + duration)
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+fn komga_error_response(action: &str, error: KomgaError) -> (StatusCode, Json<serde_json::Value>) {
+    let status = match error {
+        KomgaError::UserNotFound(_) => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+
+    (
+        status,
+        Json(serde_json::json!({
+            "ok": false,
+            "error": format!("Failed to {}: {}", action, error)
+        })),
+    )
+}
+
+/// Find a user in the registry, only the users k-librarian knows about can be offboarded here
+async fn registered_user(
+    state: &AppState,
+    kind: &str,
+    user_id: &str,
+) -> Result<ProvisionedUser, (StatusCode, Json<serde_json::Value>)> {
+    match state.db.get_user(kind, user_id).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err((
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({
+                "ok": false,
+                "error": "User not found"
+            })),
+        )),
+        Err(e) => {
+            error!("[{} / {}] Failed to get user: {}", kind, user_id, e);
+            Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "ok": false,
+                    "error": format!("Failed to get user: {}", e)
+                })),
+            ))
+        }
+    }
+}
+
+pub async fn revoke_komga_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    let user = match registered_user(&state, "komga", &user_id).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    info!("[{}] Revoking all library access for Komga user", user.id);
+    match state.komga.revoke_user_access(&user.id).await {
+        Ok(_) => {
+            // the access is gone, so the scheduler has nothing left to expire
+            if let Err(e) = state.db.mark_user_expired("komga", &user.id).await {
+                error!("[{}] Failed to mark Komga user as revoked: {}", user.id, e);
+            }
+            (StatusCode::OK, Json(serde_json::json!({ "ok": true })))
+        }
+        Err(e) => {
+            error!("[{}] Failed to revoke Komga user access: {}", user.id, e);
+            komga_error_response("revoke user access", e)
+        }
+    }
+}
+
+pub async fn delete_komga_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    let user = match registered_user(&state, "komga", &user_id).await {
+        Ok(user) => user,
+        Err(response) => return response,
+    };
+
+    info!("[{}] Deleting Komga user", user.id);
+    match state.komga.delete_user(&user.id).await {
+        Ok(_) => {
+            if let Err(e) = state.db.delete_user("komga", &user.id).await {
+                error!(
+                    "[{}] Failed to remove Komga user from registry: {}",
+                    user.id, e
+                );
+            }
+            (StatusCode::OK, Json(serde_json::json!({ "ok": true })))
+        }
+        Err(e) => {
+            error!("[{}] Failed to delete Komga user: {}", user.id, e);
+            komga_error_response("delete user", e)
+        }
+    }
+}
+
+fn navidrome_client(
+    state: &AppState,
+) -> Result<
+    &std::sync::Arc<tokio::sync::Mutex<NavidromeClient>>,
+    (StatusCode, Json<serde_json::Value>),
+> {
+    state.navidrome.as_ref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        Json(serde_json::json!({
+            "ok": false,
+            "error": "Navidrome is not configured"
+        })),
+    ))
+}
+
+/// Find a Navidrome user in the registry, refusing the account k-librarian logs in with
+async fn registered_navidrome_user(
+    state: &AppState,
+    user_id: &str,
+) -> Result<ProvisionedUser, (StatusCode, Json<serde_json::Value>)> {
+    let user = registered_user(state, "navidrome", user_id).await?;
+
+    let service_username = state
+        .config
+        .navidrome
+        .as_ref()
+        .map(|config| config.username.as_str());
+    if user.username.is_some() && user.username.as_deref() == service_username {
+        return Err((
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({
+                "ok": false,
+                "error": "The account k-librarian uses cannot be managed here"
+            })),
+        ));
+    }
+
+    Ok(user)
+}
+
+fn navidrome_error_response(
+    action: &str,
+    error: impl std::fmt::Display,
+) -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(serde_json::json!({
+            "ok": false,
+            "error": format!("Failed to {}: {}", action, error)
+        })),
+    )
+}
+
+fn navidrome_user_not_found() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::NOT_FOUND,
+        Json(serde_json::json!({
+            "ok": false,
+            "error": "User not found"
+        })),
+    )
+}
+
+pub async fn get_navidrome_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(response) = registered_navidrome_user(&state, &user_id).await {
+        return response;
+    }
+
+    let navidrome = match navidrome_client(&state) {
+        Ok(navidrome) => navidrome,
+        Err(response) => return response,
+    };
+
+    let mut client = navidrome.lock().await;
+    match client.get_user(&user_id).await {
+        Ok(Some(user)) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": user,
+            })),
+        ),
+        Ok(None) => navidrome_user_not_found(),
+        Err(e) => {
+            error!("[{}] Failed to get Navidrome user: {}", user_id, e);
+            navidrome_error_response("get user", e)
+        }
+    }
+}
+
+pub async fn update_navidrome_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+    Json(payload): Json<NavidromeUserUpdatePayload>,
+) -> impl IntoResponse {
+    if let Err(e) = payload.validate() {
+        let mut format_err = String::new();
+        for (field, err) in e.iter() {
+            format_err.push_str(&format!("- {field}: {err}"));
+            format_err.push('\n');
+        }
+
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "ok": false,
+                "error": format!("Invalid request:\n{}", format_err)
+            })),
+        );
+    }
+
+    if let Err(response) = registered_navidrome_user(&state, &user_id).await {
+        return response;
+    }
+
+    let navidrome = match navidrome_client(&state) {
+        Ok(navidrome) => navidrome,
+        Err(response) => return response,
+    };
+
+    let mut client = navidrome.lock().await;
+    let mut user: NavidromeUserUpdate = match client.get_user(&user_id).await {
+        Ok(Some(user)) => user.into(),
+        Ok(None) => return navidrome_user_not_found(),
+        Err(e) => {
+            error!("[{}] Failed to get Navidrome user: {}", user_id, e);
+            return navidrome_error_response("get user", e);
+        }
+    };
+
+    if let Some(name) = payload.name {
+        user.name = name;
+    }
+    if let Some(email) = payload.email {
+        user.email = email;
+    }
+    if let Some(is_admin) = payload.is_admin {
+        user.is_admin = is_admin;
+    }
+    user.password = payload.password;
+
+    info!("[{}] Updating Navidrome user", user_id);
+    match client.update_user(&user).await {
+        Ok(updated) => {
+            drop(client); // release the lock early
+
+            // keep the registry in sync so the admin change is not reported as drift
+            if let Some(is_admin) = payload.is_admin
+                && let Ok(Some(registered)) = state.db.get_user("navidrome", &user_id).await
+            {
+                let mut restrictions = registered.restrictions.unwrap_or_default();
+                restrictions["isAdmin"] = serde_json::json!(is_admin);
+                if let Err(e) = state
+                    .db
+                    .set_user_restrictions("navidrome", &user_id, &restrictions)
+                    .await
+                {
+                    error!(
+                        "[{}] Failed to update registered restrictions: {}",
+                        user_id, e
+                    );
+                }
+            }
+
+            (
+                StatusCode::OK,
+                Json(serde_json::json!({
+                    "ok": true,
+                    "data": updated,
+                })),
+            )
+        }
+        Err(e) => {
+            error!("[{}] Failed to update Navidrome user: {}", user_id, e);
+            navidrome_error_response("update user", e)
+        }
+    }
+}
+
+pub async fn delete_navidrome_user(
+    State(state): State<AppState>,
+    Path(user_id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(response) = registered_navidrome_user(&state, &user_id).await {
+        return response;
+    }
+
+    let navidrome = match navidrome_client(&state) {
+        Ok(navidrome) => navidrome,
+        Err(response) => return response,
+    };
+
+    info!("[{}] Deleting Navidrome user", user_id);
+    let mut client = navidrome.lock().await;
+    match client.delete_user(&user_id).await {
+        Ok(_) => {
+            drop(client); // release the lock early
+            if let Err(e) = state.db.delete_user("navidrome", &user_id).await {
+                error!(
+                    "[{}] Failed to remove Navidrome user from registry: {}",
+                    user_id, e
+                );
+            }
+            (StatusCode::OK, Json(serde_json::json!({ "ok": true })))
+        }
+        Err(e) => {
+            error!("[{}] Failed to delete Navidrome user: {}", user_id, e);
+            navidrome_error_response("delete user", e)
+        }
+    }
+}
+
+pub async fn get_all_users(State(state): State<AppState>) -> impl IntoResponse {
+    match state.db.get_all_users().await {
+        Ok(users) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": users,
+            })),
+        ),
+        Err(e) => {
+            error!("Failed to get users: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "ok": false,
+                    "error": format!("Failed to get users: {}", e)
+                })),
+            )
+        }
+    }
+}
+
+pub async fn get_drifted_users(State(state): State<AppState>) -> impl IntoResponse {
+    match state.db.get_drifted_users().await {
+        Ok(users) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": users,
+            })),
+        ),
+        Err(e) => {
+            error!("Failed to get drifted users: {}", e);
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "ok": false,
+                    "error": format!("Failed to get drifted users: {}", e)
+                })),
+            )
+        }
+    }
+}
+
+pub async fn reconcile_users(State(state): State<AppState>) -> impl IntoResponse {
+    info!("Reconciling users on request");
+    match crate::reconcile::reconcile_users(&state).await {
+        Ok(summary) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": summary,
+            })),
+        ),
+        Err(e) => {
+            error!("Failed to reconcile users: {}", e);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(serde_json::json!({
+                    "ok": false,
+                    "error": format!("Failed to reconcile users: {}", e)
+                })),
+            )
+        }
+    }
+}
+
+pub async fn import_users(State(state): State<AppState>) -> impl IntoResponse {
+    info!("Importing existing users from upstream servers");
+    match crate::reconcile::import_users(&state).await {
+        Ok(summary) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": summary,
+            })),
+        ),
+        Err(e) => {
+            error!("Failed to import users: {}", e);
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(serde_json::json!({
+                    "ok": false,
+                    "error": format!("Failed to import users: {}", e)
+                })),
+            )
+        }
+    }
+}
+
+pub async fn extend_user_access(
+    State(state): State<AppState>,
+    Path((kind, user_id)): Path<(String, String)>,
+    Json(payload): Json<ExtendAccessPayload>,
+) -> impl IntoResponse {
+    let user = match state.db.get_user(&kind, &user_id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({
+                    "ok": false,
+                    "error": "User not found"
+                })),
+            );
+        }
+        Err(e) => {
+            error!("[{} / {}] Failed to get user: {}", kind, user_id, e);
+            return (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "ok": false,
+                    "error": format!("Failed to get user: {}", e)
+                })),
+            );
+        }
+    };
+
+    if user.is_access_expired() {
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "ok": false,
+                "error": "User access has already expired"
+            })),
+        );
+    }
+
+    let Some(new_expiry) = payload.new_expiry(&user) else {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(serde_json::json!({
+                "ok": false,
+                "error": "Either duration or expiresAt must be provided"
+            })),
+        );
+    };
+
+    info!(
+        "[{} / {}] Extending user access until: {}",
+        kind, user_id, new_expiry
+    );
+    match state
+        .db
+        .set_user_access_expiry(&kind, &user_id, Some(new_expiry))
+        .await
+    {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(serde_json::json!({
+                "ok": true,
+                "data": {
+                    "accessExpiresAt": new_expiry,
+                }
+            })),
+        ),
+        Err(e) => {
+            error!(
+                "[{} / {}] Failed to extend user access: {}",
+                kind, user_id, e
+            );
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({
+                    "ok": false,
+                    "error": format!("Failed to extend user access: {}", e)
+                })),
+            )
+        }
+    }
+}
+
+pub fn user_routes(state: AppState) -> Router<AppState> {
+    Router::new()
+        .route("/", axum::routing::get(get_all_users))
+        .route("/drift", axum::routing::get(get_drifted_users))
+        .route("/reconcile", axum::routing::post(reconcile_users))
+        .route("/import", axum::routing::post(import_users))
+        .route(
+            "/{kind}/{id}/extend",
+            axum::routing::post(extend_user_access),
+        )
+        .route("/komga/{id}", axum::routing::delete(delete_komga_user))
+        .route("/komga/{id}/revoke", axum::routing::post(revoke_komga_user))
+        .route(
+            "/navidrome/{id}",
+            axum::routing::get(get_navidrome_user)
+                .patch(update_navidrome_user)
+                .delete(delete_navidrome_user),
+        )
+        .with_state(state.clone())
+        .layer(axum::middleware::from_fn_with_state(state, auth_middleware))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::response::Response;
+
+    use super::*;
+
+    async fn state_with_users() -> AppState {
+        let state = crate::testing::state("http://127.0.0.1:1").await;
+        for (id, username) in [("nd-service", "service"), ("nd-reader", "reader")] {
+            state
+                .db
+                .add_user(&ProvisionedUser::imported(
+                    "navidrome",
+                    id,
+                    username,
+                    serde_json::json!({ "isAdmin": false, "libraryIds": [] }),
+                ))
+                .await
+                .unwrap();
+        }
+        state
+    }
+
+    fn update() -> Json<NavidromeUserUpdatePayload> {
+        Json(NavidromeUserUpdatePayload {
+            name: None,
+            email: None,
+            is_admin: Some(true),
+            password: None,
+        })
+    }
+
+    async fn responses(state: &AppState, user_id: &str) -> Vec<Response> {
+        let path = || Path(user_id.to_string());
+        vec![
+            get_navidrome_user(State(state.clone()), path())
+                .await
+                .into_response(),
+            update_navidrome_user(State(state.clone()), path(), update())
+                .await
+                .into_response(),
+            delete_navidrome_user(State(state.clone()), path())
+                .await
+                .into_response(),
+        ]
+    }
+
+    #[tokio::test]
+    async fn refuses_the_navidrome_service_account() {
+        let state = state_with_users().await;
+
+        for response in responses(&state, "nd-service").await {
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_navidrome_users_missing_from_the_registry() {
+        let state = state_with_users().await;
+
+        for response in responses(&state, "nd-admin").await {
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[tokio::test]
+    async fn reaches_navidrome_for_registered_users() {
+        let state = state_with_users().await;
+
+        // the test state has no Navidrome client, so getting past the registry ends there
+        for response in responses(&state, "nd-reader").await {
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+    }
+}
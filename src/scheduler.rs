@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use crate::{
+    AppState,
+    database::{AccessExpiryAction, ProvisionedUser},
+};
+
+/// How often the scheduler checks for accounts with expired access
+const ACCESS_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How many times the expiry action is tried before the admin is asked to step in
+const MAX_EXPIRY_FAILURES: u32 = 5;
+/// How often the registered users are compared against the upstream servers
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, thiserror::Error)]
+enum ExpiryError {
+    #[error("{0}")]
+    Komga(#[from] crate::komga::KomgaError),
+    #[error("{0}")]
+    Navidrome(#[from] crate::navidrome::NavidromeError),
+    #[error("client {0} is unavailable")]
+    ClientUnavailable(&'static str),
+    #[error("unknown user kind: {0}")]
+    UnknownKind(String),
+}
+
+async fn expire_user_access(state: &AppState, user: &ProvisionedUser) -> Result<(), ExpiryError> {
+    match user.kind.as_str() {
+        "komga" => match user.access_expiry_action {
+            AccessExpiryAction::Revoke => state.komga.revoke_user_access(&user.id).await?,
+            AccessExpiryAction::Delete => state.komga.delete_user(&user.id).await?,
+        },
+        "navidrome" => {
+            let navidrome = state
+                .navidrome
+                .as_ref()
+                .ok_or(ExpiryError::ClientUnavailable("Navidrome"))?;
+            let mut client = navidrome.lock().await;
+            match user.access_expiry_action {
+                AccessExpiryAction::Revoke => client.revoke_user_access(&user.id).await?,
+                AccessExpiryAction::Delete => client.delete_user(&user.id).await?,
+            }
+        }
+        kind => return Err(ExpiryError::UnknownKind(kind.to_string())),
+    }
+
+    Ok(())
+}
+
+async fn notify_admin(state: &AppState, content: String) {
+    let Some(webhook) = &state.config.notify_webhook else {
+        return;
+    };
+
+    let client = reqwest::Client::new();
+    if let Err(e) = client
+        .post(webhook)
+        .json(&serde_json::json!({ "content": content }))
+        .send()
+        .await
+    {
+        tracing::error!("Failed to send notification to webhook: {}", e);
+    }
+}
+
+async fn run_access_expiry(state: &AppState) {
+    let users = match state.db.get_users_pending_expiry(MAX_EXPIRY_FAILURES).await {
+        Ok(users) => users,
+        Err(e) => {
+            tracing::error!("Failed to get users with expired access: {}", e);
+            return;
+        }
+    };
+
+    for user in users {
+        tracing::info!(
+            "[{} / {}] Access expired, applying action: {}",
+            user.kind,
+            user.id,
+            user.access_expiry_action.as_str()
+        );
+
+        match expire_user_access(state, &user).await {
+            Ok(_) => {
+                if let Err(e) = state.db.mark_user_expired(&user.kind, &user.id).await {
+                    tracing::error!(
+                        "[{} / {}] Failed to mark user as expired: {}",
+                        user.kind,
+                        user.id,
+                        e
+                    );
+                    continue;
+                }
+
+                notify_admin(
+                    state,
+                    format!(
+                        "Access for {} user `{}` has expired, action taken: {}",
+                        user.kind,
+                        user.id,
+                        user.access_expiry_action.as_str()
+                    ),
+                )
+                .await;
+            }
+            Err(e) => {
+                // keep the user pending so we retry on the next tick
+                tracing::error!(
+                    "[{} / {}] Failed to expire user access: {}",
+                    user.kind,
+                    user.id,
+                    e
+                );
+
+                let failures = match state.db.record_expiry_failure(&user.kind, &user.id).await {
+                    Ok(failures) => failures,
+                    Err(e) => {
+                        tracing::error!(
+                            "[{} / {}] Failed to record expiry failure: {}",
+                            user.kind,
+                            user.id,
+                            e
+                        );
+                        continue;
+                    }
+                };
+
+                if failures >= MAX_EXPIRY_FAILURES {
+                    tracing::error!(
+                        "[{} / {}] Giving up on expiring user access after {} attempts",
+                        user.kind,
+                        user.id,
+                        failures
+                    );
+                    notify_admin(
+                        state,
+                        format!(
+                            "Access for {} user `{}` has expired, but the {} action failed {} times: {}. Take it manually or set a new expiry to try again.",
+                            user.kind,
+                            user.id,
+                            user.access_expiry_action.as_str(),
+                            failures,
+                            e
+                        ),
+                    )
+                    .await;
+                }
+            }
+        }
+    }
+}
+
+/// Spawn the background jobs that run for the whole lifetime of the server
+pub fn spawn(state: AppState) {
+    tokio::spawn(crate::events::listen_komga(state.clone()));
+
+    let expiry_state = state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ACCESS_EXPIRY_INTERVAL);
+        loop {
+            interval.tick().await;
+            run_access_expiry(&expiry_state).await;
+        }
+    });
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RECONCILE_INTERVAL);
+        loop {
+            interval.tick().await;
+            match crate::reconcile::reconcile_users(&state).await {
+                Ok(summary) => {
+                    tracing::info!(
+                        "Reconciled {} users, {} drifted, {} failed",
+                        summary.checked,
+                        summary.drifted,
+                        summary.failed
+                    );
+                    for error in &summary.errors {
+                        tracing::warn!("Reconciliation was incomplete: {}", error);
+                    }
+                }
+                Err(e) => tracing::error!("Failed to reconcile users: {}", e),
+            }
+        }
+    });
+}
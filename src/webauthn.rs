@@ -0,0 +1,501 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use p256::ecdsa::{Signature, VerifyingKey, signature::Verifier};
+use rand::Rng;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+/// COSE algorithm identifier of ECDSA with P-256 and SHA-256, the only one supported.
+/// Every current platform and roaming authenticator can create such a credential.
+pub const ES256: i64 = -7;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_CREDENTIAL: u8 = 0x40;
+
+#[derive(thiserror::Error, Debug)]
+pub enum WebauthnError {
+    #[error("invalid base64url data")]
+    Base64(#[from] base64::DecodeError),
+    #[error("invalid client data: {0}")]
+    ClientData(#[from] serde_json::Error),
+    #[error("invalid CBOR data: {0}")]
+    Cbor(#[from] serde_cbor::Error),
+    #[error("unexpected ceremony type: {0}")]
+    WrongType(String),
+    #[error("the challenge does not match")]
+    ChallengeMismatch,
+    #[error("unexpected origin: {0}")]
+    OriginMismatch(String),
+    #[error("authenticator data is too short or malformed")]
+    InvalidAuthenticatorData,
+    #[error("the credential was made for another relying party")]
+    RpIdMismatch,
+    #[error("the user was not verified by the authenticator")]
+    UserNotVerified,
+    #[error("attestation object has no authenticator data")]
+    MissingAuthenticatorData,
+    #[error("unsupported public key, only ES256 is supported")]
+    UnsupportedKey,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("the signature counter went backwards, the authenticator may be cloned")]
+    CounterRegressed,
+}
+
+/// `CollectedClientData` as serialized by the browser
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+/// The authenticator data of a registration or an assertion
+pub struct AuthenticatorData {
+    rp_id_hash: [u8; 32],
+    flags: u8,
+    pub sign_count: u32,
+    /// Only present during registration
+    pub credential: Option<AttestedCredential>,
+}
+
+pub struct AttestedCredential {
+    pub id: Vec<u8>,
+    /// SEC1 encoded P-256 public key
+    pub public_key: Vec<u8>,
+}
+
+pub fn encode(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+pub fn decode(data: &str) -> Result<Vec<u8>, WebauthnError> {
+    // some clients pad their base64url, which the spec does not allow but is harmless
+    Ok(URL_SAFE_NO_PAD.decode(data.trim_end_matches('='))?)
+}
+
+/// A new random challenge, base64url encoded
+pub fn generate_challenge() -> String {
+    encode(&rand::rng().random::<[u8; 32]>())
+}
+
+/// Check the client data of a ceremony, `kind` is either `webauthn.create` or `webauthn.get`
+pub fn verify_client_data(
+    client_data_json: &[u8],
+    kind: &str,
+    challenge: &str,
+    origin: &str,
+) -> Result<(), WebauthnError> {
+    let client_data: ClientData = serde_json::from_slice(client_data_json)?;
+
+    if client_data.kind != kind {
+        return Err(WebauthnError::WrongType(client_data.kind));
+    }
+    if client_data.challenge.trim_end_matches('=') != challenge {
+        return Err(WebauthnError::ChallengeMismatch);
+    }
+    if client_data.origin.trim_end_matches('/') != origin.trim_end_matches('/') {
+        return Err(WebauthnError::OriginMismatch(client_data.origin));
+    }
+
+    Ok(())
+}
+
+impl AuthenticatorData {
+    pub fn parse(data: &[u8]) -> Result<Self, WebauthnError> {
+        let rp_id_hash: [u8; 32] = data
+            .get(0..32)
+            .and_then(|hash| hash.try_into().ok())
+            .ok_or(WebauthnError::InvalidAuthenticatorData)?;
+        let flags = *data
+            .get(32)
+            .ok_or(WebauthnError::InvalidAuthenticatorData)?;
+        let sign_count = data
+            .get(33..37)
+            .and_then(|count| count.try_into().ok())
+            .map(u32::from_be_bytes)
+            .ok_or(WebauthnError::InvalidAuthenticatorData)?;
+
+        let credential = if flags & FLAG_ATTESTED_CREDENTIAL != 0 {
+            // 16 bytes of AAGUID, then the length of the credential ID
+            let id_length = data
+                .get(53..55)
+                .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+                .ok_or(WebauthnError::InvalidAuthenticatorData)?;
+            let id = data
+                .get(55..55 + id_length)
+                .ok_or(WebauthnError::InvalidAuthenticatorData)?
+                .to_vec();
+
+            // the COSE key can be followed by extensions, only read the first CBOR value
+            let mut deserializer = serde_cbor::Deserializer::from_slice(&data[55 + id_length..]);
+            let cose_key = serde_cbor::Value::deserialize(&mut deserializer)?;
+
+            Some(AttestedCredential {
+                id,
+                public_key: cose_key_to_sec1(cose_key)?,
+            })
+        } else {
+            None
+        };
+
+        Ok(Self {
+            rp_id_hash,
+            flags,
+            sign_count,
+            credential,
+        })
+    }
+
+    /// Check that the data was made for our relying party by a present and verified user
+    pub fn verify(&self, rp_id: &str) -> Result<(), WebauthnError> {
+        if self.rp_id_hash[..] != Sha256::digest(rp_id.as_bytes())[..] {
+            return Err(WebauthnError::RpIdMismatch);
+        }
+        // a passkey replaces both the password and the second factor, so it has to verify the user
+        if self.flags & FLAG_USER_PRESENT == 0 || self.flags & FLAG_USER_VERIFIED == 0 {
+            return Err(WebauthnError::UserNotVerified);
+        }
+
+        Ok(())
+    }
+}
+
+/// Get the raw authenticator data out of a CBOR attestation object.
+/// Attestation is never requested, so the attestation statement is not checked.
+pub fn attestation_authenticator_data(attestation_object: &[u8]) -> Result<Vec<u8>, WebauthnError> {
+    let serde_cbor::Value::Map(map) = serde_cbor::from_slice(attestation_object)? else {
+        return Err(WebauthnError::MissingAuthenticatorData);
+    };
+
+    match map.get(&serde_cbor::Value::Text("authData".to_string())) {
+        Some(serde_cbor::Value::Bytes(auth_data)) => Ok(auth_data.clone()),
+        _ => Err(WebauthnError::MissingAuthenticatorData),
+    }
+}
+
+/// Turn an EC2 P-256 COSE key into an uncompressed SEC1 point
+fn cose_key_to_sec1(cose_key: serde_cbor::Value) -> Result<Vec<u8>, WebauthnError> {
+    use serde_cbor::Value;
+
+    let Value::Map(map) = cose_key else {
+        return Err(WebauthnError::UnsupportedKey);
+    };
+    let get = |label: i128| map.get(&Value::Integer(label));
+
+    // kty 2 is EC2, crv 1 is P-256
+    if get(1) != Some(&Value::Integer(2))
+        || get(3) != Some(&Value::Integer(ES256 as i128))
+        || get(-1) != Some(&Value::Integer(1))
+    {
+        return Err(WebauthnError::UnsupportedKey);
+    }
+
+    let (Some(Value::Bytes(x)), Some(Value::Bytes(y))) = (get(-2), get(-3)) else {
+        return Err(WebauthnError::UnsupportedKey);
+    };
+    if x.len() != 32 || y.len() != 32 {
+        return Err(WebauthnError::UnsupportedKey);
+    }
+
+    let mut point = Vec::with_capacity(65);
+    point.push(0x04);
+    point.extend_from_slice(x);
+    point.extend_from_slice(y);
+
+    // make sure the point is actually on the curve before storing it
+    VerifyingKey::from_sec1_bytes(&point).map_err(|_| WebauthnError::UnsupportedKey)?;
+
+    Ok(point)
+}
+
+/// Check an assertion signature, made over the authenticator data and the client data hash
+pub fn verify_signature(
+    public_key: &[u8],
+    authenticator_data: &[u8],
+    client_data_json: &[u8],
+    signature: &[u8],
+) -> Result<(), WebauthnError> {
+    let key =
+        VerifyingKey::from_sec1_bytes(public_key).map_err(|_| WebauthnError::UnsupportedKey)?;
+    let signature = Signature::from_der(signature).map_err(|_| WebauthnError::InvalidSignature)?;
+
+    let mut message = authenticator_data.to_vec();
+    message.extend_from_slice(&Sha256::digest(client_data_json));
+
+    key.verify(&message, &signature)
+        .map_err(|_| WebauthnError::InvalidSignature)
+}
+
+/// Authenticators without a counter always report 0, otherwise it has to go up on every use
+pub fn verify_sign_count(stored: u32, received: u32) -> Result<(), WebauthnError> {
+    if (stored != 0 || received != 0) && received <= stored {
+        return Err(WebauthnError::CounterRegressed);
+    }
+
+    Ok(())
+}
+
+/// A software authenticator holding a single ES256 credential, to run ceremonies in the tests
+#[cfg(test)]
+pub mod testing {
+    use std::collections::BTreeMap;
+
+    use p256::ecdsa::{SigningKey, signature::Signer};
+    use serde_cbor::Value;
+    use sha2::{Digest, Sha256};
+
+    use super::{FLAG_ATTESTED_CREDENTIAL, FLAG_USER_PRESENT, FLAG_USER_VERIFIED};
+
+    pub const FLAGS_VERIFIED: u8 = FLAG_USER_PRESENT | FLAG_USER_VERIFIED;
+    pub const FLAGS_PRESENT_ONLY: u8 = FLAG_USER_PRESENT;
+
+    pub struct SoftwareAuthenticator {
+        key: SigningKey,
+        pub credential_id: Vec<u8>,
+        pub sign_count: u32,
+    }
+
+    impl SoftwareAuthenticator {
+        pub fn new() -> Self {
+            Self {
+                key: SigningKey::from_slice(&[7; 32]).unwrap(),
+                credential_id: b"software-credential".to_vec(),
+                sign_count: 0,
+            }
+        }
+
+        /// The public key as a COSE EC2 key
+        pub fn cose_key(&self) -> Value {
+            let point = self.key.verifying_key().to_encoded_point(false);
+            let mut map = BTreeMap::new();
+            map.insert(Value::Integer(1), Value::Integer(2));
+            map.insert(Value::Integer(3), Value::Integer(super::ES256 as i128));
+            map.insert(Value::Integer(-1), Value::Integer(1));
+            map.insert(
+                Value::Integer(-2),
+                Value::Bytes(point.x().unwrap().to_vec()),
+            );
+            map.insert(
+                Value::Integer(-3),
+                Value::Bytes(point.y().unwrap().to_vec()),
+            );
+            Value::Map(map)
+        }
+
+        /// The uncompressed SEC1 public key, as it gets stored
+        pub fn public_key(&self) -> Vec<u8> {
+            self.key
+                .verifying_key()
+                .to_encoded_point(false)
+                .as_bytes()
+                .to_vec()
+        }
+
+        pub fn client_data(kind: &str, challenge: &str, origin: &str) -> Vec<u8> {
+            serde_json::to_vec(&serde_json::json!({
+                "type": kind,
+                "challenge": challenge,
+                "origin": origin,
+                "crossOrigin": false,
+            }))
+            .unwrap()
+        }
+
+        pub fn authenticator_data(&self, rp_id: &str, flags: u8, with_credential: bool) -> Vec<u8> {
+            let mut data = Sha256::digest(rp_id.as_bytes()).to_vec();
+            data.push(if with_credential {
+                flags | FLAG_ATTESTED_CREDENTIAL
+            } else {
+                flags
+            });
+            data.extend_from_slice(&self.sign_count.to_be_bytes());
+
+            if with_credential {
+                data.extend_from_slice(&[0; 16]);
+                data.extend_from_slice(&(self.credential_id.len() as u16).to_be_bytes());
+                data.extend_from_slice(&self.credential_id);
+                data.extend_from_slice(&serde_cbor::to_vec(&self.cose_key()).unwrap());
+            }
+
+            data
+        }
+
+        /// A `none` attestation object for a new credential
+        pub fn attestation_object(&self, rp_id: &str, flags: u8) -> Vec<u8> {
+            let mut map = BTreeMap::new();
+            map.insert(
+                Value::Text("fmt".to_string()),
+                Value::Text("none".to_string()),
+            );
+            map.insert(
+                Value::Text("attStmt".to_string()),
+                Value::Map(BTreeMap::new()),
+            );
+            map.insert(
+                Value::Text("authData".to_string()),
+                Value::Bytes(self.authenticator_data(rp_id, flags, true)),
+            );
+            serde_cbor::to_vec(&Value::Map(map)).unwrap()
+        }
+
+        /// Sign in, returns the authenticator data and the DER signature over it and the client data
+        pub fn assert(&mut self, rp_id: &str, flags: u8, client_data: &[u8]) -> (Vec<u8>, Vec<u8>) {
+            self.sign_count += 1;
+            let auth_data = self.authenticator_data(rp_id, flags, false);
+
+            let mut message = auth_data.clone();
+            message.extend_from_slice(&Sha256::digest(client_data));
+            let signature: p256::ecdsa::Signature = self.key.sign(&message);
+
+            (auth_data, signature.to_der().as_bytes().to_vec())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::{FLAGS_PRESENT_ONLY, FLAGS_VERIFIED, SoftwareAuthenticator};
+    use super::*;
+
+    const RP_ID: &str = "invite.example.com";
+    const ORIGIN: &str = "https://invite.example.com";
+
+    #[test]
+    fn parses_registration_authenticator_data() {
+        let authenticator = SoftwareAuthenticator::new();
+        let raw = attestation_authenticator_data(
+            &authenticator.attestation_object(RP_ID, FLAGS_VERIFIED),
+        )
+        .unwrap();
+
+        let auth_data = AuthenticatorData::parse(&raw).unwrap();
+        auth_data.verify(RP_ID).unwrap();
+
+        let credential = auth_data.credential.unwrap();
+        assert_eq!(credential.id, authenticator.credential_id);
+        assert_eq!(credential.public_key, authenticator.public_key());
+        assert_eq!(auth_data.sign_count, 0);
+    }
+
+    #[test]
+    fn rejects_truncated_authenticator_data() {
+        let authenticator = SoftwareAuthenticator::new();
+        let raw = authenticator.authenticator_data(RP_ID, FLAGS_VERIFIED, true);
+
+        assert!(AuthenticatorData::parse(&raw[..36]).is_err());
+        assert!(AuthenticatorData::parse(&raw[..60]).is_err());
+    }
+
+    #[test]
+    fn converts_only_p256_cose_keys() {
+        let authenticator = SoftwareAuthenticator::new();
+        assert_eq!(
+            cose_key_to_sec1(authenticator.cose_key()).unwrap(),
+            authenticator.public_key()
+        );
+
+        // an RS256 key
+        let serde_cbor::Value::Map(mut map) = authenticator.cose_key() else {
+            unreachable!()
+        };
+        map.insert(
+            serde_cbor::Value::Integer(3),
+            serde_cbor::Value::Integer(-257),
+        );
+        assert!(matches!(
+            cose_key_to_sec1(serde_cbor::Value::Map(map)),
+            Err(WebauthnError::UnsupportedKey)
+        ));
+    }
+
+    #[test]
+    fn accepts_a_valid_assertion() {
+        let mut authenticator = SoftwareAuthenticator::new();
+        let challenge = generate_challenge();
+        let client_data = SoftwareAuthenticator::client_data("webauthn.get", &challenge, ORIGIN);
+        let (raw, signature) = authenticator.assert(RP_ID, FLAGS_VERIFIED, &client_data);
+
+        verify_client_data(&client_data, "webauthn.get", &challenge, ORIGIN).unwrap();
+        let auth_data = AuthenticatorData::parse(&raw).unwrap();
+        auth_data.verify(RP_ID).unwrap();
+        verify_signature(&authenticator.public_key(), &raw, &client_data, &signature).unwrap();
+        verify_sign_count(0, auth_data.sign_count).unwrap();
+    }
+
+    #[test]
+    fn rejects_a_tampered_signature() {
+        let mut authenticator = SoftwareAuthenticator::new();
+        let client_data = SoftwareAuthenticator::client_data("webauthn.get", "abc", ORIGIN);
+        let (raw, signature) = authenticator.assert(RP_ID, FLAGS_VERIFIED, &client_data);
+        let other_client_data = SoftwareAuthenticator::client_data("webauthn.get", "abd", ORIGIN);
+
+        assert!(matches!(
+            verify_signature(
+                &authenticator.public_key(),
+                &raw,
+                &other_client_data,
+                &signature
+            ),
+            Err(WebauthnError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_wrong_challenge_origin_or_type() {
+        let client_data = SoftwareAuthenticator::client_data("webauthn.get", "abc", ORIGIN);
+
+        assert!(matches!(
+            verify_client_data(&client_data, "webauthn.get", "xyz", ORIGIN),
+            Err(WebauthnError::ChallengeMismatch)
+        ));
+        assert!(matches!(
+            verify_client_data(
+                &client_data,
+                "webauthn.get",
+                "abc",
+                "https://evil.example.com"
+            ),
+            Err(WebauthnError::OriginMismatch(_))
+        ));
+        assert!(matches!(
+            verify_client_data(&client_data, "webauthn.create", "abc", ORIGIN),
+            Err(WebauthnError::WrongType(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_user_verification_and_other_rp() {
+        let mut authenticator = SoftwareAuthenticator::new();
+        let client_data = SoftwareAuthenticator::client_data("webauthn.get", "abc", ORIGIN);
+
+        let (raw, _) = authenticator.assert(RP_ID, FLAGS_PRESENT_ONLY, &client_data);
+        assert!(matches!(
+            AuthenticatorData::parse(&raw).unwrap().verify(RP_ID),
+            Err(WebauthnError::UserNotVerified)
+        ));
+
+        let (raw, _) = authenticator.assert(RP_ID, FLAGS_VERIFIED, &client_data);
+        assert!(matches!(
+            AuthenticatorData::parse(&raw)
+                .unwrap()
+                .verify("evil.example.com"),
+            Err(WebauthnError::RpIdMismatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_counter_going_backwards() {
+        verify_sign_count(0, 0).unwrap();
+        verify_sign_count(4, 5).unwrap();
+        assert!(matches!(
+            verify_sign_count(5, 5),
+            Err(WebauthnError::CounterRegressed)
+        ));
+        assert!(matches!(
+            verify_sign_count(5, 2),
+            Err(WebauthnError::CounterRegressed)
+        ));
+    }
+}